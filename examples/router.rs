@@ -0,0 +1,20 @@
+use may_minihttp::{HttpServer, Router};
+
+fn main() {
+    env_logger::init();
+    let router = Router::new()
+        .get("/", |_req, rsp| {
+            rsp.body("Hello, world!");
+            Ok(())
+        })
+        .post("/echo", |req, rsp| {
+            rsp.body_vec(req.body().to_vec());
+            Ok(())
+        })
+        .get("/static/*", |req, rsp| {
+            rsp.body_vec(req.path().as_bytes().to_vec());
+            Ok(())
+        });
+    let server = HttpServer(router).start("127.0.0.1:8080").unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,28 @@
+use may_minihttp::{HttpServer, HttpService, Request, Response};
+use std::io;
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Ticker;
+
+impl HttpService for Ticker {
+    fn call(&mut self, _req: Request, rsp: &mut Response) -> io::Result<()> {
+        let events = rsp.sse();
+        may::go!(move || {
+            for i in 0..10 {
+                if events.send_event("tick", &i.to_string()).is_err() {
+                    // the client went away
+                    return;
+                }
+                may::coroutine::sleep(Duration::from_secs(1));
+            }
+        });
+        Ok(())
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let server = HttpServer(Ticker).start("127.0.0.1:8080").unwrap();
+    server.join().unwrap();
+}
@@ -12,17 +12,12 @@ use nanorand::{Rng, WyRand};
 use smallvec::SmallVec;
 use yarte::{ywrite_html, Serialize};
 
-mod utils {
-    use atoi::FromRadix10;
-
-    pub fn get_query_param(query: &str) -> u16 {
-        let q = if let Some(pos) = query.find("?q") {
-            u16::from_radix_10(query.split_at(pos + 3).1.as_ref()).0
-        } else {
-            1
-        };
-        q.clamp(1, 500)
-    }
+/// the `q` query parameter, 1 to 500 and 1 when it's missing or not a number
+fn query_count(req: &Request) -> usize {
+    req.query_param("q")
+        .and_then(|q| q.parse().ok())
+        .unwrap_or(1)
+        .clamp(1, 500)
 }
 
 #[derive(Serialize)]
@@ -247,13 +242,13 @@ impl HttpService for Techempower {
             }
             p if p.starts_with("/queries") => {
                 rsp.header("Content-Type: application/json");
-                let q = utils::get_query_param(p) as usize;
+                let q = query_count(&req);
                 let worlds = self.db.get_worlds(q, &mut self.rng).unwrap();
                 worlds.to_bytes_mut(rsp.body_mut());
             }
             p if p.starts_with("/updates") => {
                 rsp.header("Content-Type: application/json");
-                let q = utils::get_query_param(p) as usize;
+                let q = query_count(&req);
                 let worlds = self.db.updates(q, &mut self.rng).unwrap();
                 worlds.to_bytes_mut(rsp.body_mut());
             }
@@ -0,0 +1,125 @@
+//! per request access logging
+
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::request::Request;
+
+/// what is recorded of a served request
+#[derive(Debug)]
+pub struct AccessRecord<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    /// the minor version of HTTP/1.x
+    pub version: u8,
+    pub status: usize,
+    /// the response body bytes
+    pub bytes: u64,
+    /// from the request being decoded to the response being encoded
+    pub latency: Duration,
+    pub peer: Option<SocketAddr>,
+}
+
+/// one line like `127.0.0.1:50000 "GET / HTTP/1.1" 200 13 0.042ms`
+impl<'a> fmt::Display for AccessRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{peer}")?,
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            " \"{} {} HTTP/1.{}\" {} {} {:.3}ms",
+            self.method,
+            self.path,
+            self.version,
+            self.status,
+            self.bytes,
+            self.latency.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// the sink of the access records, see `ServerBuilder::access_log`
+///
+/// it is called on the connection coroutine right after the response is encoded,
+/// so it should not block for long. a closure can forward the records to a channel
+pub trait AccessLog: Send + Sync + 'static {
+    fn log(&self, record: &AccessRecord);
+}
+
+impl<F> AccessLog for F
+where
+    F: Fn(&AccessRecord) + Send + Sync + 'static,
+{
+    #[inline]
+    fn log(&self, record: &AccessRecord) {
+        self(record)
+    }
+}
+
+/// write each record as a line to `stdout`, a file or any other writer
+///
+/// ```ignore
+/// ServerBuilder::new().access_log(WriterLog::new(io::stdout()))
+/// ```
+pub struct WriterLog<W>(Mutex<W>);
+
+impl<W: Write + Send + 'static> WriterLog<W> {
+    pub fn new(writer: W) -> Self {
+        WriterLog(Mutex::new(writer))
+    }
+}
+
+impl<W: Write + Send + 'static> AccessLog for WriterLog<W> {
+    fn log(&self, record: &AccessRecord) {
+        let mut w = self.0.lock().unwrap();
+        if let Err(e) = writeln!(w, "{record}") {
+            error!("access log err = {:?}", e);
+        }
+    }
+}
+
+/// the configured sink
+#[derive(Clone)]
+pub(crate) struct Sink(pub(crate) Arc<dyn AccessLog>);
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AccessLog")
+    }
+}
+
+/// the part of the record known before the service takes the request
+pub(crate) struct Pending {
+    start: Instant,
+    method: String,
+    path: String,
+    version: u8,
+}
+
+impl Pending {
+    pub(crate) fn new(req: &Request) -> Self {
+        Pending {
+            start: Instant::now(),
+            method: req.method().as_str().to_owned(),
+            path: req.path().to_owned(),
+            version: req.version(),
+        }
+    }
+
+    pub(crate) fn finish(self, sink: &Sink, peer: Option<SocketAddr>, status: usize, bytes: u64) {
+        sink.0.log(&AccessRecord {
+            method: &self.method,
+            path: &self.path,
+            version: self.version,
+            status,
+            bytes,
+            latency: self.start.elapsed(),
+            peer,
+        });
+    }
+}
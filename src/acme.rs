@@ -0,0 +1,577 @@
+//! ACME (RFC 8555) HTTP-01 challenges and certificate renewal
+//!
+//! the server side of the challenges and the renewal schedule live here, the
+//! exchange with the CA (the account key, the signed requests, the order and
+//! the CSR) is left to an `AcmeIssuer`, usually a thin wrapper around an ACME
+//! client crate
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::method::Method;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::tls::{any_element, element, SEQUENCE};
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// a certificate is renewed that long before it expires by default
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+/// how often the expiry is checked by default
+const CHECK_EVERY: Duration = Duration::from_secs(12 * 3600);
+/// the wait before a failed renewal is tried again
+const RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// the pending HTTP-01 challenges, answered on `/.well-known/acme-challenge/{token}`
+///
+/// it is a middleware, the other requests go on down the chain.
+/// the clones share the challenges
+#[derive(Debug, Clone, Default)]
+pub struct AcmeChallenges {
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl AcmeChallenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// answer the token with its key authorization until it is removed
+    pub fn set(&self, token: impl Into<String>, key_authorization: impl Into<String>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.insert(token.into(), key_authorization.into());
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+}
+
+impl Middleware for AcmeChallenges {
+    fn handle(&self, req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        let path = req.path();
+        let token = match path.strip_prefix(CHALLENGE_PATH) {
+            Some(token) if matches!(req.method(), Method::Get | Method::Head) => {
+                token.split('?').next().unwrap_or_default()
+            }
+            _ => return next.run(req, rsp),
+        };
+        match self.tokens.lock().unwrap().get(token) {
+            Some(key_authorization) => {
+                rsp.header("Content-Type: application/octet-stream");
+                rsp.body_vec(key_authorization.clone().into_bytes());
+            }
+            None => {
+                rsp.status(StatusCode::NotFound);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a certificate chain, the leaf first, and its private key, PEM encoded
+#[derive(Clone)]
+pub struct AcmeCertificate {
+    chain_pem: String,
+    key_pem: String,
+    not_after: Option<SystemTime>,
+}
+
+impl AcmeCertificate {
+    pub fn new(chain_pem: impl Into<String>, key_pem: impl Into<String>) -> Self {
+        let chain_pem = chain_pem.into();
+        let not_after = not_after(&chain_pem);
+        AcmeCertificate {
+            chain_pem,
+            key_pem: key_pem.into(),
+            not_after,
+        }
+    }
+
+    pub fn chain_pem(&self) -> &str {
+        &self.chain_pem
+    }
+
+    pub fn key_pem(&self) -> &str {
+        &self.key_pem
+    }
+
+    /// when the leaf certificate expires, `None` when it can't be read
+    pub fn not_after(&self) -> Option<SystemTime> {
+        self.not_after
+    }
+
+    /// it expires within `before`, one that can't be read is renewed
+    fn expires_within(&self, before: Duration) -> bool {
+        match self.not_after {
+            Some(not_after) => SystemTime::now() + before >= not_after,
+            None => true,
+        }
+    }
+}
+
+impl fmt::Debug for AcmeCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the key is left out of the logs
+        f.debug_struct("AcmeCertificate")
+            .field("not_after", &self.not_after)
+            .finish_non_exhaustive()
+    }
+}
+
+/// where the issued certificates are kept between runs
+pub trait CertStore: Send + 'static {
+    /// the certificate stored under the name, `None` if there is none
+    fn load(&self, name: &str) -> io::Result<Option<AcmeCertificate>>;
+
+    fn store(&self, name: &str, cert: &AcmeCertificate) -> io::Result<()>;
+}
+
+/// keep the certificates as `{name}.crt.pem` and `{name}.key.pem` in a directory
+#[derive(Debug, Clone)]
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirStore { dir: dir.into() }
+    }
+
+    fn paths(&self, name: &str) -> (PathBuf, PathBuf) {
+        (
+            self.dir.join(format!("{name}.crt.pem")),
+            self.dir.join(format!("{name}.key.pem")),
+        )
+    }
+}
+
+impl CertStore for DirStore {
+    fn load(&self, name: &str) -> io::Result<Option<AcmeCertificate>> {
+        let (crt, key) = self.paths(name);
+        let read = |path| match fs::read_to_string(path) {
+            Ok(pem) => Ok(Some(pem)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        match (read(crt)?, read(key)?) {
+            (Some(chain), Some(key)) => Ok(Some(AcmeCertificate::new(chain, key))),
+            _ => Ok(None),
+        }
+    }
+
+    fn store(&self, name: &str, cert: &AcmeCertificate) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let (crt, key) = self.paths(name);
+        // written aside then renamed, a reader never sees half a file
+        let write = |path: &PathBuf, pem: &str, private: bool| {
+            let tmp = path.with_extension("pem.tmp");
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            if private {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            #[cfg(not(unix))]
+            let _ = private;
+            io::Write::write_all(&mut options.open(&tmp)?, pem.as_bytes())?;
+            fs::rename(tmp, path)
+        };
+        write(&key, &cert.key_pem, true)?;
+        write(&crt, &cert.chain_pem, false)
+    }
+}
+
+/// gets a certificate for the domains from the CA
+///
+/// each HTTP-01 challenge of the order is `set` on `challenges` before it is
+/// answered to the CA, and removed once the authorization is over
+pub trait AcmeIssuer: Send + 'static {
+    fn issue(
+        &mut self,
+        domains: &[String],
+        challenges: &AcmeChallenges,
+    ) -> io::Result<AcmeCertificate>;
+}
+
+/// keep a certificate for the domains issued and renewed
+///
+/// the challenges are only answered by a server on port 80 of the domains that
+/// is wrapped with `challenges()`, it must be started first
+///
+/// ```ignore
+/// let mut acme = Acme::new(&["example.com"], MyIssuer::new(..), DirStore::new("certs"));
+/// let challenges = Chain::new(Redirect).wrap(acme.challenges());
+/// HttpServer(challenges).start("0.0.0.0:80")?;
+///
+/// let tls = ReloadableTls::new(rustls_config(&acme.certificate()?)?);
+/// let reload = tls.clone();
+/// acme.spawn_renewal(move |cert| match rustls_config(cert) {
+///     Ok(config) => reload.reload(config),
+///     Err(e) => eprintln!("bad certificate: {e}"),
+/// });
+/// HttpServer(App).start_tls("0.0.0.0:443", tls)?;
+/// ```
+pub struct Acme<I, S> {
+    domains: Vec<String>,
+    issuer: I,
+    store: S,
+    challenges: AcmeChallenges,
+    renew_before: Duration,
+    check_every: Duration,
+}
+
+impl<I: AcmeIssuer, S: CertStore> Acme<I, S> {
+    /// the certificate is stored under the first domain
+    pub fn new(domains: &[&str], issuer: I, store: S) -> Self {
+        Acme {
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            issuer,
+            store,
+            challenges: AcmeChallenges::new(),
+            renew_before: RENEW_BEFORE,
+            check_every: CHECK_EVERY,
+        }
+    }
+
+    /// renew the certificate that long before it expires, 30 days by default
+    pub fn renew_before(mut self, before: Duration) -> Self {
+        self.renew_before = before;
+        self
+    }
+
+    /// how often the expiry is checked by `spawn_renewal`, 12 hours by default
+    pub fn check_every(mut self, interval: Duration) -> Self {
+        self.check_every = interval;
+        self
+    }
+
+    /// the middleware answering the challenges
+    pub fn challenges(&self) -> AcmeChallenges {
+        self.challenges.clone()
+    }
+
+    /// the stored certificate, or a new one when there is none or it expires soon
+    pub fn certificate(&mut self) -> io::Result<AcmeCertificate> {
+        Ok(self.renew()?.0)
+    }
+
+    /// the certificate and whether it was just issued
+    fn renew(&mut self) -> io::Result<(AcmeCertificate, bool)> {
+        let name = self
+            .domains
+            .first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no domain"))?;
+        if let Some(cert) = self.store.load(name)? {
+            if !cert.expires_within(self.renew_before) {
+                return Ok((cert, false));
+            }
+        }
+        info!("issuing a certificate for {:?}", self.domains);
+        let cert = self.issuer.issue(&self.domains, &self.challenges)?;
+        self.store.store(name, &cert)?;
+        Ok((cert, true))
+    }
+
+    /// check the expiry in the background, `on_renewed` gets each new
+    /// certificate to reload the TLS acceptor with. a failed renewal is
+    /// logged and tried again an hour later
+    pub fn spawn_renewal<F>(self, on_renewed: F)
+    where
+        F: Fn(&AcmeCertificate) + Send + 'static,
+    {
+        may::go!(move || self.renewal(on_renewed));
+    }
+
+    fn renewal<F: Fn(&AcmeCertificate)>(mut self, on_renewed: F) {
+        loop {
+            let wait = match self.renew() {
+                Ok((cert, renewed)) => {
+                    if renewed {
+                        on_renewed(&cert);
+                    }
+                    self.check_every
+                }
+                Err(e) => {
+                    error!("acme renewal err = {:?}", e);
+                    RETRY_AFTER.min(self.check_every)
+                }
+            };
+            may::coroutine::sleep(wait);
+        }
+    }
+}
+
+impl<I, S> fmt::Debug for Acme<I, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Acme")
+            .field("domains", &self.domains)
+            .field("renew_before", &self.renew_before)
+            .field("check_every", &self.check_every)
+            .finish_non_exhaustive()
+    }
+}
+
+/// the `notAfter` of the first certificate of a PEM chain
+fn not_after(pem: &str) -> Option<SystemTime> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    let start = pem.find(BEGIN)? + BEGIN.len();
+    let end = start + pem[start..].find("-----END")?;
+    let der = base64_decode(&pem[start..end])?;
+
+    let (_, cert, _) = element(&der, SEQUENCE)?;
+    let (_, tbs, _) = element(cert, SEQUENCE)?;
+    let (tag, _, mut rest) = any_element(tbs)?;
+    // the version is left out for v1
+    if tag == 0xa0 {
+        rest = any_element(rest)?.2;
+    }
+    // the signature algorithm and the issuer come before the validity
+    for _ in 0..2 {
+        rest = any_element(rest)?.2;
+    }
+    let (_, validity, _) = element(rest, SEQUENCE)?;
+    let (_, _, validity) = any_element(validity)?;
+    let (tag, time, _) = any_element(validity)?;
+    let time = std::str::from_utf8(time).ok()?;
+    // UTCTime has a two digit year, GeneralizedTime a four digit one
+    let (year, rest) = match tag {
+        0x17 => {
+            let yy: u64 = time.get(..2)?.parse().ok()?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, &time[2..])
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<u64> { rest.get(i..i + 2)?.parse().ok() };
+    let days = days_from_civil(year, field(0)?, field(2)?)?;
+    let secs = days * 86400 + field(4)? * 3600 + field(6)? * 60 + field(8)?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// the days from 1970-01-01 to the date, for the years from 1970
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let yoe = y % 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
+}
+
+/// decode standard base64, the line breaks are skipped
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in s.bytes() {
+        let v = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b if b.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Chain;
+    use crate::request::with_request;
+    use crate::router::Router;
+    use crate::HttpService;
+
+    const V3: &str = include_str!("testdata/v3.pem");
+    // a GeneralizedTime `notAfter`, in 2136
+    const FAR: &str = include_str!("testdata/far.pem");
+
+    /// the status and body of the answer to the request
+    fn call(service: &mut impl HttpService, head: &str) -> (usize, Vec<u8>) {
+        let mut body = bytes::BytesMut::new();
+        let mut rsp = Response::new(&mut body, b"");
+        with_request(head, |req| service.call(req, &mut rsp)).unwrap();
+        let code = rsp.code();
+        let mut buf = bytes::BytesMut::new();
+        crate::response::encode(rsp, &mut buf);
+        let at = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (code, buf[at..].to_vec())
+    }
+
+    #[test]
+    fn challenges() {
+        let accepted = |_: Request, rsp: &mut Response| {
+            rsp.status(StatusCode::Accepted);
+            Ok(())
+        };
+        let challenges = AcmeChallenges::new();
+        let router = Router::new()
+            .get("/app", accepted)
+            .post("/.well-known/acme-challenge/t", accepted);
+        let mut service = Chain::new(router).wrap(challenges.clone());
+        let mut get = |path: &str| {
+            call(
+                &mut service,
+                &format!("GET {path} HTTP/1.1\r\nHost: h\r\n\r\n"),
+            )
+        };
+
+        challenges.set("t", "t.key");
+        assert_eq!(
+            get("/.well-known/acme-challenge/t"),
+            (200, b"t.key".to_vec())
+        );
+        assert_eq!(
+            get("/.well-known/acme-challenge/t?x=1"),
+            (200, b"t.key".to_vec())
+        );
+        assert_eq!(get("/.well-known/acme-challenge/u").0, 404);
+        assert_eq!(get("/.well-known/acme-challenge/").0, 404);
+        assert_eq!(get("/app").0, 202);
+        challenges.remove("t");
+        assert_eq!(get("/.well-known/acme-challenge/t").0, 404);
+
+        // the other methods go on down the chain
+        let post =
+            "POST /.well-known/acme-challenge/t HTTP/1.1\r\nHost: h\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(call(&mut service, post).0, 202);
+    }
+
+    #[test]
+    fn expiry() {
+        let secs =
+            |pem: &str| not_after(pem).map(|t| t.duration_since(UNIX_EPOCH).unwrap().as_secs());
+        assert_eq!(secs(V3), Some(2107480467));
+        assert_eq!(secs(FAR), Some(5248121009));
+        // the leaf is the first one
+        assert_eq!(secs(&format!("{FAR}{V3}")), Some(5248121009));
+        assert_eq!(secs(""), None);
+        assert_eq!(secs(&V3.replace("MII", "MIJ")), None);
+        assert_eq!(secs(&V3.replace("-----END", "!-----END")), None);
+
+        let cert = AcmeCertificate::new(V3, "key");
+        assert!(!cert.expires_within(Duration::from_secs(3600)));
+        assert!(cert.expires_within(Duration::from_secs(200 * 365 * 86400)));
+        assert!(AcmeCertificate::new("junk", "key").expires_within(Duration::ZERO));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), Some(0));
+        assert_eq!(days_from_civil(2000, 3, 1), Some(11017));
+        assert_eq!(days_from_civil(2024, 2, 29), Some(19782));
+        assert_eq!(days_from_civil(2024, 12, 31), Some(20088));
+        assert_eq!(days_from_civil(1969, 12, 31), None);
+        assert_eq!(days_from_civil(2024, 13, 1), None);
+        assert_eq!(days_from_civil(2024, 1, 0), None);
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64_decode("aGVs\r\nbG8h").unwrap(), b"hello!");
+        assert_eq!(base64_decode("+/8=").unwrap(), [0xfb, 0xff]);
+        assert_eq!(base64_decode(""), Some(vec![]));
+        assert_eq!(base64_decode("aGV-"), None);
+    }
+
+    /// issues the certificate, answering the challenge of each domain
+    struct Issuer {
+        pem: &'static str,
+        issued: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl AcmeIssuer for Issuer {
+        fn issue(
+            &mut self,
+            domains: &[String],
+            challenges: &AcmeChallenges,
+        ) -> io::Result<AcmeCertificate> {
+            for domain in domains {
+                challenges.set(domain.as_str(), "key-authorization");
+                challenges.remove(domain);
+            }
+            self.issued.lock().unwrap().push(domains.to_vec());
+            Ok(AcmeCertificate::new(self.pem, "private key"))
+        }
+    }
+
+    #[test]
+    fn renewal() {
+        let dir = std::env::temp_dir().join(format!("acme-{}", std::process::id()));
+        let issued = Arc::new(Mutex::new(Vec::new()));
+        let acme = |pem| {
+            let issuer = Issuer {
+                pem,
+                issued: issued.clone(),
+            };
+            Acme::new(
+                &["example.com", "www.example.com"],
+                issuer,
+                DirStore::new(&dir),
+            )
+        };
+
+        // issued then stored under the first domain
+        let (cert, renewed) = acme(V3).renew().unwrap();
+        assert!(renewed);
+        assert_eq!(cert.chain_pem(), V3);
+        assert_eq!(issued.lock().unwrap().len(), 1);
+        assert_eq!(
+            issued.lock().unwrap()[0],
+            ["example.com", "www.example.com"]
+        );
+        let stored = fs::read_to_string(dir.join("example.com.crt.pem")).unwrap();
+        assert_eq!(stored, V3);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = fs::metadata(dir.join("example.com.key.pem")).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+
+        // the stored one is good for a while
+        let (cert, renewed) = acme(FAR).renew().unwrap();
+        assert!(!renewed);
+        assert_eq!((cert.chain_pem(), cert.key_pem()), (V3, "private key"));
+        assert_eq!(issued.lock().unwrap().len(), 1);
+
+        // it expires within the window
+        let mut soon = acme(FAR).renew_before(Duration::from_secs(100 * 365 * 86400));
+        assert_eq!(soon.certificate().unwrap().chain_pem(), FAR);
+        assert_eq!(issued.lock().unwrap().len(), 2);
+        assert_eq!(acme(V3).certificate().unwrap().chain_pem(), FAR);
+        assert!(!dir.join("example.com.crt.pem.tmp").exists());
+
+        let mut none = Acme::new(&[], Issuer { pem: V3, issued }, DirStore::new(&dir));
+        assert_eq!(
+            none.certificate().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! `Authorization` checking middleware
+
+use std::io;
+use std::sync::Arc;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// checks the token of a `Bearer` authorization
+pub trait TokenVerifier: Send + Sync + 'static {
+    fn verify(&self, token: &str) -> bool;
+}
+
+impl<F> TokenVerifier for F
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    #[inline]
+    fn verify(&self, token: &str) -> bool {
+        self(token)
+    }
+}
+
+/// the check of a user and password
+type Credentials = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// require `Authorization: Basic` credentials accepted by the callback
+///
+/// a request without them, or with a wrong user or password, is answered with
+/// `401 Unauthorized` and a `WWW-Authenticate` challenge for the realm
+///
+/// ```ignore
+/// let service = Chain::new(Admin).wrap(BasicAuth::new("admin", |user, pass| {
+///     user == "admin" && pass == password
+/// }));
+/// ```
+#[derive(Clone)]
+pub struct BasicAuth {
+    challenge: String,
+    check: Arc<Credentials>,
+}
+
+impl BasicAuth {
+    pub fn new<F>(realm: &str, check: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        BasicAuth {
+            challenge: format!("WWW-Authenticate: Basic realm=\"{realm}\", charset=\"UTF-8\""),
+            check: Arc::new(check),
+        }
+    }
+
+    fn authorized(&self, req: &Request) -> bool {
+        let credentials = match authorization(req, "basic").and_then(base64_decode) {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        match std::str::from_utf8(&credentials)
+            .ok()
+            .and_then(|c| c.split_once(':'))
+        {
+            Some((user, password)) => (self.check)(user, password),
+            None => false,
+        }
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        if !self.authorized(&req) {
+            rsp.status(StatusCode::Unauthorized)
+                .header(self.challenge.clone());
+            return Ok(());
+        }
+        next.run(req, rsp)
+    }
+}
+
+/// require an `Authorization: Bearer` token accepted by the verifier
+///
+/// a request without a token is answered with `401 Unauthorized` and a
+/// `WWW-Authenticate` challenge for the realm, a rejected token gets the
+/// same with `error="invalid_token"`
+#[derive(Clone)]
+pub struct BearerAuth<V> {
+    realm: String,
+    verifier: V,
+}
+
+impl<V: TokenVerifier> BearerAuth<V> {
+    pub fn new(realm: &str, verifier: V) -> Self {
+        BearerAuth {
+            realm: realm.to_owned(),
+            verifier,
+        }
+    }
+}
+
+impl<V: TokenVerifier> Middleware for BearerAuth<V> {
+    fn handle(&self, req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        let challenge = match authorization(&req, "bearer") {
+            Some(token) if self.verifier.verify(token) => return next.run(req, rsp),
+            Some(_) => format!(
+                "WWW-Authenticate: Bearer realm=\"{}\", error=\"invalid_token\"",
+                self.realm
+            ),
+            None => format!("WWW-Authenticate: Bearer realm=\"{}\"", self.realm),
+        };
+        rsp.status(StatusCode::Unauthorized).header(challenge);
+        Ok(())
+    }
+}
+
+/// the credentials of the `Authorization` header under the scheme
+fn authorization<'r>(req: &'r Request, scheme: &str) -> Option<&'r str> {
+    req.authorization()?.of_scheme(scheme)
+}
+
+/// decode standard base64, the padding is optional
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &c in s {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        // only the bits not output yet are kept
+        acc = (acc << 6 | v as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,170 @@
+//! choosing among several upstreams
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// how `ProxyService` spreads the requests over its upstreams
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// each upstream in turn, the weights are ignored
+    #[default]
+    RoundRobin,
+    /// in turn, each upstream gets a share of the requests after its weight
+    Weighted,
+    /// the upstream with the fewest requests in progress for its weight
+    LeastConnections,
+}
+
+/// an upstream and its health
+#[derive(Debug)]
+pub(crate) struct Backend {
+    // the `Host` sent to it
+    authority: String,
+    addrs: Vec<SocketAddr>,
+    weight: u32,
+    // the requests in progress, until their response body is sent
+    active: AtomicUsize,
+    // the failures since the last success
+    fails: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    pub(crate) fn new(authority: String, addrs: Vec<SocketAddr>, weight: u32) -> Self {
+        Backend {
+            authority,
+            addrs,
+            weight: weight.max(1),
+            active: AtomicUsize::new(0),
+            fails: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    #[inline]
+    pub(crate) fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+
+    /// count a failure, the upstream is left out for `eject_for` once
+    /// `max_fails` follow each other. 0 never ejects it
+    pub(crate) fn failed(&self, max_fails: u32, eject_for: Duration) {
+        let fails = self.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if max_fails == 0 || fails < max_fails {
+            return;
+        }
+        warn!(
+            "upstream {} failed {} times, ejected for {:?}",
+            self.authority, fails, eject_for
+        );
+        self.fails.store(0, Ordering::Relaxed);
+        *self.ejected_until.lock().unwrap() = Some(Instant::now() + eject_for);
+    }
+
+    pub(crate) fn succeeded(&self) {
+        self.fails.store(0, Ordering::Relaxed);
+    }
+}
+
+/// a request in progress on a backend, counted until dropped
+pub(crate) struct Lease {
+    backend: Arc<Backend>,
+    index: usize,
+}
+
+impl Lease {
+    #[inline]
+    pub(crate) fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    /// the position of the backend, to leave it out of a retry
+    #[inline]
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for Lease {
+    #[inline]
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// choose a backend that is not in `tried`
+///
+/// the ejected ones are only chosen when no other is left, so a request is
+/// still tried when they are all down. `next` is the turn shared by the requests
+pub(crate) fn pick(
+    backends: &[Arc<Backend>],
+    balance: Balance,
+    next: &AtomicUsize,
+    tried: &[usize],
+) -> Option<Lease> {
+    let now = Instant::now();
+    let untried = || (0..backends.len()).filter(|i| !tried.contains(i));
+    let mut candidates: Vec<usize> = untried()
+        .filter(|&i| backends[i].is_available(now))
+        .collect();
+    if candidates.is_empty() {
+        candidates = untried().collect();
+    }
+    if candidates.is_empty() {
+        return None;
+    }
+    let turn = next.fetch_add(1, Ordering::Relaxed);
+    let index = match balance {
+        Balance::RoundRobin => candidates[turn % candidates.len()],
+        Balance::Weighted => {
+            let total: u64 = candidates.iter().map(|&i| backends[i].weight as u64).sum();
+            let mut n = turn as u64 % total;
+            *candidates
+                .iter()
+                .find(|&&i| {
+                    let weight = backends[i].weight as u64;
+                    if n < weight {
+                        return true;
+                    }
+                    n -= weight;
+                    false
+                })
+                .unwrap_or(&candidates[0])
+        }
+        Balance::LeastConnections => {
+            // start at the turn so the ties are spread
+            let start = turn % candidates.len();
+            let load = |i: usize| {
+                let b = &backends[i];
+                (b.active.load(Ordering::Relaxed) as u64, b.weight as u64)
+            };
+            let mut best = candidates[start];
+            for &i in candidates[start..].iter().chain(&candidates[..start]) {
+                let (active, weight) = load(i);
+                let (best_active, best_weight) = load(best);
+                // active / weight < best_active / best_weight
+                if active * best_weight < best_active * weight {
+                    best = i;
+                }
+            }
+            best
+        }
+    };
+    let backend = backends[index].clone();
+    backend.active.fetch_add(1, Ordering::Relaxed);
+    Some(Lease { backend, index })
+}
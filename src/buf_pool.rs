@@ -1,86 +1,153 @@
-use bytes::BytesMut;
-use may::sync::Mutex;
-use once_cell::sync::Lazy;
+//! connection buffers reused across connections
 
 use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
 
-const MAX_BUFS: usize = 4096;
-const BUF_LEN: usize = 4096 * 8;
+use bytes::BytesMut;
 
-pub struct BytesBuf(BytesMut);
+/// the buffers kept by default, over all the shards
+pub(crate) const MAX_BUFS: usize = 1024;
 
-impl Deref for BytesBuf {
-    type Target = BytesMut;
-    fn deref(&self) -> &BytesMut {
-        &self.0
-    }
+/// how the connection buffers are sized
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BufSizes {
+    // the capacity of a fresh buffer
+    pub(crate) initial: usize,
+    // the request buffer grows back to `initial` when less room is left
+    pub(crate) min_free: usize,
+    // an empty buffer that grew past this is swapped for a fresh one
+    pub(crate) max: usize,
 }
 
-impl From<BytesMut> for BytesBuf {
-    fn from(value: BytesMut) -> Self {
-        Self(value)
+impl Default for BufSizes {
+    fn default() -> Self {
+        BufSizes {
+            initial: 4096 * 8,
+            min_free: 1024,
+            max: 4096 * 32,
+        }
     }
 }
 
-impl DerefMut for BytesBuf {
-    fn deref_mut(&mut self) -> &mut BytesMut {
-        &mut self.0
-    }
+/// the free buffers, sharded to keep the connections from contending on one lock
+pub(crate) struct BufPool {
+    sizes: BufSizes,
+    shards: Box<[Mutex<Vec<BytesMut>>]>,
+    // the buffers kept in each shard, none when 0
+    per_shard: usize,
 }
 
-impl Drop for BytesBuf {
-    fn drop(&mut self) {
-        let buf = std::mem::replace(self, BytesMut::new().into());
-        BUF_POOL.put(buf)
+impl BufPool {
+    /// a pool keeping up to `max` free buffers
+    pub(crate) fn new(max: usize, sizes: BufSizes) -> Self {
+        let shards = std::thread::available_parallelism().map_or(4, |n| n.get());
+        BufPool {
+            sizes,
+            shards: (0..shards).map(|_| Mutex::new(Vec::new())).collect(),
+            per_shard: max.div_ceil(shards),
+        }
+    }
+
+    /// an empty buffer for the connection, a free one if there is
+    pub(crate) fn get(&self, conn: usize) -> PooledBuf<'_> {
+        let shard = conn % self.shards.len();
+        let mut buf = PooledBuf {
+            pool: self,
+            shard,
+            buf: BytesMut::new(),
+        };
+        buf.ensure();
+        buf
+    }
+
+    fn take(&self, shard: usize) -> BytesMut {
+        if self.per_shard > 0 {
+            if let Some(buf) = self.shards[shard].lock().unwrap().pop() {
+                return buf;
+            }
+        }
+        BytesMut::with_capacity(self.sizes.initial)
+    }
+
+    fn put(&self, shard: usize, mut buf: BytesMut) {
+        if self.per_shard == 0 || buf.capacity() == 0 {
+            return;
+        }
+        // take back the room before the consumed bytes
+        buf.clear();
+        buf.reserve(self.sizes.initial);
+        if buf.capacity() > self.sizes.max {
+            return;
+        }
+        let mut free = self.shards[shard].lock().unwrap();
+        if free.len() < self.per_shard {
+            free.push(buf);
+        }
     }
 }
 
-pub struct BufBool {
-    // the pool must support mpmc operation!
-    pool: Mutex<Vec<BytesBuf>>,
+/// a connection buffer, it goes back to the pool when dropped
+pub(crate) struct PooledBuf<'a> {
+    pool: &'a BufPool,
+    shard: usize,
+    buf: BytesMut,
 }
 
-impl BufBool {
-    pub fn new() -> Self {
-        let capacity = MAX_BUFS;
-        let mut pool = Vec::new();
-        for _ in 0..capacity {
-            let buf = BytesMut::with_capacity(BUF_LEN).into();
-            pool.push(buf);
+impl<'a> PooledBuf<'a> {
+    /// give the buffer back while the connection is idle, it must be empty
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn release(&mut self) {
+        debug_assert!(self.buf.is_empty());
+        if self.buf.capacity() > 0 {
+            let buf = std::mem::take(&mut self.buf);
+            self.pool.put(self.shard, buf);
         }
+    }
 
-        BufBool {
-            pool: Mutex::new(pool),
+    /// get a buffer again after `release`, or a fresh one for an empty
+    /// buffer that grew too large
+    #[inline]
+    pub(crate) fn ensure(&mut self) {
+        let capacity = self.buf.capacity();
+        if capacity == 0 {
+            self.buf = self.pool.take(self.shard);
+        } else if capacity > self.pool.sizes.max && self.buf.is_empty() {
+            self.buf = BytesMut::with_capacity(self.pool.sizes.initial);
         }
     }
 
-    /// get a raw coroutine from the pool
+    /// make room for the next read
     #[inline]
-    pub fn get(&self) -> BytesBuf {
-        match self.pool.lock().unwrap().pop() {
-            Some(buf) => buf,
-            None => BytesMut::with_capacity(BUF_LEN).into(),
+    pub(crate) fn make_room(&mut self) {
+        let BufSizes {
+            initial, min_free, ..
+        } = self.pool.sizes;
+        let capacity = self.buf.capacity();
+        if capacity < min_free {
+            self.buf.reserve(initial.max(min_free) - capacity);
         }
     }
+}
+
+impl<'a> Deref for PooledBuf<'a> {
+    type Target = BytesMut;
 
-    /// put a raw coroutine into the pool
     #[inline]
-    pub fn put(&self, buf: BytesBuf) {
-        let mut pool = self.pool.lock().unwrap();
-        // discard the co if push failed
-        if pool.len() >= MAX_BUFS {
-            return;
-        }
-        pool.push(buf);
+    fn deref(&self) -> &BytesMut {
+        &self.buf
     }
 }
 
-pub static BUF_POOL: Lazy<BufBool> = Lazy::new(BufBool::new);
+impl<'a> DerefMut for PooledBuf<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
 
-#[inline]
-pub fn reserve_buf(buf: &mut BytesMut) {
-    let capacity = buf.capacity();
-    if capacity < 1024 {
-        buf.reserve(BUF_LEN - capacity);
+impl<'a> Drop for PooledBuf<'a> {
+    fn drop(&mut self) {
+        let buf = std::mem::take(&mut self.buf);
+        self.pool.put(self.shard, buf);
     }
 }
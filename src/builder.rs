@@ -0,0 +1,558 @@
+//! server configuration
+
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::access_log::{AccessLog, Sink};
+use crate::buf_pool::{BufSizes, MAX_BUFS};
+use crate::error_handler::{ErrorHandler, OnError};
+use crate::handle::ServerHandle;
+use crate::hooks::{Hooks, ServerHooks};
+use crate::http_server::{self, HttpServiceFactory, Plain};
+use crate::metrics::Metrics;
+use crate::proxy_protocol::Trusted;
+use crate::request::{MAX_HEADERS, MAX_HEAD_SIZE};
+use crate::socket::SocketOptions;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use crate::tls::TlsAcceptor;
+
+/// the settings shared by all the connections of a server
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub(crate) max_body_size: usize,
+    pub(crate) max_headers: usize,
+    pub(crate) max_header_size: usize,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) header_timeout: Option<Duration>,
+    pub(crate) max_requests: Option<usize>,
+    pub(crate) max_in_flight: Option<usize>,
+    pub(crate) max_connections: Option<usize>,
+    // answer the connections over the limit instead of leaving them queued
+    pub(crate) shed_connections: bool,
+    // the processes serving the listeners, forked at the start
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) workers: usize,
+    // of the connection coroutines, may's default when unset
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) max_queued_response: usize,
+    pub(crate) lenient_framing: bool,
+    pub(crate) access_log: Option<Sink>,
+    pub(crate) metrics: Option<Metrics>,
+    pub(crate) hooks: Option<Hooks>,
+    pub(crate) error_handler: Option<OnError>,
+    pub(crate) socket: SocketOptions,
+    pub(crate) proxy_protocol: Option<Trusted>,
+    pub(crate) buffer_pool: usize,
+    pub(crate) buffer_sizes: BufSizes,
+    // the whole `Server` header line, empty to leave it out
+    pub(crate) server_header: Bytes,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_body_size: usize::MAX,
+            max_headers: MAX_HEADERS,
+            max_header_size: MAX_HEAD_SIZE,
+            request_timeout: None,
+            idle_timeout: None,
+            header_timeout: None,
+            max_requests: None,
+            max_in_flight: None,
+            max_connections: None,
+            shed_connections: false,
+            workers: 1,
+            stack_size: None,
+            max_queued_response: 256 * 1024,
+            lenient_framing: false,
+            access_log: None,
+            metrics: None,
+            hooks: None,
+            error_handler: None,
+            socket: SocketOptions::default(),
+            proxy_protocol: None,
+            buffer_pool: MAX_BUFS,
+            buffer_sizes: BufSizes::default(),
+            server_header: Bytes::from_static(b"\r\nServer: M"),
+        }
+    }
+}
+
+impl Config {
+    /// how often the connection deadlines are checked, `None` without any timer
+    pub(crate) fn sweep_interval(&self) -> Option<Duration> {
+        let timeout = match (self.idle_timeout, self.header_timeout) {
+            (Some(idle), Some(head)) => idle.min(head),
+            (idle, head) => idle.or(head)?,
+        };
+        Some((timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1)))
+    }
+}
+
+/// builder of a configured http server
+///
+/// `HttpServer::start` and `HttpServiceFactory::start` use the default settings
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    config: Config,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the largest request body accepted, for both `Content-Length` and chunked bodies
+    ///
+    /// a bigger one is answered with `413 Payload Too Large` and the connection is closed.
+    /// unlimited by default
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.config.max_body_size = size;
+        self
+    }
+
+    /// the most headers a request may carry, 16 by default
+    ///
+    /// a request with more is answered with `431 Request Header Fields Too Large`
+    /// and the connection is closed
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.config.max_headers = count;
+        self
+    }
+
+    /// the limit of the request line plus all the header bytes, 64KiB by default
+    ///
+    /// a bigger head is answered with `431 Request Header Fields Too Large`
+    /// and the connection is closed, a client can't grow the request buffer past it
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.config.max_header_size = size;
+        self
+    }
+
+    /// the most requests served over one keep-alive connection, unlimited by default
+    ///
+    /// the last response is sent with `Connection: close` and the connection is closed,
+    /// the client opens a new one for its next request
+    pub fn max_requests(mut self, count: usize) -> Self {
+        self.config.max_requests = Some(count);
+        self
+    }
+
+    /// the most requests handled at the same time over all the connections,
+    /// unlimited by default
+    ///
+    /// a request beyond the limit is not passed to the service, it is answered
+    /// with `503 Service Unavailable` right away so an overload spike is shed
+    /// instead of piling up coroutines
+    pub fn max_in_flight(mut self, count: usize) -> Self {
+        self.config.max_in_flight = Some(count);
+        self
+    }
+
+    /// the most connections open at the same time, unlimited by default
+    ///
+    /// at the limit the listeners stop accepting until a connection closes,
+    /// the new ones wait in the listen backlog. see `shed_connections`
+    pub fn max_connections(mut self, count: usize) -> Self {
+        self.config.max_connections = Some(count);
+        self
+    }
+
+    /// keep accepting past `max_connections` and answer the extra connections
+    /// with `503 Service Unavailable` before closing them, off by default
+    ///
+    /// the clients learn about the overload right away instead of waiting
+    /// for the server to take them out of the backlog
+    pub fn shed_connections(mut self, shed: bool) -> Self {
+        self.config.shed_connections = shed;
+        self
+    }
+
+    /// serve the listeners from this many processes, 1 by default
+    ///
+    /// the `start` methods bind the listeners then fork the other workers,
+    /// which share them and each run their own scheduler, so a stalled
+    /// worker only holds up its own connections. every worker goes on from
+    /// `start` with its own handle. the process must not have started any
+    /// coroutine before, the workers only keep the forking thread
+    #[cfg(target_os = "linux")]
+    pub fn workers(mut self, count: usize) -> Self {
+        self.config.workers = count.max(1);
+        self
+    }
+
+    /// the stack size of the connection coroutines, the one set with
+    /// `may::config().set_stack_size` by default, in the same unit
+    ///
+    /// the services run on this stack, deep ones need more than may's default
+    /// while trivial ones can do with less and keep more connections in memory.
+    /// the other coroutines of the process keep the default
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.config.stack_size = Some(size);
+        self
+    }
+
+    /// the response bytes queued for a slow client before the pipelined
+    /// requests are held off, 256KiB by default
+    ///
+    /// past the limit no more requests are read or parsed until the queued
+    /// responses are written out, so a client pipelining requests without
+    /// reading the responses can't grow the connection buffers
+    pub fn max_queued_response(mut self, size: usize) -> Self {
+        self.config.max_queued_response = size;
+        self
+    }
+
+    /// the product token of the `Server` header sent with every response,
+    /// `M` by default
+    ///
+    /// the header line is formatted once here, the responses only copy it
+    pub fn server_header(mut self, product: &str) -> Self {
+        assert!(
+            !product.contains(['\r', '\n']),
+            "line break in the Server header"
+        );
+        self.config.server_header = Bytes::from(format!("\r\nServer: {product}"));
+        self
+    }
+
+    /// leave the `Server` header out of the responses
+    pub fn no_server_header(mut self) -> Self {
+        self.config.server_header = Bytes::new();
+        self
+    }
+
+    /// accept the requests with an ambiguous body length, off by default
+    ///
+    /// a request with both `Content-Length` and `Transfer-Encoding`, or with
+    /// `Content-Length` values that disagree, is answered with `400 Bad Request`
+    /// and the connection is closed, as a proxy in front may frame it another way
+    /// and smuggle a request in its body. lenient, the body is framed by
+    /// `Transfer-Encoding` first, then the last `Content-Length`, and the
+    /// connection is still closed after the response
+    pub fn lenient_framing(mut self, lenient: bool) -> Self {
+        self.config.lenient_framing = lenient;
+        self
+    }
+
+    /// record every served request to the sink, nothing is logged by default
+    ///
+    /// the record has the request line, the status, the body length, the time
+    /// the request took and the client address
+    pub fn access_log<L: AccessLog>(mut self, sink: L) -> Self {
+        self.config.access_log = Some(Sink(Arc::new(sink)));
+        self
+    }
+
+    /// count the requests, their duration, the connections and the bytes in the metrics,
+    /// mount the same `Metrics` as a handler to expose them
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// call the hooks on the connection and request events
+    pub fn hooks<H: ServerHooks>(mut self, hooks: H) -> Self {
+        self.config.hooks = Some(Hooks(Arc::new(hooks)));
+        self
+    }
+
+    /// answer the errors returned by the service with the handler
+    /// instead of a `500` holding the error message
+    pub fn error_handler<H: ErrorHandler>(mut self, handler: H) -> Self {
+        self.config.error_handler = Some(OnError(Arc::new(handler)));
+        self
+    }
+
+    /// the time a service call may take, there is no limit by default
+    ///
+    /// the handler runs in its own coroutine, when it is late it gets canceled
+    /// and the client is answered with `504 Gateway Timeout` before the connection
+    /// is closed, the service may have been stopped halfway.
+    /// the cancel only takes effect at a blocking `may` call (io, channel, sleep, ...)
+    /// and requires unwinding, the server fails to start with `InvalidInput` in a
+    /// build with `panic = "abort"`, like the release profile of this crate
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = Some(timeout);
+        self
+    }
+
+    /// close keep-alive connections that have no request for this long,
+    /// they are kept open forever by default
+    ///
+    /// a client that takes none of the queued responses for this long is
+    /// dropped too, except outside unix where the responses are written blocking
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// the time a client has to send a complete request head, no limit by default
+    ///
+    /// the timer starts with the first byte of the request and is not pushed back
+    /// by the bytes that follow, so a client trickling the head in can't hold the
+    /// connection. a late request is answered with `408 Request Timeout` and the
+    /// connection is closed. a buffered body has to arrive in the same time.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.config.header_timeout = Some(timeout);
+        self
+    }
+
+    /// set `TCP_NODELAY` on the connections, so small responses are not held back
+    /// waiting for more data. it is off by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.socket.nodelay = nodelay;
+        self
+    }
+
+    /// enable TCP keepalive on the connections, probing a connection
+    /// after it has been idle for `time`
+    pub fn keepalive(mut self, time: Duration) -> Self {
+        self.config.socket.keepalive = Some(time);
+        self
+    }
+
+    /// the time between the keepalive probes, the system default if not set
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.config.socket.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// the keepalive probes sent before the connection is dropped,
+    /// the system default if not set. it can't be set on windows
+    pub fn keepalive_retries(mut self, retries: u32) -> Self {
+        self.config.socket.keepalive_retries = Some(retries);
+        self
+    }
+
+    /// the queue length of the connections waiting to be accepted, 128 by default
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.config.socket.backlog = Some(backlog);
+        self
+    }
+
+    /// the `SO_SNDBUF` size of the sockets
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.config.socket.send_buffer_size = Some(size);
+        self
+    }
+
+    /// the `SO_RCVBUF` size of the sockets
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.config.socket.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// read the PROXY protocol header, version 1 or 2, a load balancer sends
+    /// ahead of each connection, `Request::remote_addr` is then the client behind it
+    ///
+    /// a connection without the header is dropped, so this is only for
+    /// listeners no one else can reach, see `proxy_protocol_listeners`
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.config.proxy_protocol = enabled.then_some(Trusted::All);
+        self
+    }
+
+    /// expect the PROXY protocol header only on these listeners,
+    /// by their index in the addresses of `start_all`
+    pub fn proxy_protocol_listeners(mut self, listeners: &[usize]) -> Self {
+        self.config.proxy_protocol = Some(Trusted::Listeners(listeners.to_vec()));
+        self
+    }
+
+    /// the free connection buffers kept for the next connections, 1024 by default.
+    /// 0 allocates the buffers of each connection anew
+    ///
+    /// the buffers of an idle keep-alive connection go back to the pool while it
+    /// waits for its next request, so the memory follows the busy connections
+    /// rather than the open ones
+    pub fn buffer_pool(mut self, max_buffers: usize) -> Self {
+        self.config.buffer_pool = max_buffers;
+        self
+    }
+
+    /// the capacity of a fresh connection buffer, 32KiB by default
+    ///
+    /// each connection holds three while busy, for the request, the response
+    /// and its body, a small one suits many small requests on little memory
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.config.buffer_sizes.initial = size;
+        self
+    }
+
+    /// the room left in the request buffer under which it is grown back to
+    /// `buffer_size` before the next read, 1KiB by default
+    pub fn buffer_min_free(mut self, size: usize) -> Self {
+        self.config.buffer_sizes.min_free = size;
+        self
+    }
+
+    /// a buffer grown past this by a large request or response is swapped for
+    /// a fresh one once emptied, and never pooled. 128KiB by default
+    pub fn max_buffer_size(mut self, size: usize) -> Self {
+        self.config.buffer_sizes.max = size;
+        self
+    }
+
+    /// Spawns the http service, binding to the given address
+    /// return a handle to wait for or shut down the server
+    pub fn start<L: ToSocketAddrs, F: HttpServiceFactory>(
+        self,
+        addr: L,
+        factory: F,
+    ) -> io::Result<ServerHandle> {
+        let listener = self.config.socket.bind(addr)?;
+        http_server::serve(vec![listener], factory, Plain, self.config, "TcpServer")
+    }
+
+    /// Spawns the http service on all the addresses, like `0.0.0.0:80` and `[::]:80`,
+    /// `ConnectionInfo::listener` is the index of the address the connection came in on
+    /// return a handle to wait for or shut down the server
+    ///
+    /// the addresses are TCP only, Unix domain sockets are not supported
+    pub fn start_all<I, L, F>(self, addrs: I, factory: F) -> io::Result<ServerHandle>
+    where
+        I: IntoIterator<Item = L>,
+        L: ToSocketAddrs,
+        F: HttpServiceFactory,
+    {
+        http_server::serve(
+            bind_all(&self.config.socket, addrs)?,
+            factory,
+            Plain,
+            self.config,
+            "TcpServer",
+        )
+    }
+
+    /// Spawns the http service on a listener that is already bound,
+    /// by a supervisor, a parent process handing it over or a test on port 0
+    /// return a handle to wait for or shut down the server
+    pub fn start_with_listener<F: HttpServiceFactory>(
+        self,
+        listener: TcpListener,
+        factory: F,
+    ) -> io::Result<ServerHandle> {
+        http_server::serve(vec![listener], factory, Plain, self.config, "TcpServer")
+    }
+
+    /// Spawns the http service on an inherited listening socket
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open listening TCP socket that nothing else owns,
+    /// it is closed with the server
+    #[cfg(unix)]
+    pub unsafe fn start_with_fd<F: HttpServiceFactory>(
+        self,
+        fd: RawFd,
+        factory: F,
+    ) -> io::Result<ServerHandle> {
+        self.start_with_listener(TcpListener::from_raw_fd(fd), factory)
+    }
+
+    /// Spawns the http service on the sockets passed by systemd
+    /// and tell it the server is ready
+    /// return a handle to wait for or shut down the server
+    ///
+    /// `ConnectionInfo::listener` is the index of the socket in the socket unit,
+    /// it fails with `NotFound` when the process was not socket activated
+    #[cfg(target_os = "linux")]
+    pub fn start_activated<F: HttpServiceFactory>(self, factory: F) -> io::Result<ServerHandle> {
+        let listeners = crate::systemd::listen_fds()?;
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no socket passed by systemd",
+            ));
+        }
+        let handle = http_server::serve(listeners, factory, Plain, self.config, "TcpServer")?;
+        crate::systemd::notify_ready()?;
+        Ok(handle)
+    }
+
+    /// Spawns the http service on the sockets handed over by the process
+    /// being replaced, see `handoff::hand_over`
+    /// return a handle to wait for or shut down the server
+    ///
+    /// it fails with `NotFound` when the process was given no socket
+    #[cfg(target_os = "linux")]
+    pub fn start_inherited<F: HttpServiceFactory>(self, factory: F) -> io::Result<ServerHandle> {
+        let listeners = crate::handoff::inherited_listeners()?;
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no socket handed over",
+            ));
+        }
+        http_server::serve(listeners, factory, Plain, self.config, "TcpServer")
+    }
+
+    /// Spawns the https service, binding to the given address
+    /// each accepted connection is wrapped in a TLS session by the `acceptor`
+    /// return a handle to wait for or shut down the server
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub fn start_tls<L: ToSocketAddrs, F: HttpServiceFactory, A: TlsAcceptor>(
+        self,
+        addr: L,
+        factory: F,
+        acceptor: A,
+    ) -> io::Result<ServerHandle> {
+        let listener = self.config.socket.bind(addr)?;
+        http_server::serve(vec![listener], factory, acceptor, self.config, "TlsServer")
+    }
+
+    /// Spawns the https service on all the addresses
+    /// each accepted connection is wrapped in a TLS session by the `acceptor`
+    /// return a handle to wait for or shut down the server
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub fn start_tls_all<I, L, F, A>(
+        self,
+        addrs: I,
+        factory: F,
+        acceptor: A,
+    ) -> io::Result<ServerHandle>
+    where
+        I: IntoIterator<Item = L>,
+        L: ToSocketAddrs,
+        F: HttpServiceFactory,
+        A: TlsAcceptor,
+    {
+        http_server::serve(
+            bind_all(&self.config.socket, addrs)?,
+            factory,
+            acceptor,
+            self.config,
+            "TlsServer",
+        )
+    }
+
+    /// Spawns the https service on a listener that is already bound
+    /// each accepted connection is wrapped in a TLS session by the `acceptor`
+    /// return a handle to wait for or shut down the server
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub fn start_tls_with_listener<F: HttpServiceFactory, A: TlsAcceptor>(
+        self,
+        listener: TcpListener,
+        factory: F,
+        acceptor: A,
+    ) -> io::Result<ServerHandle> {
+        http_server::serve(vec![listener], factory, acceptor, self.config, "TlsServer")
+    }
+}
+
+/// a listener for each address, in order
+fn bind_all<I, L>(socket: &SocketOptions, addrs: I) -> io::Result<Vec<TcpListener>>
+where
+    I: IntoIterator<Item = L>,
+    L: ToSocketAddrs,
+{
+    addrs.into_iter().map(|addr| socket.bind(addr)).collect()
+}
@@ -0,0 +1,615 @@
+//! a blocking HTTP/1.1 client for the coroutines
+//!
+//! the requests are sent over may's `TcpStream`, so a service can call other
+//! services without blocking its worker thread. the connections are kept alive
+//! and reused for the next requests to the same host, the redirects are followed
+//!
+//! ```ignore
+//! let rsp = client::get("http://127.0.0.1:3000/users/1")?;
+//! if rsp.status() == 200 {
+//!     let user = rsp.text()?;
+//! }
+//! let api = Client::new().timeout(Duration::from_secs(5)).max_redirects(0);
+//! let rsp = api
+//!     .request("PUT", "http://127.0.0.1:3000/users/1")?
+//!     .header("Content-Type", "application/json")
+//!     .body(r#"{"name":"ann"}"#)
+//!     .send()?;
+//! ```
+//!
+//! only `http://` urls are supported
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::headers;
+use crate::pool::Pool;
+use crate::status::StatusCode;
+use crate::upstream::{read_head, Head, ResponseBody, Upstream};
+
+/// the redirects followed by default
+const MAX_REDIRECTS: usize = 10;
+/// the most of a redirect body that is read to reuse the connection
+const DRAIN_LEN: u64 = 64 * 1024;
+
+// the client of the functions of the module
+static CLIENT: Lazy<Client> = Lazy::new(Client::new);
+
+/// send a `GET` request with the shared client
+pub fn get(url: &str) -> io::Result<Response> {
+    CLIENT.get(url)
+}
+
+/// send a `POST` request with the body with the shared client
+pub fn post(url: &str, body: impl Into<Vec<u8>>) -> io::Result<Response> {
+    CLIENT.post(url, body)
+}
+
+/// build a request for the shared client, it is sent by `RequestBuilder::send`
+pub fn request(method: &str, url: &str) -> io::Result<RequestBuilder> {
+    CLIENT.request(method, url)
+}
+
+/// the settings and the idle connections shared by its requests, the clones
+/// share the connections
+#[derive(Debug, Clone)]
+pub struct Client {
+    pool: Arc<Pool>,
+    max_redirects: usize,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client {
+            pool: Arc::new(Pool::default()),
+            max_redirects: MAX_REDIRECTS,
+            connect_timeout: None,
+            timeout: None,
+        }
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the redirects followed before giving up, 10 by default. with 0 the
+    /// redirect responses are returned as they are
+    pub fn max_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// give up connecting to a server after this long
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// give up on a server that is silent for this long, while sending a
+    /// request or reading a response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// the idle connections kept for each server address, 8 by default.
+    /// 0 closes each connection after its response
+    pub fn pool_max_idle(mut self, max: usize) -> Self {
+        self.pool = Arc::new(Pool::new(max, self.pool.max_lifetime()));
+        self
+    }
+
+    /// close the connections that are older than this instead of reusing them
+    pub fn pool_max_lifetime(mut self, max: Duration) -> Self {
+        self.pool = Arc::new(Pool::new(self.pool.max_idle(), Some(max)));
+        self
+    }
+
+    /// send a `GET` request
+    pub fn get(&self, url: &str) -> io::Result<Response> {
+        self.request("GET", url)?.send()
+    }
+
+    /// send a `POST` request with the body
+    pub fn post(&self, url: &str, body: impl Into<Vec<u8>>) -> io::Result<Response> {
+        self.request("POST", url)?.body(body).send()
+    }
+
+    /// build a request, it is sent by `RequestBuilder::send`
+    pub fn request(&self, method: &str, url: &str) -> io::Result<RequestBuilder> {
+        check_token(method)?;
+        Ok(RequestBuilder {
+            client: self.clone(),
+            method: method.to_owned(),
+            url: Url::parse(url)?,
+            headers: Vec::new(),
+            body: Vec::new(),
+            connect_timeout: self.connect_timeout,
+            timeout: self.timeout,
+        })
+    }
+}
+
+/// a request to send
+#[derive(Debug, Clone)]
+pub struct RequestBuilder {
+    client: Client,
+    method: String,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+}
+
+impl RequestBuilder {
+    /// add a header, `Host`, `Content-Length` and `Connection` are set by the client
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// give up connecting to the server after this long, instead of the
+    /// timeout of the client
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// give up on a server that is silent for this long, instead of the
+    /// timeout of the client
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// send the request and read the head of the response, following the
+    /// redirects. the body is read from the returned response
+    pub fn send(mut self) -> io::Result<Response> {
+        let mut redirects = 0;
+        loop {
+            let mut rsp = self.exchange()?;
+            let location = match rsp.header("location") {
+                Some(location) if is_redirect(rsp.status) => location,
+                _ => return Ok(rsp),
+            };
+            if redirects == self.client.max_redirects {
+                if redirects == 0 {
+                    return Ok(rsp);
+                }
+                return Err(io::Error::other("too many redirects"));
+            }
+            // a location the client can't follow is left to the caller
+            let next = match self.url.join(location) {
+                Ok(next) => next,
+                Err(_) => return Ok(rsp),
+            };
+            redirects += 1;
+            if rsp.status == 303 || (matches!(rsp.status, 301 | 302) && self.method == "POST") {
+                self.method = if self.method == "HEAD" { "HEAD" } else { "GET" }.to_owned();
+                self.body.clear();
+                self.headers
+                    .retain(|(n, _)| !n.eq_ignore_ascii_case("content-type"));
+            }
+            if next.authority != self.url.authority {
+                // the credentials are for the first host only
+                self.headers.retain(|(n, _)| {
+                    !n.eq_ignore_ascii_case("authorization") && !n.eq_ignore_ascii_case("cookie")
+                });
+            }
+            self.url = next;
+            // read a short body to the end so the connection goes back to the pool
+            io::copy(&mut (&mut rsp).take(DRAIN_LEN), &mut io::sink()).ok();
+        }
+    }
+
+    /// send the request once, again on a new connection when a reused one
+    /// turns out to be closed
+    fn exchange(&self) -> io::Result<Response> {
+        let head = self.encode_head()?;
+        let mut upstream = self.connect(true)?;
+        let mut ret = self.round_trip(&head, &mut upstream);
+        if ret.is_err() && upstream.is_reused() && upstream.is_untouched() {
+            upstream = self.connect(false)?;
+            ret = self.round_trip(&head, &mut upstream);
+        }
+        let (head, headers) = ret?;
+        let has_body = self.method != "HEAD";
+        Ok(Response {
+            status: head.code,
+            headers,
+            url: self.url.to_string(),
+            body: ResponseBody::new(upstream, head, has_body, Some(self.client.pool.clone())),
+        })
+    }
+
+    /// send the request, read the head of the response and its headers
+    fn round_trip(
+        &self,
+        head: &[u8],
+        upstream: &mut Upstream,
+    ) -> io::Result<(Head, Vec<(String, String)>)> {
+        upstream.write_all(head)?;
+        upstream.write_all(&self.body)?;
+        upstream.flush()?;
+
+        let mut headers = Vec::new();
+        let head = read_head(upstream, |h| {
+            headers = h
+                .iter()
+                .filter_map(|h| {
+                    let value = std::str::from_utf8(h.value).ok()?;
+                    Some((h.name.to_owned(), value.to_owned()))
+                })
+                .collect();
+        })?;
+        Ok((head, headers))
+    }
+
+    /// an idle connection from the pool if `reuse` and there is one, else a new one
+    fn connect(&self, reuse: bool) -> io::Result<Upstream> {
+        let addrs = self.url.addrs()?;
+        if reuse {
+            let idle = addrs
+                .iter()
+                .find_map(|&addr| self.client.pool.checkout(addr));
+            if let Some(upstream) = idle {
+                upstream.set_timeout(self.timeout)?;
+                return Ok(upstream);
+            }
+        }
+        let mut last = None;
+        for addr in addrs {
+            match Upstream::connect(addr, self.connect_timeout, self.timeout) {
+                Ok(upstream) => return Ok(upstream),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| io::ErrorKind::NotConnected.into()))
+    }
+
+    fn encode_head(&self) -> io::Result<Vec<u8>> {
+        let own = ["host", "content-length", "transfer-encoding", "connection"];
+        let mut head = Vec::with_capacity(256);
+        write!(head, "{} {} HTTP/1.1\r\n", self.method, self.url.path)?;
+        write!(head, "Host: {}\r\n", self.url.authority)?;
+        for (name, value) in &self.headers {
+            check_token(name)?;
+            if !headers::is_field_value(value) {
+                return Err(invalid_input("invalid header value"));
+            }
+            if own.iter().any(|n| name.eq_ignore_ascii_case(n)) {
+                continue;
+            }
+            write!(head, "{name}: {value}\r\n")?;
+        }
+        if !self.body.is_empty() || matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") {
+            write!(head, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if self.client.pool.max_idle() == 0 {
+            head.extend_from_slice(b"Connection: close\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        Ok(head)
+    }
+}
+
+/// the status sends the client to the `Location`
+#[inline]
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// the response of the server, reading it reads the body
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    // after the redirects
+    url: String,
+    body: ResponseBody,
+}
+
+impl Response {
+    /// the status code
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// the status, `None` for a code that is not known
+    pub fn status_code(&self) -> Option<StatusCode> {
+        StatusCode::from_u16(self.status)
+    }
+
+    /// the url that answered, the last one of the redirects
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// the headers, in the order they were received
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// the value of the first header with the name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// read the whole body
+    pub fn bytes(mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        self.body.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// read the whole body as utf-8
+    pub fn text(mut self) -> io::Result<String> {
+        let mut body = String::new();
+        self.body.read_to_string(&mut body)?;
+        Ok(body)
+    }
+}
+
+impl Read for Response {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("url", &self.url)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+/// the parts of an `http://` url
+#[derive(Debug, Clone)]
+struct Url {
+    // `host` or `host:port`, sent as the `Host`
+    authority: String,
+    host: String,
+    port: u16,
+    // the path and the query
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> io::Result<Url> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some(_) => return Err(invalid_input("only http urls are supported")),
+            None => return Err(invalid_input("url without a scheme")),
+        };
+        // the fragment is not sent
+        let rest = rest.split_once('#').map_or(rest, |(r, _)| r);
+        let end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(end);
+        if authority.is_empty() || authority.contains('@') {
+            return Err(invalid_input("invalid url host"));
+        }
+        // the port is after the closing bracket of an IPv6 address
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| invalid_input("invalid url port"))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let path = match path {
+            "" => "/".to_owned(),
+            p if p.starts_with('?') => format!("/{p}"),
+            p => p.to_owned(),
+        };
+        Ok(Url {
+            authority: authority.to_owned(),
+            host: host.to_owned(),
+            port,
+            path: check_path(path)?,
+        })
+    }
+
+    /// the url of a `Location`, absolute or relative to this one
+    fn join(&self, location: &str) -> io::Result<Url> {
+        let location = location.split_once('#').map_or(location, |(l, _)| l);
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&format!("http://{rest}"));
+        }
+        let path = if location.starts_with('/') {
+            location.to_owned()
+        } else {
+            // next to the last segment of the path
+            let base = self
+                .path
+                .split_once('?')
+                .map_or(self.path.as_str(), |(p, _)| p);
+            let dir = base.rsplit_once('/').map_or("", |(dir, _)| dir);
+            match location {
+                "" => self.path.clone(),
+                l if l.starts_with('?') => format!("{base}{l}"),
+                l => format!("{dir}/{l}"),
+            }
+        };
+        Ok(Url {
+            path: check_path(path)?,
+            ..self.clone()
+        })
+    }
+
+    fn addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok((self.host.as_str(), self.port).to_socket_addrs()?.collect())
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority, self.path)
+    }
+}
+
+/// a path that can go on the request line as it is
+fn check_path(path: String) -> io::Result<String> {
+    if path.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()) {
+        return Err(invalid_input("invalid url path"));
+    }
+    Ok(path)
+}
+
+/// a method or header name is a token, RFC 9110 section 5.6.2
+fn check_token(s: &str) -> io::Result<()> {
+    if !headers::is_token(s) {
+        return Err(invalid_input("invalid token"));
+    }
+    Ok(())
+}
+
+fn invalid_input(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn urls() {
+        let u = url("http://example.com");
+        assert_eq!(
+            (u.authority.as_str(), u.host.as_str(), u.port),
+            ("example.com", "example.com", 80)
+        );
+        assert_eq!(u.path, "/");
+        let u = url("HTTP://127.0.0.1:8080/a/b?x=1#frag");
+        assert_eq!(
+            (u.host.as_str(), u.port, u.path.as_str()),
+            ("127.0.0.1", 8080, "/a/b?x=1")
+        );
+        assert_eq!(u.to_string(), "http://127.0.0.1:8080/a/b?x=1");
+        let u = url("http://[::1]:3000?q");
+        assert_eq!(
+            (u.authority.as_str(), u.host.as_str(), u.port),
+            ("[::1]:3000", "::1", 3000)
+        );
+        assert_eq!(u.path, "/?q");
+        let u = url("http://[2001:db8::1]/");
+        assert_eq!((u.host.as_str(), u.port), ("2001:db8::1", 80));
+
+        let bad = [
+            "https://example.com/",
+            "example.com/",
+            "http:///a",
+            "http://user:pw@example.com/",
+            "http://example.com:http/",
+            "http://example.com:99999/",
+            "http://example.com/a b",
+            "http://example.com/a\r\nX: 1",
+        ];
+        for s in bad {
+            assert_eq!(
+                Url::parse(s).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput,
+                "{s}"
+            );
+        }
+    }
+
+    #[test]
+    fn redirects() {
+        let base = url("http://a.example:8080/dir/page?x=1");
+        let join = |l| base.join(l).unwrap().to_string();
+        assert_eq!(join("http://b.example/x"), "http://b.example/x");
+        assert_eq!(join("//b.example/x"), "http://b.example/x");
+        assert_eq!(join("/root?y"), "http://a.example:8080/root?y");
+        assert_eq!(join("other"), "http://a.example:8080/dir/other");
+        assert_eq!(join("?y=2"), "http://a.example:8080/dir/page?y=2");
+        assert_eq!(join(""), "http://a.example:8080/dir/page?x=1");
+        assert_eq!(join("next#frag"), "http://a.example:8080/dir/next");
+        assert!(base.join("https://b.example/").is_err());
+        assert!(base.join("/a b").is_err());
+        assert!(is_redirect(302) && is_redirect(308) && !is_redirect(304) && !is_redirect(300));
+    }
+
+    #[test]
+    fn request_head() {
+        let head = |req: RequestBuilder| String::from_utf8(req.encode_head().unwrap()).unwrap();
+        let client = Client::new();
+        let get = client.request("GET", "http://a.example/x?y").unwrap();
+        assert_eq!(head(get), "GET /x?y HTTP/1.1\r\nHost: a.example\r\n\r\n");
+
+        let post = client
+            .request("POST", "http://a.example:81/")
+            .unwrap()
+            .header("Content-Type", "text/plain")
+            // set by the client
+            .header("host", "evil.example")
+            .header("Content-Length", "1")
+            .header("Connection", "upgrade")
+            .body("hello");
+        assert_eq!(
+            head(post),
+            "POST / HTTP/1.1\r\nHost: a.example:81\r\nContent-Type: text/plain\r\n\
+             Content-Length: 5\r\n\r\n"
+        );
+        let put = client.request("PUT", "http://a.example/").unwrap();
+        assert!(head(put).ends_with("Content-Length: 0\r\n\r\n"));
+
+        let no_pool = Client::new().pool_max_idle(0);
+        let get = no_pool.request("GET", "http://a.example/").unwrap();
+        assert!(head(get).ends_with("Connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let client = Client::new();
+        for method in ["", "GE T", "GET\r\n", "G:T"] {
+            let err = client.request(method, "http://a.example/").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{method:?}");
+        }
+        let req = || client.request("GET", "http://a.example/").unwrap();
+        let bad = [
+            ("X-A", "1\r\nX-B: 2"),
+            ("X-A", "\0"),
+            ("X A", "1"),
+            ("", "1"),
+        ];
+        for (name, value) in bad {
+            let err = req().header(name, value).encode_head().unwrap_err();
+            assert_eq!(
+                err.kind(),
+                io::ErrorKind::InvalidInput,
+                "{name:?}: {value:?}"
+            );
+        }
+    }
+}
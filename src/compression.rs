@@ -0,0 +1,343 @@
+//! response compression
+
+use std::io;
+#[cfg(any(feature = "gzip", feature = "br"))]
+use std::io::Write;
+
+use crate::headers;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+
+/// a content coding, the variants are enabled by the cargo feature of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coding {
+    #[cfg(feature = "br")]
+    Br,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Coding {
+    /// in the order of preference when the client accepts several as much
+    const ALL: &'static [Coding] = &[
+        #[cfg(feature = "br")]
+        Coding::Br,
+        #[cfg(feature = "zstd")]
+        Coding::Zstd,
+        #[cfg(feature = "gzip")]
+        Coding::Gzip,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "br")]
+            Coding::Br => "br",
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => "zstd",
+            #[cfg(feature = "gzip")]
+            Coding::Gzip => "gzip",
+        }
+    }
+}
+
+/// compress the response bodies with the best coding the client accepts
+///
+/// the codings are enabled by the `gzip`, `br` and `zstd` cargo features,
+/// the one with the highest `q` in `Accept-Encoding` is used, on a tie
+/// `br` is preferred over `zstd` over `gzip`.
+/// only bodies held in memory are compressed, streams and files are sent as is.
+/// a response that already has a `Content-Encoding`, is smaller than the minimum
+/// size or has no body by its status is left alone.
+/// `Content-Length` is computed from the compressed body
+///
+/// ```ignore
+/// let service = Chain::new(Api).wrap(Compression::new().min_size(1024).br_quality(5));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: usize,
+    #[cfg(feature = "gzip")]
+    gzip_level: u32,
+    #[cfg(feature = "br")]
+    br_quality: u32,
+    #[cfg(feature = "zstd")]
+    zstd_level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            min_size: 256,
+            #[cfg(feature = "gzip")]
+            gzip_level: 6,
+            #[cfg(feature = "br")]
+            br_quality: 4,
+            #[cfg(feature = "zstd")]
+            zstd_level: 3,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the smallest body worth compressing, 256 bytes by default
+    pub fn min_size(mut self, size: usize) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// the gzip level from 0 (none) to 9 (best), 6 by default
+    #[cfg(feature = "gzip")]
+    pub fn gzip_level(mut self, level: u32) -> Self {
+        self.gzip_level = level.min(9);
+        self
+    }
+
+    /// the brotli quality from 0 (fastest) to 11 (best), 4 by default
+    #[cfg(feature = "br")]
+    pub fn br_quality(mut self, quality: u32) -> Self {
+        self.br_quality = quality.min(11);
+        self
+    }
+
+    /// the zstd level from 1 (fastest) to 22 (best), 3 by default
+    #[cfg(feature = "zstd")]
+    pub fn zstd_level(mut self, level: i32) -> Self {
+        self.zstd_level = level.clamp(1, 22);
+        self
+    }
+
+    fn compress(&self, coding: Coding, rsp: &mut Response) -> io::Result<()> {
+        let code = rsp.code();
+        if code < 200
+            || code == 204
+            || code == 304
+            || rsp.header_value("content-encoding").is_some()
+        {
+            return Ok(());
+        }
+        let body = match rsp.buffered_body() {
+            Some(body) if body.len() >= self.min_size => body,
+            _ => return Ok(()),
+        };
+        let body = self.encode(coding, body)?;
+        rsp.header(format!("Content-Encoding: {}", coding.name()));
+        rsp.body_vec(body);
+        Ok(())
+    }
+
+    fn encode(&self, coding: Coding, body: &[u8]) -> io::Result<Vec<u8>> {
+        match coding {
+            #[cfg(feature = "br")]
+            Coding::Br => {
+                let out = Vec::with_capacity(body.len() / 2);
+                let mut br = brotli::CompressorWriter::new(out, 4096, self.br_quality, 22);
+                br.write_all(body)?;
+                Ok(br.into_inner())
+            }
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => zstd::bulk::compress(body, self.zstd_level),
+            #[cfg(feature = "gzip")]
+            Coding::Gzip => {
+                let out = Vec::with_capacity(body.len() / 2);
+                let level = flate2::Compression::new(self.gzip_level);
+                let mut gz = flate2::write::GzEncoder::new(out, level);
+                gz.write_all(body)?;
+                gz.finish()
+            }
+        }
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        let coding = negotiate(&req);
+        next.run(req, rsp)?;
+        // the body varies with the header whether it is compressed or not
+        rsp.header("Vary: Accept-Encoding");
+        match coding {
+            Some(coding) => self.compress(coding, rsp),
+            None => Ok(()),
+        }
+    }
+}
+
+/// pick the coding with the highest `q` in `Accept-Encoding`
+///
+/// a coding that is not listed gets the `q` of `*`, `q=0` refuses it
+fn negotiate(req: &Request) -> Option<Coding> {
+    let mut accepted: Vec<(&str, f32)> = Vec::new();
+    for h in req.headers() {
+        if !h.name.eq_ignore_ascii_case("accept-encoding") {
+            continue;
+        }
+        let value = match std::str::from_utf8(h.value) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let (name, params) = item.split_once(';').unwrap_or((item, ""));
+            let name = name.trim();
+            // the parameter names are case-insensitive
+            let q = headers::params(params)
+                .filter(|(n, _)| n.eq_ignore_ascii_case("q"))
+                .find_map(|(_, q)| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if !name.is_empty() {
+                accepted.push((name, q));
+            }
+        }
+    }
+    let q_of = |name: &str| {
+        accepted
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .or_else(|| accepted.iter().find(|(n, _)| *n == "*"))
+            .map_or(0.0, |&(_, q)| q)
+    };
+
+    let mut best: Option<(Coding, f32)> = None;
+    for &coding in Coding::ALL {
+        let q = q_of(coding.name());
+        if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::with_request;
+    #[cfg(feature = "gzip")]
+    use crate::status::StatusCode;
+    use std::io::Read;
+
+    fn negotiated(accept: &str) -> Option<Coding> {
+        let head = match accept {
+            "" => "GET / HTTP/1.1\r\nHost: h\r\n\r\n".to_owned(),
+            _ => format!("GET / HTTP/1.1\r\nHost: h\r\nAccept-Encoding: {accept}\r\n\r\n"),
+        };
+        with_request(&head, |req| negotiate(&req))
+    }
+
+    fn decode(coding: Coding, body: &[u8]) -> Vec<u8> {
+        let mut reader: Box<dyn Read + '_> = match coding {
+            #[cfg(feature = "br")]
+            Coding::Br => Box::new(brotli::Decompressor::new(body, 4096)),
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => Box::new(zstd::stream::read::Decoder::new(body).unwrap()),
+            #[cfg(feature = "gzip")]
+            Coding::Gzip => Box::new(flate2::read::GzDecoder::new(body)),
+        };
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn negotiation() {
+        let best = Coding::ALL[0];
+        assert_eq!(negotiated(""), None);
+        assert_eq!(negotiated("identity"), None);
+        assert_eq!(negotiated("*"), Some(best));
+        assert_eq!(negotiated("*;q=0"), None);
+        for &coding in Coding::ALL {
+            let name = coding.name();
+            assert_eq!(negotiated(name), Some(coding));
+            assert_eq!(negotiated(&name.to_uppercase()), Some(coding));
+            assert_eq!(
+                negotiated(&format!("identity, {name} ; Q=0.5")),
+                Some(coding)
+            );
+            assert_eq!(negotiated(&format!("{name};q=0")), None);
+            assert_eq!(
+                negotiated(&format!("*, {name};q=0")).filter(|&c| c == coding),
+                None
+            );
+        }
+    }
+
+    #[cfg(all(feature = "gzip", feature = "br", feature = "zstd"))]
+    #[test]
+    fn preference() {
+        assert_eq!(negotiated("gzip, zstd, br"), Some(Coding::Br));
+        assert_eq!(negotiated("gzip, zstd"), Some(Coding::Zstd));
+        assert_eq!(negotiated("gzip, br;q=0.9"), Some(Coding::Gzip));
+        assert_eq!(negotiated("br;q=0.1, *;q=0.5"), Some(Coding::Zstd));
+    }
+
+    #[test]
+    fn round_trip() {
+        let body = "the quick brown fox jumps over the lazy dog. ".repeat(50);
+        let compression = Compression::new();
+        for &coding in Coding::ALL {
+            let encoded = compression.encode(coding, body.as_bytes()).unwrap();
+            assert_eq!(decode(coding, &encoded), body.as_bytes(), "{coding:?}");
+        }
+    }
+
+    /// the content coding and body of the response to `handler`
+    #[cfg(feature = "gzip")]
+    fn respond(
+        compression: &Compression,
+        accept: &str,
+        handler: impl FnOnce(&mut Response),
+    ) -> (Option<String>, Vec<u8>) {
+        let mut body = bytes::BytesMut::new();
+        let mut rsp = Response::new(&mut body, b"");
+        handler(&mut rsp);
+        if let Some(coding) = negotiated(accept) {
+            compression.compress(coding, &mut rsp).unwrap();
+        }
+        let encoding = rsp.header_value("content-encoding").map(str::to_owned);
+        (encoding, rsp.buffered_body().unwrap().to_vec())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compress() {
+        let compression = Compression::new().min_size(16);
+        let text = "0123456789abcdef".repeat(4);
+        let ok = |rsp: &mut Response| {
+            rsp.body_vec(text.clone().into_bytes());
+        };
+
+        let (encoding, body) = respond(&compression, "gzip", ok);
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert_eq!(decode(Coding::Gzip, &body), text.as_bytes());
+
+        // too small
+        let (encoding, body) = respond(&compression, "gzip", |rsp| {
+            rsp.body("short");
+        });
+        assert_eq!((encoding, body), (None, b"short".to_vec()));
+        // no body by its status
+        for status in [StatusCode::NoContent, StatusCode::NotModified] {
+            let (encoding, _) = respond(&compression, "gzip", |rsp| {
+                ok(rsp);
+                rsp.status(status);
+            });
+            assert_eq!(encoding, None);
+        }
+        // already encoded
+        let (encoding, body) = respond(&compression, "gzip", |rsp| {
+            ok(rsp);
+            rsp.header("Content-Encoding: br");
+        });
+        assert_eq!(
+            (encoding.as_deref(), body),
+            (Some("br"), text.clone().into_bytes())
+        );
+        // not accepted
+        let (encoding, body) = respond(&compression, "identity", ok);
+        assert_eq!((encoding, body), (None, text.clone().into_bytes()));
+    }
+}
@@ -0,0 +1,159 @@
+//! what is known about a connection
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// the connection a request came in on, see `Request::connection`
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) local: Option<SocketAddr>,
+    pub(crate) proxy: Option<SocketAddr>,
+    pub(crate) listener: usize,
+    pub(crate) tls: bool,
+    // known once the handshake is over
+    pub(crate) tls_info: Option<Arc<TlsInfo>>,
+}
+
+impl ConnectionInfo {
+    /// the address of the client
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// the address the connection was accepted on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local
+    }
+
+    /// the load balancer that passed on the connection with a PROXY protocol
+    /// header, `peer_addr` is then the client it was passed from
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy
+    }
+
+    /// the index of the listener that accepted the connection,
+    /// in the order the addresses were bound
+    pub fn listener(&self) -> usize {
+        self.listener
+    }
+
+    /// the connection is served over TLS
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    /// what the TLS handshake settled, `None` without TLS
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls_info.as_deref()
+    }
+}
+
+/// the outcome of the TLS handshake of a connection
+///
+/// native-tls only tells the peer certificate
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    pub(crate) version: Option<&'static str>,
+    pub(crate) cipher_suite: Option<&'static str>,
+    pub(crate) alpn_protocol: Option<Vec<u8>>,
+    pub(crate) server_name: Option<String>,
+    pub(crate) peer_certificate: Option<PeerCertificate>,
+}
+
+impl TlsInfo {
+    /// the protocol version, as `TLSv1.2` or `TLSv1.3`
+    pub fn version(&self) -> Option<&str> {
+        self.version
+    }
+
+    /// the IANA name of the cipher suite, as `TLS13_AES_128_GCM_SHA256`
+    pub fn cipher_suite(&self) -> Option<&str> {
+        self.cipher_suite
+    }
+
+    /// the protocol agreed on with ALPN, `http/1.1` when the client offered it
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
+    /// the host name the client asked for with SNI
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// the certificate the client authenticated with, when the TLS config
+    /// asks for one and verified it
+    pub fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.peer_certificate.as_ref()
+    }
+}
+
+/// a verified client certificate
+#[derive(Debug, Clone)]
+pub struct PeerCertificate {
+    pub(crate) der: Vec<u8>,
+    pub(crate) subject: String,
+    pub(crate) alt_names: Vec<String>,
+    pub(crate) fingerprint: String,
+}
+
+impl PeerCertificate {
+    /// the DER encoded certificate, to parse it further
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// the subject as `C=US, O=Example, CN=client`, in the certificate order
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// the subject alternative names as `DNS:client.example.com`,
+    /// `IP:10.0.0.1`, `URI:spiffe://example.com/client` or `email:a@example.com`
+    pub fn subject_alt_names(&self) -> &[String] {
+        &self.alt_names
+    }
+
+    /// the lowercase hex SHA-256 of the DER certificate
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// the accepted connection a service is created for,
+/// see `HttpServiceFactory::new_service_for`
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    pub(crate) id: usize,
+    pub(crate) info: ConnectionInfo,
+    pub(crate) accepted: Instant,
+}
+
+impl ConnectionContext {
+    /// the id also passed to `HttpServiceFactory::new_service`
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the address of the client, the one of the load balancer
+    /// when it sends a PROXY protocol header as that is read later
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.info.peer
+    }
+
+    /// the address the connection was accepted on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.info.local
+    }
+
+    /// when the connection was accepted, before any TLS handshake
+    pub fn accepted(&self) -> Instant {
+        self.accepted
+    }
+
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+}
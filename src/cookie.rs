@@ -0,0 +1,255 @@
+//! request cookies and the `Set-Cookie` builder
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::headers;
+
+/// the `SameSite` attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// a cookie to send with `Response::set_cookie`
+///
+/// ```ignore
+/// rsp.set_cookie(
+///     Cookie::new("sid", token)
+///         .path("/")
+///         .max_age(Duration::from_secs(3600))
+///         .same_site(SameSite::Lax)
+///         .http_only(true),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    same_site: Option<SameSite>,
+    secure: bool,
+    http_only: bool,
+}
+
+impl Cookie {
+    /// the name and value are sent as given
+    ///
+    /// panics on a name that is not a token or a value with other than the
+    /// RFC 6265 cookie octets, no spaces, `"`, `,`, `;`, `\\` or control
+    /// characters. encode such a value first, with base64 or percent-encoding
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        let (name, value) = (name.into(), value.into());
+        assert!(headers::is_token(&name), "invalid cookie name {name:?}");
+        assert!(
+            is_value(&value),
+            "invalid value of cookie {name}: {value:?}"
+        );
+        Cookie {
+            name,
+            value,
+            path: None,
+            domain: None,
+            max_age: None,
+            same_site: None,
+            secure: false,
+            http_only: false,
+        }
+    }
+
+    /// a cookie that makes the client drop the one with this name
+    pub fn removal(name: impl Into<String>) -> Self {
+        Cookie::new(name, "").max_age(Duration::ZERO)
+    }
+
+    /// panics on a `;` or a control character, like the domain
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        assert!(is_attribute(&path), "invalid cookie path {path:?}");
+        self.path = Some(path);
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        let domain = domain.into();
+        assert!(is_attribute(&domain), "invalid cookie domain {domain:?}");
+        self.domain = Some(domain);
+        self
+    }
+
+    /// the cookie expires after this long, a session cookie by default
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// `SameSite::None` requires `secure` in the browsers
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// only send the cookie over https
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// hide the cookie from scripts
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+}
+
+/// the `Set-Cookie` header value
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(ref path) = self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(ref domain) = self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict")?,
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax")?,
+            Some(SameSite::None) => f.write_str("; SameSite=None")?,
+            None => {}
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        Ok(())
+    }
+}
+
+/// the cookie octets, optionally in double quotes
+fn is_value(value: &str) -> bool {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    value
+        .bytes()
+        .all(|b| matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e))
+}
+
+/// the value of an attribute ends at a `;` and can't hold control characters
+pub(crate) fn is_attribute(value: &str) -> bool {
+    headers::is_field_value(value) && !value.contains([';', '\t'])
+}
+
+/// the name/value pairs of the `Cookie` headers, a quoted value is unquoted
+pub(crate) fn parse<'h>(
+    headers: &'h [httparse::Header<'h>],
+) -> impl Iterator<Item = (&'h str, &'h str)> + 'h {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("cookie"))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (!name.is_empty()).then_some((name, value))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_cookie() {
+        let cookie = Cookie::new("sid", "a-b_c.D=")
+            .path("/app")
+            .domain("example.com")
+            .max_age(Duration::from_secs(60))
+            .same_site(SameSite::Lax)
+            .secure(true)
+            .http_only(true);
+        assert_eq!(
+            cookie.to_string(),
+            "sid=a-b_c.D=; Path=/app; Domain=example.com; Max-Age=60; SameSite=Lax; Secure; HttpOnly"
+        );
+        assert_eq!(Cookie::new("a", "\"b\"").to_string(), "a=\"b\"");
+        assert_eq!(Cookie::removal("a").to_string(), "a=; Max-Age=0");
+    }
+
+    #[test]
+    fn values() {
+        for value in ["", "abc", "\"abc\"", "!#$%&'()*+-./:<=>?@[]^_`{|}~"] {
+            assert!(is_value(value), "{value}");
+        }
+        for value in [
+            "a b", "a;b", "a,b", "a\\b", "a\"b", "\"a", "a\r\nb", "\u{e9}", "a\0",
+        ] {
+            assert!(!is_value(value), "{value}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value of cookie")]
+    fn value_injection() {
+        Cookie::new("a", "b\r\nSet-Cookie: admin=1");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid cookie name")]
+    fn name_with_equals() {
+        Cookie::new("a=b", "c");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid cookie path")]
+    fn path_with_attribute() {
+        let _ = Cookie::new("a", "b").path("/; Domain=evil.com");
+    }
+
+    fn parse_header(value: &[u8]) -> Vec<(String, String)> {
+        let headers = [
+            httparse::Header {
+                name: "Cookie",
+                value,
+            },
+            httparse::Header {
+                name: "Accept",
+                value: b"a=b",
+            },
+        ];
+        parse(&headers)
+            .map(|(n, v)| (n.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn request_cookies() {
+        let pairs = |pairs: &[(&str, &str)]| {
+            let owned = pairs.iter().map(|(n, v)| (n.to_string(), v.to_string()));
+            owned.collect::<Vec<_>>()
+        };
+        assert_eq!(
+            parse_header(b"a=1; b=\"2\";c = 3 ;=4; d; e=x=y"),
+            pairs(&[("a", "1"), ("b", "2"), ("c", "3"), ("e", "x=y")])
+        );
+        assert_eq!(parse_header(b""), pairs(&[]));
+        assert_eq!(parse_header(b"\xff=1"), pairs(&[]));
+    }
+}
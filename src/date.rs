@@ -1,6 +1,7 @@
-use std::cell::UnsafeCell;
+use std::cell::RefCell;
 use std::fmt::{self, Write};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bytes::BytesMut;
 use once_cell::sync::Lazy;
@@ -8,36 +9,56 @@ use once_cell::sync::Lazy;
 // "Sun, 06 Nov 1994 08:49:37 GMT".len()
 const DATE_VALUE_LENGTH: usize = 29;
 
-static CURRENT_DATE: Lazy<Arc<DataWrap>> = Lazy::new(|| {
-    let date = Arc::new(DataWrap(UnsafeCell::new(Date::new())));
-    let date_clone = date.clone();
-    may::go!(move || loop {
-        may::coroutine::sleep(std::time::Duration::from_millis(500));
-        unsafe { &mut *(date_clone.0).get() }.update();
+// the current unix time in seconds, kept up to date by a coroutine so the
+// responses don't read the clock
+static CURRENT_SECS: Lazy<&'static AtomicU64> = Lazy::new(|| {
+    static SECS: AtomicU64 = AtomicU64::new(0);
+    SECS.store(unix_secs(), Ordering::Relaxed);
+    may::go!(|| loop {
+        may::coroutine::sleep(Duration::from_millis(500));
+        SECS.store(unix_secs(), Ordering::Relaxed);
     });
-    date
+    &SECS
 });
 
-struct DataWrap(UnsafeCell<Date>);
-unsafe impl Sync for DataWrap {}
+thread_local! {
+    // the date of each worker thread, formatted once per second
+    static DATE: RefCell<Date> = RefCell::new(Date::new());
+}
+
+#[inline]
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
 
 #[doc(hidden)]
 #[inline]
 pub fn append_date(dst: &mut BytesMut) {
-    let date = unsafe { &*CURRENT_DATE.0.get() };
-    dst.extend_from_slice(date.as_bytes());
+    let secs = CURRENT_SECS.load(Ordering::Relaxed);
+    DATE.with(|date| {
+        let mut date = date.borrow_mut();
+        if date.secs != secs {
+            date.update(secs);
+        }
+        dst.extend_from_slice(date.as_bytes());
+    });
 }
 
 struct Date {
+    // the time of the formatted date
+    secs: u64,
     bytes: [u8; DATE_VALUE_LENGTH],
 }
 
 impl Date {
     fn new() -> Date {
         let mut date = Date {
+            secs: 0,
             bytes: [0; DATE_VALUE_LENGTH],
         };
-        date.update();
+        date.update(unix_secs());
         date
     }
 
@@ -46,10 +67,11 @@ impl Date {
         &self.bytes
     }
 
-    fn update(&mut self) {
-        let t = std::time::SystemTime::now();
+    fn update(&mut self, secs: u64) {
+        let t = UNIX_EPOCH + Duration::from_secs(secs);
         let date = httpdate::HttpDate::from(t);
         write!(self, "{date}").unwrap();
+        self.secs = secs;
     }
 }
 
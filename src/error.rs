@@ -0,0 +1,110 @@
+//! errors that carry the status to answer with
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::status::StatusCode;
+
+/// an error answered with its status and message
+///
+/// the services return `io::Result`, an `HttpError` goes through it with `?`
+/// or `into()` and the server answers with its status and its message as the body.
+/// unlike the message of any other error the message is meant for the client
+///
+/// ```ignore
+/// let id: u32 = req
+///     .query_param("id")
+///     .and_then(|id| id.parse().ok())
+///     .ok_or_else(|| HttpError::new(StatusCode::BadRequest, "id must be a number"))?;
+/// let user = db.user(id)?.ok_or(HttpError::not_found())?;
+/// ```
+#[derive(Debug)]
+pub struct HttpError {
+    status: StatusCode,
+    message: Cow<'static, str>,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// the status with its reason phrase as the message
+    pub fn from_status(status: StatusCode) -> Self {
+        Self::new(status, status.reason())
+    }
+
+    pub fn bad_request(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::BadRequest, message)
+    }
+
+    pub fn not_found() -> Self {
+        Self::from_status(StatusCode::NotFound)
+    }
+
+    pub fn unprocessable(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::UnprocessableEntity, message)
+    }
+
+    /// keep the error that caused this one, it is logged but not sent
+    pub fn with_source(mut self, source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// the `HttpError` carried by `err`, if any
+    pub fn of(err: &io::Error) -> Option<&HttpError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<HttpError>())
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl Error for HttpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|e| e as _)
+    }
+}
+
+impl From<HttpError> for io::Error {
+    fn from(e: HttpError) -> io::Error {
+        let kind = match e.status {
+            StatusCode::NotFound => io::ErrorKind::NotFound,
+            StatusCode::Unauthorized | StatusCode::Forbidden => io::ErrorKind::PermissionDenied,
+            StatusCode::RequestTimeout | StatusCode::GatewayTimeout => io::ErrorKind::TimedOut,
+            s if (400..500).contains(&s.as_u16()) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// an `io::Error` is answered with `500 Internal Server Error`,
+/// or with the status of the `HttpError` it carries
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> HttpError {
+        match e.get_ref().map(|inner| inner.is::<HttpError>()) {
+            Some(true) => *e.into_inner().unwrap().downcast().unwrap(),
+            _ => HttpError::from_status(StatusCode::InternalServerError).with_source(e),
+        }
+    }
+}
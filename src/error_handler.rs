@@ -0,0 +1,49 @@
+//! answering the errors of the service
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::response::Response;
+
+/// turn an error returned by the service into a response, see `ServerBuilder::error_handler`
+///
+/// without one the server answers `500 Internal Server Error` with the error
+/// message as the body, which may tell the client more than it should.
+/// the response is reset before the call, what the service had set on it is gone.
+/// the requests the server refuses itself, like a too big body, are not passed here.
+/// `HttpError::of` gives the status and message of an error meant for the client
+///
+/// ```ignore
+/// ServerBuilder::new().error_handler(|e: &io::Error, rsp: &mut Response| {
+///     let status = match e.kind() {
+///         io::ErrorKind::NotFound => StatusCode::NotFound,
+///         io::ErrorKind::InvalidData => StatusCode::BadRequest,
+///         _ => StatusCode::InternalServerError,
+///     };
+///     rsp.status(status).body(status.reason());
+/// })
+/// ```
+pub trait ErrorHandler: Send + Sync + 'static {
+    fn handle(&self, err: &io::Error, rsp: &mut Response);
+}
+
+impl<F> ErrorHandler for F
+where
+    F: Fn(&io::Error, &mut Response) + Send + Sync + 'static,
+{
+    #[inline]
+    fn handle(&self, err: &io::Error, rsp: &mut Response) {
+        self(err, rsp)
+    }
+}
+
+/// the configured handler
+#[derive(Clone)]
+pub(crate) struct OnError(pub(crate) Arc<dyn ErrorHandler>);
+
+impl fmt::Debug for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
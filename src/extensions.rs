@@ -0,0 +1,87 @@
+//! typed per request storage
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+type AnyMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// values of any type keyed by their type, see `Request::extensions`
+///
+/// a middleware inserts what it found out about the request and the handlers
+/// down the chain read it back
+///
+/// ```ignore
+/// struct User(String);
+///
+/// // in the middleware
+/// req.extensions_mut().insert(User(name));
+/// // in the handler
+/// let user = req.extensions().get::<User>();
+/// ```
+#[derive(Default)]
+pub struct Extensions {
+    // most requests carry none, so the map is only allocated on the first insert
+    map: Option<Box<AnyMap>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// store a value, returning the previous one of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .get_or_insert_with(Default::default)
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .as_ref()?
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .as_mut()?
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_mut())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .as_mut()?
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast().ok().map(|v| *v))
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.get::<T>().is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |m| m.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        if let Some(map) = self.map.as_mut() {
+            map.clear();
+        }
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
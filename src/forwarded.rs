@@ -0,0 +1,254 @@
+//! the client address behind trusted proxies
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+
+/// the client address found by `ForwardedFor`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+/// a network like `10.0.0.0/8` or `::1/128`
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.trim().parse().ok()?, None),
+        };
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Cidr { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 client seen through an IPv6 socket
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// find the client address behind the trusted proxies, see `Request::client_ip`
+///
+/// when the connection comes from a trusted proxy the `Forwarded` header, or
+/// `X-Forwarded-For` without it, is walked from the last hop back. the first
+/// address that is not a trusted proxy is the client, as the ones before it
+/// could have been made up by the client itself. a request from anywhere else
+/// keeps the address of the connection
+///
+/// ```ignore
+/// let service = Chain::new(App).wrap(ForwardedFor::new().trust("10.0.0.0/8")?);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ForwardedFor {
+    trusted: Arc<Vec<Cidr>>,
+}
+
+impl ForwardedFor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// trust the proxies in a network like `10.0.0.0/8`, or a single address
+    pub fn trust(mut self, cidr: &str) -> io::Result<Self> {
+        let cidr = Cidr::parse(cidr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad network"))?;
+        Arc::make_mut(&mut self.trusted).push(cidr);
+        Ok(self)
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|c| c.contains(ip))
+    }
+
+    fn client_ip(&self, req: &Request) -> Option<IpAddr> {
+        let mut client = req.remote_addr()?.ip();
+        if !self.trusts(client) {
+            return Some(client);
+        }
+        let hops = forwarded_hops(req);
+        for hop in hops.iter().rev() {
+            match parse_node(hop) {
+                Some(ip) => client = ip,
+                // `unknown` or an obfuscated name, no address to go on
+                None => break,
+            }
+            if !self.trusts(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+impl Middleware for ForwardedFor {
+    fn handle(&self, mut req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        if let Some(ip) = self.client_ip(&req) {
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+        next.run(req, rsp)
+    }
+}
+
+/// the `for` nodes of `Forwarded`, or the `X-Forwarded-For` list, in order
+fn forwarded_hops<'r>(req: &'r Request) -> Vec<&'r str> {
+    let values = |name: &'static str| {
+        req.headers()
+            .iter()
+            .filter(move |h| h.name.eq_ignore_ascii_case(name))
+            .filter_map(|h| std::str::from_utf8(h.value).ok())
+    };
+    let forwarded: Vec<&str> = values("forwarded")
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (k, v) = pair.split_once('=')?;
+                k.trim().eq_ignore_ascii_case("for").then(|| v.trim())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    values("x-forwarded-for")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// `192.0.2.60`, `"[2001:db8::1]:4711"`, `192.0.2.60:80` or a bare IPv6 address
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // `[v6]` without a port
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionInfo;
+    use crate::request::with_request;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.255.1")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("192.0.2.1")));
+        let host = Cidr::parse(" 192.0.2.1 ").unwrap();
+        assert!(host.contains(ip("192.0.2.1")) && !host.contains(ip("192.0.2.2")));
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("::1")));
+        for bad in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "host",
+            "10.0.0.0/-1",
+        ] {
+            assert!(Cidr::parse(bad).is_none(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("192.0.2.60:80"), Some(ip("192.0.2.60")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:4711\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("\"[2001:db8::1]\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    fn client_ip(proxies: &ForwardedFor, peer: &str, headers: &str) -> Option<IpAddr> {
+        let head = format!("GET / HTTP/1.1\r\nHost: a\r\n{headers}\r\n");
+        with_request(&head, |mut req| {
+            req.set_connection(ConnectionInfo {
+                peer: Some(SocketAddr::new(ip(peer), 4000)),
+                ..Default::default()
+            });
+            proxies.client_ip(&req)
+        })
+    }
+
+    #[test]
+    fn client() {
+        let proxies = ForwardedFor::new().trust("10.0.0.0/8").unwrap();
+        let xff = "X-Forwarded-For: 1.1.1.1, 2.2.2.2, 10.0.0.2\r\n";
+        // the first untrusted hop from the end, the ones before it can be forged
+        assert_eq!(client_ip(&proxies, "10.0.0.1", xff), Some(ip("2.2.2.2")));
+        // not from a trusted proxy, the header is ignored
+        assert_eq!(client_ip(&proxies, "3.3.3.3", xff), Some(ip("3.3.3.3")));
+        // `Forwarded` wins over `X-Forwarded-For`
+        let both = "Forwarded: for=4.4.4.4;proto=https, for=\"[2001:db8::1]:80\"\r\n\
+            X-Forwarded-For: 1.1.1.1\r\n";
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", both),
+            Some(ip("2001:db8::1"))
+        );
+        // an obfuscated hop stops the walk at the last address known
+        let hidden = "Forwarded: for=4.4.4.4, for=_hidden\r\n";
+        assert_eq!(
+            client_ip(&proxies, "10.0.0.1", hidden),
+            Some(ip("10.0.0.1"))
+        );
+        // every hop trusted, the first one is the client
+        let all = "X-Forwarded-For: 10.0.0.3,10.0.0.2\r\n";
+        assert_eq!(client_ip(&proxies, "10.0.0.1", all), Some(ip("10.0.0.3")));
+        assert_eq!(client_ip(&proxies, "10.0.0.1", ""), Some(ip("10.0.0.1")));
+        // several headers are one list
+        let split = "X-Forwarded-For: 5.5.5.5\r\nX-Forwarded-For: 10.0.0.2\r\n";
+        assert_eq!(client_ip(&proxies, "10.0.0.1", split), Some(ip("5.5.5.5")));
+    }
+
+    #[test]
+    fn bad_network() {
+        let err = ForwardedFor::new().trust("10.0.0.0/40").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
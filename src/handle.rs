@@ -0,0 +1,400 @@
+//! the running server and its graceful shutdown
+
+use std::collections::HashMap;
+#[cfg(feature = "signal")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use may::coroutine::{self, Coroutine};
+#[cfg(unix)]
+use may::io::WaitIoWaker;
+use may::sync::Semphore;
+
+use crate::buf_pool::BufPool;
+use crate::builder::Config;
+use crate::connection::{ConnectionInfo, TlsInfo};
+
+#[cfg(unix)]
+type Waker = WaitIoWaker;
+// the blocking connection loop can't be woken up
+#[cfg(not(unix))]
+type Waker = ();
+
+// no deadline armed
+const NEVER: u64 = u64::MAX;
+
+/// the state shared by the accept loop and all the connections
+pub(crate) struct ServerState {
+    pub(crate) config: Config,
+    // the free connection buffers
+    pub(crate) buffers: BufPool,
+    draining: AtomicBool,
+    conns: Mutex<HashMap<usize, Arc<Conn>>>,
+    // the requests being handled by the services
+    in_flight: AtomicUsize,
+    // the free connection slots under the connection limit
+    connections: Option<Semphore>,
+    // the origin of the connection deadlines
+    #[cfg_attr(not(unix), allow(dead_code))]
+    epoch: Instant,
+}
+
+/// a live connection as seen by the server
+#[cfg_attr(not(unix), allow(dead_code))]
+struct Conn {
+    waker: Waker,
+    // milliseconds since the epoch, the connection is woken up once it passes
+    deadline: AtomicU64,
+}
+
+impl ServerState {
+    pub(crate) fn new(config: Config) -> Arc<Self> {
+        let state = Arc::new(ServerState {
+            buffers: BufPool::new(config.buffer_pool, config.buffer_sizes),
+            connections: config.max_connections.map(Semphore::new),
+            config,
+            draining: AtomicBool::new(false),
+            conns: Mutex::new(HashMap::new()),
+            in_flight: AtomicUsize::new(0),
+            epoch: Instant::now(),
+        });
+        #[cfg(unix)]
+        if let Some(tick) = state.config.sweep_interval() {
+            let state = Arc::downgrade(&state);
+            may::go!(move || loop {
+                coroutine::sleep(tick);
+                match state.upgrade() {
+                    Some(state) => state.wake_expired(),
+                    None => break,
+                }
+            });
+        }
+        state
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// wake up the connections whose deadline has passed so they close themselves
+    #[cfg(unix)]
+    fn wake_expired(&self) {
+        let now = self.now();
+        for conn in self.conns.lock().unwrap().values() {
+            if conn.deadline.load(Ordering::Relaxed) <= now {
+                conn.waker.wakeup();
+            }
+        }
+    }
+
+    /// the server is shutting down, every connection is closed after its current response
+    #[inline]
+    pub(crate) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// count a request handed to the service until the returned guard is dropped,
+    /// `None` when the in flight limit is reached
+    #[inline]
+    pub(crate) fn enter(&self) -> Option<InFlight<'_>> {
+        let max = match self.config.max_in_flight {
+            Some(max) => max,
+            None => return Some(InFlight(None)),
+        };
+        if self.in_flight.fetch_add(1, Ordering::Acquire) >= max {
+            self.in_flight.fetch_sub(1, Ordering::Release);
+            return None;
+        }
+        Some(InFlight(Some(&self.in_flight)))
+    }
+
+    /// take a slot under the connection limit until the returned guard is dropped,
+    /// waiting for a connection to close when they are all taken or, with `wait`
+    /// off, giving up with `None`
+    pub(crate) fn connection_slot(self: &Arc<Self>, wait: bool) -> Option<ConnSlot> {
+        let slots = match self.connections {
+            Some(ref slots) => slots,
+            None => return Some(ConnSlot(None)),
+        };
+        if wait {
+            slots.wait();
+        } else if !slots.try_wait() {
+            return None;
+        }
+        Some(ConnSlot(Some(self.clone())))
+    }
+
+    /// track a live connection until the returned guard is dropped
+    pub(crate) fn register(&self, id: usize, waker: Waker, info: ConnectionInfo) -> ConnGuard<'_> {
+        let conn = Arc::new(Conn {
+            waker,
+            deadline: AtomicU64::new(NEVER),
+        });
+        self.conns.lock().unwrap().insert(id, conn.clone());
+        ConnGuard {
+            state: self,
+            id,
+            conn,
+            info,
+            requests: 0,
+            timer: None,
+        }
+    }
+}
+
+/// a request being handled, counted against the in flight limit if there is one
+pub(crate) struct InFlight<'a>(Option<&'a AtomicUsize>);
+
+impl<'a> Drop for InFlight<'a> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(count) = self.0 {
+            count.fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+/// a connection counted against the connection limit, if there is one
+pub(crate) struct ConnSlot(Option<Arc<ServerState>>);
+
+impl Drop for ConnSlot {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(ref state) = self.0 {
+            if let Some(ref slots) = state.connections {
+                slots.post();
+            }
+        }
+    }
+}
+
+/// removes the connection from the server once it is done,
+/// must be dropped before the socket so the id is not reused in between
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) struct ConnGuard<'a> {
+    state: &'a ServerState,
+    id: usize,
+    conn: Arc<Conn>,
+    info: ConnectionInfo,
+    // the requests served on the connection
+    requests: usize,
+    // the armed timer
+    timer: Option<Timer>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Timer {
+    Idle,
+    Head,
+    Write,
+}
+
+impl<'a> ConnGuard<'a> {
+    #[inline]
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the connection being served
+    #[inline]
+    pub(crate) fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    #[inline]
+    pub(crate) fn set_tls_info(&mut self, tls: Option<TlsInfo>) {
+        self.info.tls_info = tls.map(Arc::new);
+    }
+
+    /// the server the connection belongs to
+    #[inline]
+    pub(crate) fn server(&self) -> &'a ServerState {
+        self.state
+    }
+
+    /// count a new request, return the number of requests so far
+    #[inline]
+    pub(crate) fn count_request(&mut self) -> usize {
+        self.requests += 1;
+        self.requests
+    }
+}
+
+// the deadlines are only armed by the unix connection loop, the other one
+// bounds its blocking reads with them instead
+#[cfg(unix)]
+impl<'a> ConnGuard<'a> {
+    /// arm the timer of the current state before the connection parks in `wait_io`
+    ///
+    /// `idle` tells that there is no request in progress, otherwise the request head
+    /// (and a buffered body) is still arriving. `progress` tells that requests were
+    /// served since the last wait, which restarts the timer.
+    /// return false once the armed timer has expired
+    #[inline]
+    pub(crate) fn before_wait(&mut self, idle: bool, progress: bool) -> bool {
+        let config = &self.state.config;
+        if idle {
+            self.wait_timer(Timer::Idle, config.idle_timeout, progress)
+        } else {
+            self.wait_timer(Timer::Head, config.header_timeout, progress)
+        }
+    }
+
+    /// arm the timer before the connection parks in `wait_io` on a socket that
+    /// takes no more response bytes, it runs for the idle timeout
+    ///
+    /// `progress` tells that bytes went out since the last wait.
+    /// return false once the armed timer has expired
+    #[inline]
+    pub(crate) fn before_write_wait(&mut self, progress: bool) -> bool {
+        self.wait_timer(Timer::Write, self.state.config.idle_timeout, progress)
+    }
+
+    fn wait_timer(&mut self, timer: Timer, timeout: Option<Duration>, progress: bool) -> bool {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                if self.timer.is_some() {
+                    self.disarm();
+                }
+                return true;
+            }
+        };
+        let now = self.state.now();
+        if self.timer == Some(timer) && !progress {
+            return self.conn.deadline.load(Ordering::Relaxed) > now;
+        }
+        self.arm(timer, now, timeout);
+        true
+    }
+
+    #[inline]
+    fn arm(&mut self, timer: Timer, now: u64, timeout: Duration) {
+        let deadline = now.saturating_add(timeout.as_millis() as u64);
+        self.conn.deadline.store(deadline, Ordering::Relaxed);
+        self.timer = Some(timer);
+    }
+
+    #[inline]
+    fn disarm(&mut self) {
+        self.conn.deadline.store(NEVER, Ordering::Relaxed);
+        self.timer = None;
+    }
+}
+
+impl<'a> Drop for ConnGuard<'a> {
+    fn drop(&mut self) {
+        self.state.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// handle of a running server, returned by the `start` methods
+pub struct ServerHandle {
+    // one accept loop for each listener
+    handles: Vec<coroutine::JoinHandle<()>>,
+    state: Arc<ServerState>,
+    // the listening sockets, open as long as the accept loops run
+    #[cfg(target_os = "linux")]
+    pub(crate) listeners: Vec<RawFd>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(handles: Vec<coroutine::JoinHandle<()>>, state: Arc<ServerState>) -> Self {
+        ServerHandle {
+            handles,
+            state,
+            #[cfg(target_os = "linux")]
+            listeners: Vec::new(),
+        }
+    }
+
+    /// the listening sockets, `None` once they are closed by the shutdown
+    #[cfg(target_os = "linux")]
+    pub(crate) fn listener_fds(&self) -> Option<&[RawFd]> {
+        (!self.state.draining()).then_some(&self.listeners)
+    }
+
+    /// the coroutine running the accept loop of the first listener
+    pub fn coroutine(&self) -> &Coroutine {
+        self.handles[0].coroutine()
+    }
+
+    /// block until the accept loops are done
+    pub fn wait(&self) {
+        for handle in &self.handles {
+            handle.wait();
+        }
+    }
+
+    /// join the accept loops, the first panic is returned
+    pub fn join(self) -> thread::Result<()> {
+        let mut ret = Ok(());
+        for handle in self.handles {
+            let r = handle.join();
+            if ret.is_ok() {
+                ret = r;
+            }
+        }
+        ret
+    }
+
+    /// the number of connections that are still open
+    pub fn connections(&self) -> usize {
+        self.state.conns.lock().unwrap().len()
+    }
+
+    /// stop accepting and drain the open connections
+    ///
+    /// the response being processed on each keep-alive connection is the last one,
+    /// it is sent with `Connection: close` before the socket is shut down.
+    /// idle connections are closed right away (on unix, elsewhere with their next request
+    /// or their idle timeout).
+    /// poll `connections` to wait for the drain to finish
+    pub fn shutdown(&self) {
+        let accepts = self.handles.iter().map(|h| h.coroutine());
+        shutdown(&self.state, accepts);
+    }
+
+    /// shut the server down on `SIGINT` or `SIGTERM`, Ctrl-C on windows
+    ///
+    /// the first signal starts the drain like `shutdown`, a second one exits the
+    /// process right away. only one handler can be installed in a process
+    ///
+    /// ```ignore
+    /// let server = HttpServer(Hello).start("0.0.0.0:8080")?;
+    /// server.shutdown_on_signal()?;
+    /// server.join().ok();
+    /// ```
+    #[cfg(feature = "signal")]
+    pub fn shutdown_on_signal(&self) -> io::Result<()> {
+        let state = self.state.clone();
+        let accepts: Vec<Coroutine> = self.handles.iter().map(|h| h.coroutine().clone()).collect();
+        ctrlc::set_handler(move || {
+            if state.draining() {
+                std::process::exit(130);
+            }
+            info!("signal received, shutting down");
+            shutdown(&state, accepts.iter());
+        })
+        .map_err(io::Error::other)
+    }
+}
+
+/// stop the accept loops and have the connections close after their current response
+fn shutdown<'a>(state: &ServerState, accepts: impl Iterator<Item = &'a Coroutine>) {
+    state.draining.store(true, Ordering::Relaxed);
+    for co in accepts {
+        unsafe { co.cancel() };
+    }
+    #[cfg(unix)]
+    for conn in state.conns.lock().unwrap().values() {
+        conn.waker.wakeup();
+    }
+}
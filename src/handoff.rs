@@ -0,0 +1,91 @@
+//! restarts without downtime, the listening sockets are handed to a new process
+//!
+//! ```ignore
+//! // the new process takes the sockets over, a first start binds them
+//! let server = match ServerBuilder::new().start_inherited(HttpServer(Hello)) {
+//!     Err(e) if e.kind() == io::ErrorKind::NotFound => {
+//!         ServerBuilder::new().start("0.0.0.0:8080", HttpServer(Hello))?
+//!     }
+//!     ret => ret?,
+//! };
+//! // to upgrade, run the new binary and drain this server
+//! handoff::hand_over(&server, Command::new(env::current_exe()?))?;
+//! server.join().unwrap();
+//! ```
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+use crate::handle::ServerHandle;
+
+// the comma separated listening sockets passed to the new process
+const LISTEN_FDS: &str = "MAY_MINIHTTP_LISTEN_FDS";
+
+/// take the listening sockets handed over by `hand_over`, in the order of
+/// the listeners of the old server
+///
+/// empty when the process was not given any. the variable is removed from
+/// the environment so the sockets are not passed on to the child processes,
+/// a second call finds none
+pub fn inherited_listeners() -> io::Result<Vec<TcpListener>> {
+    let fds = match env::var(LISTEN_FDS) {
+        Ok(fds) => fds,
+        Err(_) => return Ok(Vec::new()),
+    };
+    env::remove_var(LISTEN_FDS);
+    fds.split(',')
+        .map(|fd| {
+            let fd: RawFd = fd.parse().map_err(|_| invalid("bad listening socket"))?;
+            // they are inherited without close-on-exec
+            set_cloexec(fd, true)?;
+            Ok(unsafe { TcpListener::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+/// spawn `cmd` with the listening sockets of `server`, then drain the server
+///
+/// the sockets are shared by both processes, the connections arriving between
+/// the last accept of this one and the first of the new one wait in the listen
+/// backlog, none is refused. the connections already open are served until
+/// they close, see `ServerHandle::shutdown`. the new process takes the sockets
+/// with `inherited_listeners` or `ServerBuilder::start_inherited`
+pub fn hand_over(server: &ServerHandle, mut cmd: Command) -> io::Result<Child> {
+    let fds = server
+        .listener_fds()
+        .ok_or_else(|| invalid("the server is shutting down"))?
+        .to_vec();
+    let list = fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>();
+    cmd.env(LISTEN_FDS, list.join(","));
+    // only the new process gets them, not the ones spawned meanwhile
+    unsafe {
+        cmd.pre_exec(move || fds.iter().try_for_each(|&fd| set_cloexec(fd, false)));
+    }
+    let child = cmd.spawn()?;
+    server.shutdown();
+    Ok(child)
+}
+
+fn set_cloexec(fd: RawFd, on: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD, 0) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,311 @@
+//! typed values of the common request headers
+
+/// a `Content-Type` like `text/html; charset=utf-8`, see `Request::content_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaType<'a> {
+    type_: &'a str,
+    subtype: &'a str,
+    params: &'a str,
+}
+
+impl<'a> MediaType<'a> {
+    pub fn parse(s: &'a str) -> Option<Self> {
+        let (essence, params) = s.split_once(';').unwrap_or((s, ""));
+        let (type_, subtype) = essence.trim().split_once('/')?;
+        let (type_, subtype) = (type_.trim(), subtype.trim());
+        if type_.is_empty() || subtype.is_empty() {
+            return None;
+        }
+        Some(MediaType {
+            type_,
+            subtype,
+            params,
+        })
+    }
+
+    /// the top level type, `text` of `text/html`
+    pub fn type_(&self) -> &'a str {
+        self.type_
+    }
+
+    /// `html` of `text/html`
+    pub fn subtype(&self) -> &'a str {
+        self.subtype
+    }
+
+    /// the type without the parameters, compare it ignoring case
+    pub fn essence(&self) -> (&'a str, &'a str) {
+        (self.type_, self.subtype)
+    }
+
+    /// the type and subtype are these, ignoring case
+    pub fn is(&self, type_: &str, subtype: &str) -> bool {
+        self.type_.eq_ignore_ascii_case(type_) && self.subtype.eq_ignore_ascii_case(subtype)
+    }
+
+    /// the parameters as name/value pairs, a quoted value is unquoted
+    pub fn params(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        params(self.params)
+    }
+
+    /// the named parameter, the name is matched ignoring case
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        self.params()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+}
+
+/// the `; name=value` parameters of a header, a quoted value is unquoted
+///
+/// a `;` inside quotes is part of the value, backslash escapes are kept as is
+pub(crate) fn params(s: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        while !rest.is_empty() {
+            let mut quoted = false;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    quoted ^= c == '"';
+                    c == ';' && !quoted
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            let param = &rest[..end];
+            rest = rest.get(end + 1..).unwrap_or_default();
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            return Some((name.trim(), value));
+        }
+        None
+    })
+}
+
+/// a header name or another RFC 9110 token, not empty and no separators
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// a header value can't hold control characters other than tab,
+/// a CR or LF would end the header line
+pub(crate) fn is_field_value(s: &str) -> bool {
+    s.bytes().all(|b| b == b'\t' || (b >= b' ' && b != 0x7f))
+}
+
+/// the scheme and credentials of an `Authorization`, see `Request::authorization`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Authorization<'a> {
+    scheme: &'a str,
+    credentials: &'a str,
+}
+
+impl<'a> Authorization<'a> {
+    pub fn parse(s: &'a str) -> Option<Self> {
+        let (scheme, credentials) = s.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        (!scheme.is_empty() && !credentials.is_empty()).then_some(Authorization {
+            scheme,
+            credentials,
+        })
+    }
+
+    /// like `Basic` or `Bearer`, compare it ignoring case
+    pub fn scheme(&self) -> &'a str {
+        self.scheme
+    }
+
+    pub fn credentials(&self) -> &'a str {
+        self.credentials
+    }
+
+    /// the credentials if the scheme is this one, ignoring case
+    pub fn of_scheme(&self, scheme: &str) -> Option<&'a str> {
+        self.scheme
+            .eq_ignore_ascii_case(scheme)
+            .then_some(self.credentials)
+    }
+}
+
+/// the entity tags of an `If-None-Match` or `If-Match`, see `Request::if_none_match`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityTags<'a>(&'a str);
+
+impl<'a> EntityTags<'a> {
+    pub fn new(s: &'a str) -> Self {
+        EntityTags(s)
+    }
+
+    /// `*`, any current representation
+    pub fn is_any(&self) -> bool {
+        self.0.trim() == "*"
+    }
+
+    /// the quoted tags, weak ones keep their `W/` prefix
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> {
+        self.0.split(',').map(str::trim).filter(|t| !t.is_empty())
+    }
+
+    /// the list holds `*` or the tag, compared weakly
+    pub fn matches(&self, etag: &str) -> bool {
+        let opaque = |tag: &'a str| tag.strip_prefix("W/").unwrap_or(tag);
+        let etag = etag.trim();
+        let etag = etag.strip_prefix("W/").unwrap_or(etag);
+        self.iter().any(|tag| tag == "*" || opaque(tag) == etag)
+    }
+}
+
+/// one range of a `Range: bytes=...` header, see `Request::range`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `first-last`, both included
+    Bounded(u64, u64),
+    /// `first-`, to the end
+    From(u64),
+    /// `-n`, the last n bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// parse the `bytes` ranges, `None` for another unit or an invalid range
+    pub fn parse(s: &str) -> Option<Vec<ByteRange>> {
+        let specs = s.trim().strip_prefix("bytes=")?;
+        specs
+            .split(',')
+            .map(|spec| {
+                let (first, last) = spec.trim().split_once('-')?;
+                match (first, last) {
+                    ("", n) => n.parse().ok().map(ByteRange::Suffix),
+                    (first, "") => first.parse().ok().map(ByteRange::From),
+                    (first, last) => {
+                        let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                        (last >= first).then_some(ByteRange::Bounded(first, last))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// the first and last byte of the range over a body of `len` bytes,
+    /// `None` when no byte of it is in the body
+    pub fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        match self {
+            ByteRange::Bounded(first, last) if first < len => Some((first, last.min(len - 1))),
+            ByteRange::From(first) if first < len => Some((first, len - 1)),
+            ByteRange::Suffix(n) if n > 0 && len > 0 => Some((len.saturating_sub(n), len - 1)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_type() {
+        let t = MediaType::parse("Text/HTML ; charset=\"utf-8\"; q=1").unwrap();
+        assert_eq!(t.essence(), ("Text", "HTML"));
+        assert!(t.is("text", "html"));
+        assert!(!t.is("text", "plain"));
+        assert_eq!(t.param("Charset"), Some("utf-8"));
+        assert_eq!(t.param("q"), Some("1"));
+        assert_eq!(t.param("x"), None);
+        assert_eq!(t.params().count(), 2);
+        let json = MediaType::parse("application/json").unwrap();
+        assert_eq!((json.type_(), json.subtype()), ("application", "json"));
+        assert_eq!(json.params().count(), 0);
+        for bad in ["", "text", "text/", "/html", " / ;a=b"] {
+            assert_eq!(MediaType::parse(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn parameters() {
+        let parsed = |s| params(s).collect::<Vec<_>>();
+        assert_eq!(
+            parsed(" a=1;b = \"x;y\" ;c; d=\"\";"),
+            [("a", "1"), ("b", "x;y"), ("d", "")]
+        );
+        assert_eq!(parsed(""), []);
+        // an unterminated quote runs to the end
+        assert_eq!(parsed("a=\"x;b=1"), [("a", "\"x;b=1")]);
+    }
+
+    #[test]
+    fn authorization() {
+        let auth = Authorization::parse(" Bearer  abc.def ").unwrap();
+        assert_eq!(auth.scheme(), "Bearer");
+        assert_eq!(auth.credentials(), "abc.def");
+        assert_eq!(auth.of_scheme("bearer"), Some("abc.def"));
+        assert_eq!(auth.of_scheme("Basic"), None);
+        assert_eq!(Authorization::parse("Bearer"), None);
+        assert_eq!(Authorization::parse("Bearer "), None);
+        assert_eq!(Authorization::parse(""), None);
+    }
+
+    #[test]
+    fn entity_tags() {
+        let tags = EntityTags::new("\"a\", W/\"b\" ,,\"c\"");
+        assert_eq!(
+            tags.iter().collect::<Vec<_>>(),
+            ["\"a\"", "W/\"b\"", "\"c\""]
+        );
+        assert!(!tags.is_any());
+        assert!(tags.matches("\"a\""));
+        assert!(tags.matches("W/\"a\""));
+        assert!(tags.matches("\"b\""));
+        assert!(!tags.matches("\"d\""));
+        assert!(EntityTags::new(" * ").is_any());
+        assert!(EntityTags::new("*").matches("\"x\""));
+    }
+
+    #[test]
+    fn byte_ranges() {
+        use ByteRange::*;
+        assert_eq!(
+            ByteRange::parse("bytes=0-9, 20-, -5"),
+            Some(vec![Bounded(0, 9), From(20), Suffix(5)])
+        );
+        for bad in [
+            "0-9",
+            "bytes=",
+            "bytes=9-0",
+            "bytes=a-1",
+            "bytes=-",
+            "items=0-1",
+            "bytes=0-1,x",
+        ] {
+            assert_eq!(ByteRange::parse(bad), None, "{bad}");
+        }
+        assert_eq!(Bounded(0, 99).resolve(10), Some((0, 9)));
+        assert_eq!(Bounded(10, 20).resolve(10), None);
+        assert_eq!(From(3).resolve(10), Some((3, 9)));
+        assert_eq!(From(10).resolve(10), None);
+        assert_eq!(Suffix(3).resolve(10), Some((7, 9)));
+        assert_eq!(Suffix(30).resolve(10), Some((0, 9)));
+        assert_eq!(Suffix(0).resolve(10), None);
+        assert_eq!(Suffix(3).resolve(0), None);
+    }
+
+    #[test]
+    fn tokens_and_values() {
+        assert!(is_token("Content-Type"));
+        assert!(is_token("x!#$%&'*+.^_`|~9"));
+        for bad in ["", "a b", "a:b", "a\r\n", "é", "a\"", "(a)"] {
+            assert!(!is_token(bad), "{bad:?}");
+        }
+        assert!(is_field_value(""));
+        assert!(is_field_value("a\tb c é ~"));
+        for bad in ["a\rb", "a\nb", "a\0", "a\x7f", "\x1b[0m"] {
+            assert!(!is_field_value(bad), "{bad:?}");
+        }
+    }
+}
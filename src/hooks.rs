@@ -0,0 +1,58 @@
+//! callbacks on the life of the connections and requests
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::request::Request;
+
+/// why a connection ended
+#[derive(Debug)]
+pub enum Disconnect {
+    /// the server closed it after its last response,
+    /// on `Connection: close`, a timeout or the shutdown
+    Closed,
+    /// the client hung up
+    ClientClosed,
+    /// the connection failed
+    Error(io::Error),
+}
+
+impl Disconnect {
+    pub(crate) fn of(ret: io::Result<()>) -> Self {
+        match ret {
+            Ok(()) => Disconnect::Closed,
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Disconnect::ClientClosed,
+            Err(e) => Disconnect::Error(e),
+        }
+    }
+}
+
+/// observe the server without changing how it serves, see `ServerBuilder::hooks`
+///
+/// the callbacks run on the connection coroutines, all of them do nothing by default
+pub trait ServerHooks: Send + Sync + 'static {
+    /// a connection was accepted, before the TLS handshake if there is one
+    fn on_connect(&self, _peer: Option<SocketAddr>) {}
+
+    /// a request is about to be handed to the service
+    fn on_request(&self, _req: &Request) {}
+
+    /// the response to a request was encoded
+    fn on_response(&self, _status: usize, _latency: Duration) {}
+
+    /// a connection ended
+    fn on_disconnect(&self, _reason: &Disconnect) {}
+}
+
+/// the configured hooks
+#[derive(Clone)]
+pub(crate) struct Hooks(pub(crate) Arc<dyn ServerHooks>);
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerHooks")
+    }
+}
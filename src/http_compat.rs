@@ -0,0 +1,65 @@
+//! conversions between the crate types and the `http` crate types
+
+use std::io::{self, Read};
+
+use bytes::Bytes;
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+
+use crate::request::Request;
+use crate::response::Response;
+
+#[inline]
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// build an `http::Request`, a streamed body is read in from the socket
+///
+/// the conversion is fallible since reading the body can fail, or the request
+/// may carry a method, path or header the `http` crate rejects
+impl<'a, 'header> TryFrom<Request<'a, 'header>> for http::Request<Bytes> {
+    type Error = io::Error;
+
+    fn try_from(req: Request<'a, 'header>) -> io::Result<Self> {
+        to_http_request(req)
+    }
+}
+
+fn to_http_request(mut req: Request) -> io::Result<http::Request<Bytes>> {
+    let mut body = Vec::with_capacity(req.body().len());
+    req.body_reader().read_to_end(&mut body)?;
+
+    let mut out = http::Request::new(Bytes::from(body));
+    *out.method_mut() =
+        http::Method::from_bytes(req.method().as_str().as_bytes()).map_err(invalid)?;
+    *out.uri_mut() = http::Uri::try_from(req.path()).map_err(invalid)?;
+    *out.version_mut() = match req.version() {
+        0 => http::Version::HTTP_10,
+        _ => http::Version::HTTP_11,
+    };
+    let headers = out.headers_mut();
+    for h in req.headers() {
+        let name = HeaderName::from_bytes(h.name.as_bytes()).map_err(invalid)?;
+        let value = HeaderValue::from_bytes(h.value).map_err(invalid)?;
+        headers.append(name, value);
+    }
+    Ok(out)
+}
+
+impl<'a> Response<'a> {
+    /// copy the status, headers and body of an `http::Response`
+    ///
+    /// the message framing headers are left out, the server takes care of them
+    pub fn write_http<B: Into<Bytes>>(&mut self, src: http::Response<B>) {
+        let (parts, body) = src.into_parts();
+        self.status_u16(parts.status.as_u16());
+        for (name, value) in parts.headers.iter() {
+            if *name == CONTENT_LENGTH || *name == TRANSFER_ENCODING {
+                continue;
+            }
+            let value = String::from_utf8_lossy(value.as_bytes());
+            self.header(format!("{}: {}", name.as_str(), value));
+        }
+        self.body_vec(Vec::from(body.into()));
+    }
+}
@@ -1,15 +1,35 @@
 //! http server implementation on top of `MAY`
 
+use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::MaybeUninit;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(not(panic = "abort"))]
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+#[cfg(not(panic = "abort"))]
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::request::{self, Request};
+use crate::access_log::Pending;
+use crate::builder::{Config, ServerBuilder};
+use crate::connection::{ConnectionContext, ConnectionInfo, TlsInfo};
+use crate::handle::{ConnGuard, ServerHandle, ServerState};
+use crate::hooks::Disconnect;
+use crate::method::Method;
+use crate::metrics::Metrics;
+use crate::proxy_protocol;
+use crate::request::{self, BodyLeft, HeadScan, Rejection, Request, SocketBody};
 use crate::response::{self, Response};
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use crate::tls::TlsAcceptor;
+use crate::upgrade::Upgraded;
 use bytes::{Buf, BufMut, BytesMut};
 #[cfg(unix)]
-use may::io::WaitIo;
+use may::io::{WaitIo, WaitIoWaker};
 use may::net::{TcpListener, TcpStream};
+#[cfg(not(panic = "abort"))]
+use may::sync::mpsc;
 use may::{coroutine, go};
 
 macro_rules! t_c {
@@ -27,8 +47,18 @@ macro_rules! t_c {
 /// the http service trait
 /// user code should supply a type that impl the `call` method for the http server
 ///
+/// a panic in `call` is answered with `500 Internal Server Error` and closes the
+/// connection. that needs unwinding, with `panic = "abort"` the process aborts
 pub trait HttpService {
     fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()>;
+
+    /// called once the connection is set up, before its first request,
+    /// to open the per connection resources
+    fn on_connection_start(&mut self) {}
+
+    /// called when the connection is done, however it ended,
+    /// to release what `on_connection_start` opened
+    fn on_connection_end(&mut self) {}
 }
 
 pub trait HttpServiceFactory: Send + Sized + 'static {
@@ -36,43 +66,347 @@ pub trait HttpServiceFactory: Send + Sized + 'static {
     // create a new http service for each connection
     fn new_service(&self, id: usize) -> Self::Service;
 
+    /// create the service of a connection knowing who it is from,
+    /// calls `new_service` with the connection id by default
+    fn new_service_for(&self, ctx: &ConnectionContext) -> Self::Service {
+        self.new_service(ctx.id())
+    }
+
     /// Spawns the http service, binding to the given address
-    /// return a coroutine that you can cancel it when need to stop the service
-    fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<coroutine::JoinHandle<()>> {
-        let listener = TcpListener::bind(addr)?;
-        go!(
-            coroutine::Builder::new().name("TcpServerFac".to_owned()),
-            move || {
-                #[cfg(unix)]
-                use std::os::fd::AsRawFd;
-                #[cfg(windows)]
-                use std::os::windows::io::AsRawSocket;
-                for stream in listener.incoming() {
-                    let mut stream = t_c!(stream);
-                    #[cfg(unix)]
-                    let id = stream.as_raw_fd() as usize;
-                    #[cfg(windows)]
-                    let id = stream.as_raw_socket() as usize;
-                    // t_c!(stream.set_nodelay(true));
-                    let service = self.new_service(id);
-                    let builder = may::coroutine::Builder::new().id(id);
-                    go!(
-                        builder,
-                        move || if let Err(e) = each_connection_loop(&mut stream, service) {
-                            error!("service err = {:?}", e);
-                            stream.shutdown(std::net::Shutdown::Both).ok();
+    /// return a handle to wait for or shut down the server
+    fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerHandle> {
+        ServerBuilder::new().start(addr, self)
+    }
+
+    /// Spawns the http service on all the addresses
+    /// return a handle to wait for or shut down the server
+    fn start_all<I, L>(self, addrs: I) -> io::Result<ServerHandle>
+    where
+        I: IntoIterator<Item = L>,
+        L: ToSocketAddrs,
+    {
+        ServerBuilder::new().start_all(addrs, self)
+    }
+
+    /// Spawns the http service on a listener that is already bound
+    /// return a handle to wait for or shut down the server
+    fn start_with_listener(self, listener: std::net::TcpListener) -> io::Result<ServerHandle> {
+        ServerBuilder::new().start_with_listener(listener, self)
+    }
+
+    /// Spawns the https service, binding to the given address
+    /// each accepted connection is wrapped in a TLS session by the `acceptor`
+    /// return a handle to wait for or shut down the server
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    fn start_tls<L: ToSocketAddrs, A: TlsAcceptor>(
+        self,
+        addr: L,
+        acceptor: A,
+    ) -> io::Result<ServerHandle> {
+        ServerBuilder::new().start_tls(addr, self, acceptor)
+    }
+}
+
+/// turns an accepted socket into the transport the connection is served over
+pub(crate) trait Accept: Clone + Send + 'static {
+    type Stream: Transport + 'static;
+    /// the stream is encrypted
+    const TLS: bool;
+
+    /// a blocking handshake has to be over within `timeout`
+    fn accept(&self, sock: TcpStream, timeout: Option<Duration>) -> io::Result<Self::Stream>;
+}
+
+/// serve the plain tcp socket
+#[derive(Clone)]
+pub(crate) struct Plain;
+
+impl Accept for Plain {
+    type Stream = TcpStream;
+    const TLS: bool = false;
+
+    #[inline]
+    fn accept(&self, sock: TcpStream, _timeout: Option<Duration>) -> io::Result<TcpStream> {
+        Ok(sock)
+    }
+}
+
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+impl<A: TlsAcceptor> Accept for A {
+    type Stream = A::Stream;
+    const TLS: bool = true;
+
+    #[inline]
+    fn accept(&self, sock: TcpStream, timeout: Option<Duration>) -> io::Result<A::Stream> {
+        TlsAcceptor::accept_timeout(self, sock, timeout)
+    }
+}
+
+/// run an accept loop in a new coroutine for each listener,
+/// each connection gets its own coroutine
+pub(crate) fn serve<F: HttpServiceFactory, A: Accept>(
+    listeners: Vec<std::net::TcpListener>,
+    factory: F,
+    acceptor: A,
+    config: Config,
+    name: &str,
+) -> io::Result<ServerHandle> {
+    if listeners.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no listener"));
+    }
+    // the timeout cancels the handler by unwinding its coroutine
+    if cfg!(panic = "abort") && config.request_timeout.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "request_timeout needs panic = \"unwind\"",
+        ));
+    }
+    // before the runtime is started by the listeners
+    #[cfg(target_os = "linux")]
+    crate::prefork::fork_workers(config.workers)?;
+    let listeners = listeners
+        .into_iter()
+        .map(TcpListener::new)
+        .collect::<io::Result<Vec<_>>>()?;
+    let state = ServerState::new(config);
+    #[cfg(target_os = "linux")]
+    let fds = listeners
+        .iter()
+        .map(std::os::fd::AsRawFd::as_raw_fd)
+        .collect();
+    // shared by the accept loops, it only has to be `Send`
+    let factory = Arc::new(Mutex::new(factory));
+    let mut handles = Vec::with_capacity(listeners.len());
+    for (index, listener) in listeners.into_iter().enumerate() {
+        let factory = factory.clone();
+        let acceptor = acceptor.clone();
+        let server = state.clone();
+        let handle = go!(coroutine::Builder::new().name(name.to_owned()), move || {
+            let shed = server.config.shed_connections;
+            loop {
+                // at the limit the accepts are held off, the backlog takes the burst
+                let slot = if shed {
+                    None
+                } else {
+                    server.connection_slot(true)
+                };
+                let (stream, _) = t_c!(listener.accept());
+                let slot = match slot.or_else(|| server.connection_slot(false)) {
+                    Some(slot) => slot,
+                    None => {
+                        reject_connection(stream, &server.config);
+                        continue;
+                    }
+                };
+                let id = connection_id(&stream);
+                if let Err(e) = server.config.socket.apply(&stream) {
+                    error!("socket options err = {:?}", e);
+                }
+                let ctx = ConnectionContext {
+                    id,
+                    info: ConnectionInfo {
+                        peer: stream.peer_addr().ok(),
+                        local: stream.local_addr().ok(),
+                        proxy: None,
+                        listener: index,
+                        tls: A::TLS,
+                        tls_info: None,
+                    },
+                    accepted: Instant::now(),
+                };
+                let acceptor = acceptor.clone();
+                let server = server.clone();
+                let service = factory.lock().unwrap().new_service_for(&ctx);
+                let mut builder = may::coroutine::Builder::new().id(id);
+                if let Some(size) = server.config.stack_size {
+                    builder = builder.stack_size(size);
+                }
+                go!(builder, move || {
+                    each_connection(stream, ctx, acceptor, service, &server);
+                    drop(slot);
+                })
+                .unwrap();
+            }
+        })?;
+        handles.push(handle);
+    }
+    #[allow(unused_mut)]
+    let mut handle = ServerHandle::new(handles, state);
+    #[cfg(target_os = "linux")]
+    {
+        handle.listeners = fds;
+    }
+    Ok(handle)
+}
+
+/// answer a connection over the limit and close it
+#[cold]
+fn reject_connection(mut stream: TcpStream, config: &Config) {
+    let mut buf = BytesMut::with_capacity(256);
+    let server = &config.server_header;
+    response::encode_rejection(503, "Service Unavailable", server, &mut buf);
+    stream.write_all(&buf).ok();
+    stream.shutdown(std::net::Shutdown::Write).ok();
+}
+
+#[cfg(unix)]
+#[inline]
+fn connection_id(stream: &TcpStream) -> usize {
+    use std::os::fd::AsRawFd;
+    stream.as_raw_fd() as usize
+}
+
+#[cfg(windows)]
+#[inline]
+fn connection_id(stream: &TcpStream) -> usize {
+    use std::os::windows::io::AsRawSocket;
+    stream.as_raw_socket() as usize
+}
+
+/// the byte stream that a connection is served over
+///
+/// the blocking `Read`/`Write` impls park the coroutine, the `*_nonblock`
+/// methods are used by the unix fast path between `reset_io`/`wait_io`
+pub trait Transport: Read + Write {
+    #[cfg(unix)]
+    fn reset_io(&self);
+
+    #[cfg(unix)]
+    fn wait_io(&self);
+
+    /// wake up the connection parked in `wait_io`
+    #[cfg(unix)]
+    fn waker(&self) -> WaitIoWaker;
+
+    /// read all the available bytes into `req_buf` without blocking
+    #[cfg(unix)]
+    fn read_nonblock(&mut self, req_buf: &mut BytesMut) -> io::Result<usize>;
+
+    /// write as much of `write_buf` as possible without blocking
+    #[cfg(unix)]
+    fn write_nonblock(&mut self, write_buf: &mut BytesMut) -> io::Result<usize>;
+
+    /// there are still bytes buffered inside the transport that need a write
+    #[cfg(unix)]
+    fn wants_write(&self) -> bool {
+        false
+    }
+
+    /// bound the blocking reads, the connection timers rely on it where the
+    /// connection can't be woken up
+    #[cfg(not(unix))]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// send `len` bytes of the file from its current position
+    ///
+    /// the bytes are copied through a buffer unless the transport can do better
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        copy_file(file, len, self)
+    }
+
+    /// what the TLS handshake settled, once it is over
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+
+    /// shut down both halves of the connection
+    fn close(&mut self);
+}
+
+/// copy `len` bytes of the file into the writer
+fn copy_file<W: Write + ?Sized>(file: &mut File, len: u64, out: &mut W) -> io::Result<()> {
+    let n = io::copy(&mut file.take(len), out)?;
+    if n < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file is shorter than announced",
+        ));
+    }
+    Ok(())
+}
+
+impl Transport for TcpStream {
+    #[cfg(not(unix))]
+    #[inline]
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn reset_io(&self) {
+        WaitIo::reset_io(self)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn wait_io(&self) {
+        WaitIo::wait_io(self)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn waker(&self) -> WaitIoWaker {
+        WaitIo::waker(self)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn read_nonblock(&mut self, req_buf: &mut BytesMut) -> io::Result<usize> {
+        nonblock_read(self.inner_mut(), req_buf)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn write_nonblock(&mut self, write_buf: &mut BytesMut) -> io::Result<usize> {
+        nonblock_write(self.inner_mut(), write_buf)
+    }
+
+    /// hand the file to the kernel with `sendfile`, the bytes never enter userspace
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &mut File, mut len: u64) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        // the most bytes a single `sendfile` call transfers
+        const MAX_COUNT: u64 = 0x7fff_f000;
+
+        let (sock, fd) = (self.as_raw_fd(), file.as_raw_fd());
+        while len > 0 {
+            WaitIo::reset_io(self);
+            // a null offset sends from the file position and advances it
+            let count = len.min(MAX_COUNT) as usize;
+            match unsafe { libc::sendfile(sock, fd, std::ptr::null_mut(), count) } {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file is shorter than announced",
+                    ))
+                }
+                n if n > 0 => len -= n as u64,
+                _ => {
+                    let err = io::Error::last_os_error();
+                    match err.kind() {
+                        io::ErrorKind::WouldBlock => WaitIo::wait_io(self),
+                        io::ErrorKind::Interrupted => {}
+                        // not supported for this file, copy it instead
+                        _ if err.raw_os_error() == Some(libc::EINVAL) => {
+                            return copy_file(file, len, self)
                         }
-                    )
-                    .unwrap();
+                        _ => return Err(err),
+                    }
                 }
             }
-        )
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.shutdown(std::net::Shutdown::Both).ok();
     }
 }
 
 #[cfg(unix)]
 #[inline]
-fn nonblock_read(stream: &mut impl Read, req_buf: &mut BytesMut) -> io::Result<usize> {
+pub(crate) fn nonblock_read(stream: &mut impl Read, req_buf: &mut BytesMut) -> io::Result<usize> {
     let mut read_cnt = 0;
     loop {
         let read_buf: &mut [u8] = unsafe { std::mem::transmute(&mut *req_buf.chunk_mut()) };
@@ -90,7 +424,10 @@ fn nonblock_read(stream: &mut impl Read, req_buf: &mut BytesMut) -> io::Result<u
 
 #[cfg(unix)]
 #[inline]
-fn nonblock_write(stream: &mut impl Write, write_buf: &mut BytesMut) -> io::Result<usize> {
+pub(crate) fn nonblock_write(
+    stream: &mut impl Write,
+    write_buf: &mut BytesMut,
+) -> io::Result<usize> {
     let len = write_buf.len();
     if len == 0 {
         return Ok(0);
@@ -109,12 +446,319 @@ fn nonblock_write(stream: &mut impl Write, write_buf: &mut BytesMut) -> io::Resu
     Ok(written)
 }
 
-const BUF_LEN: usize = 4096 * 8;
+/// too many response bytes wait for the client to take new requests
+#[inline]
+fn is_backlog(rsp_buf: &BytesMut, config: &Config) -> bool {
+    !rsp_buf.is_empty() && rsp_buf.len() >= config.max_queued_response
+}
+
+fn each_connection<A: Accept, T: HttpService>(
+    mut stream: TcpStream,
+    ctx: ConnectionContext,
+    acceptor: A,
+    mut service: T,
+    server: &ServerState,
+) {
+    let ConnectionContext { id, mut info, .. } = ctx;
+    let config = &server.config;
+    if let Some(ref trusted) = config.proxy_protocol {
+        if trusted.trusts(info.listener) {
+            match proxy_protocol::read_source(&mut stream, config.header_timeout) {
+                Ok(Some(client)) => {
+                    info.proxy = info.peer;
+                    info.peer = Some(client);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("proxy protocol err = {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+    let hooks = server.config.hooks.as_ref().map(|h| &h.0);
+    if let Some(hooks) = hooks {
+        hooks.on_connect(info.peer);
+    }
+    let timeout = config.header_timeout.or(config.idle_timeout);
+    let mut stream = match acceptor.accept(stream, timeout) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("accept err = {:?}", e);
+            if let Some(hooks) = hooks {
+                hooks.on_disconnect(&Disconnect::Error(e));
+            }
+            return;
+        }
+    };
+    let _open = server.config.metrics.as_ref().map(Metrics::connection);
+    #[cfg(unix)]
+    let mut conn = server.register(id, stream.waker(), info);
+    #[cfg(not(unix))]
+    let mut conn = server.register(id, (), info);
+    service.on_connection_start();
+    let ret = each_connection_loop(&mut stream, &mut service, server, &mut conn);
+    service.on_connection_end();
+    if let Err(ref e) = ret {
+        error!("service err = {:?}", e);
+        stream.close();
+    }
+    if let Some(hooks) = hooks {
+        hooks.on_disconnect(&Disconnect::of(ret));
+    }
+}
+
+/// the per connection header storage, bound to the lifetime of the request buffer
+#[inline]
+fn request_headers<'a, 'b>(
+    buf: &'a mut [MaybeUninit<httparse::Header<'static>>],
+) -> &'a mut [MaybeUninit<httparse::Header<'b>>] {
+    // SAFETY: the headers are uninitialized, only the borrowed lifetime is changed
+    unsafe { std::mem::transmute(buf) }
+}
+
+/// discard the part of a streamed request body that the service didn't read
+/// the bytes are read into the spare capacity of `scratch` without touching its content
+fn drain_body(
+    stream: &mut dyn Transport,
+    left: &mut BodyLeft,
+    scratch: &mut BytesMut,
+) -> io::Result<()> {
+    let mut body = SocketBody::new(stream, left);
+    scratch.reserve(4096);
+    loop {
+        let read_buf: &mut [u8] = unsafe { std::mem::transmute(&mut *scratch.chunk_mut()) };
+        match body.read(read_buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// answer a request that can't be decoded before the connection is closed
+///
+/// HTTP/2 is not served, a client that opens with the h2 connection preface
+/// (prior knowledge or `h2` negotiated by ALPN) is told to retry over HTTP/1.1.
+/// a request refused by the configured limits gets its error status,
+/// after the responses still pending in `rsp_buf`
+#[cold]
+fn reject_request<S: Write>(
+    stream: &mut S,
+    config: &Config,
+    req_buf: &[u8],
+    rsp_buf: &mut BytesMut,
+    err: io::Error,
+) -> io::Error {
+    if req_buf.starts_with(request::H2_PREFACE) {
+        stream.write_all(response::H2_GOAWAY).ok();
+        return io::Error::new(io::ErrorKind::Unsupported, "http2 is not supported");
+    }
+    // any request that can't be parsed is answered before the connection is closed
+    let (code, msg) = match Rejection::of(&err) {
+        Some(r) => (r.code, r.msg),
+        None => (400, "Bad Request"),
+    };
+    response::encode_rejection(code, msg, &config.server_header, rsp_buf);
+    stream.write_all(rsp_buf).ok();
+    rsp_buf.clear();
+    err
+}
+
+/// hand a served request to the access log, the metrics and the hooks, if there are any
 #[inline]
-fn reserve_buf(buf: &mut BytesMut) {
-    let capacity = buf.capacity();
-    if capacity < 1024 {
-        buf.reserve(BUF_LEN - capacity);
+fn observe(
+    config: &Config,
+    peer: Option<SocketAddr>,
+    access: Option<Pending>,
+    start: Option<Instant>,
+    status: usize,
+    bytes_in: u64,
+    bytes_out: u64,
+) {
+    if let (Some(sink), Some(access)) = (&config.access_log, access) {
+        access.finish(sink, peer, status, bytes_out);
+    }
+    let latency = match start {
+        Some(start) => start.elapsed(),
+        None => return,
+    };
+    if let Some(ref metrics) = config.metrics {
+        metrics.observe(status, latency, bytes_in, bytes_out);
+    }
+    if let Some(ref hooks) = config.hooks {
+        hooks.0.on_response(status, latency);
+    }
+}
+
+/// asserts that a value is only used by one coroutine at a time
+#[cfg(not(panic = "abort"))]
+struct AssertSend<T>(T);
+
+#[cfg(not(panic = "abort"))]
+unsafe impl<T> Send for AssertSend<T> {}
+
+#[cfg(not(panic = "abort"))]
+impl<T> AssertSend<T> {
+    // take the value as a whole so closures don't capture the inner fields
+    #[inline]
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[cfg(not(panic = "abort"))]
+enum Handler {
+    Started(coroutine::Coroutine),
+    Done(thread::Result<io::Result<()>>),
+}
+
+/// call the service, a panic in it is answered with `500 Internal Server Error`
+/// and closes the connection as the service may be left in a bad state
+#[cfg(not(panic = "abort"))]
+fn call_service<T: HttpService>(
+    service: &mut T,
+    req: Request,
+    rsp: &mut Response,
+    config: &Config,
+) -> io::Result<()> {
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| match config.request_timeout {
+        None => service.call(req, rsp),
+        Some(timeout) => call_timeout(service, req, rsp, timeout),
+    }));
+    match ret {
+        Ok(ret) => ret,
+        // the cancel of the coroutine unwinds through here, let it go on
+        Err(panic) if panic.is::<coroutine::Error>() => panic::resume_unwind(panic),
+        Err(panic) => {
+            let msg = match panic.downcast_ref::<&str>() {
+                Some(s) => s,
+                None => panic.downcast_ref::<String>().map_or("..", |s| s.as_str()),
+            };
+            error!("service panicked: {}", msg);
+            rsp.reset();
+            rsp.set_close();
+            Err(Rejection::error(500, "Internal Server Error"))
+        }
+    }
+}
+
+/// call the service, a panic aborts the process so there is nothing to recover
+/// and `request_timeout` was refused at the start
+#[cfg(panic = "abort")]
+#[inline]
+fn call_service<T: HttpService>(
+    service: &mut T,
+    req: Request,
+    rsp: &mut Response,
+    _config: &Config,
+) -> io::Result<()> {
+    service.call(req, rsp)
+}
+
+/// run the service call in a scoped coroutine and cancel it once `timeout` elapses
+/// the connection coroutine is parked meanwhile, so the borrowed request is never shared
+///
+/// the cancel unwinds the handler coroutine, so the server refuses to start with a
+/// timeout in a build with `panic = "abort"`. a canceled handler may leave the
+/// service and the response half done, the response is dropped and the connection
+/// closed after the `504`
+#[cfg(not(panic = "abort"))]
+fn call_timeout<T: HttpService>(
+    service: &mut T,
+    req: Request,
+    rsp: &mut Response,
+    timeout: Duration,
+) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let call = AssertSend((service, req, &mut *rsp, tx));
+    let ret = coroutine::scope(|s| {
+        let handler = move || {
+            let (service, req, rsp, tx) = call.into_inner();
+            tx.send(Handler::Started(coroutine::current())).ok();
+            let ret = panic::catch_unwind(AssertUnwindSafe(|| service.call(req, rsp)));
+            tx.send(Handler::Done(ret)).ok();
+        };
+        unsafe { s.spawn(handler) };
+
+        let co = match rx.recv() {
+            Ok(Handler::Started(co)) => co,
+            _ => unreachable!("handler not started"),
+        };
+        match rx.recv_timeout(timeout) {
+            Ok(Handler::Done(Ok(ret))) => Some(ret),
+            Ok(Handler::Done(Err(panic))) => panic::resume_unwind(panic),
+            _ => {
+                // the scope waits for the handler to unwind
+                unsafe { co.cancel() };
+                None
+            }
+        }
+    });
+    match ret {
+        Some(ret) => ret,
+        None => {
+            rsp.reset();
+            rsp.set_close();
+            Err(Rejection::error(504, "Gateway Timeout"))
+        }
+    }
+}
+
+/// send out the last responses and shut down the connection
+fn close_after<S: Transport>(stream: &mut S, rsp_buf: &[u8]) -> io::Result<()> {
+    stream.write_all(rsp_buf)?;
+    stream.flush()?;
+    stream.close();
+    Ok(())
+}
+
+/// send out the pending responses together with the event stream head,
+/// then forward every event to the client until all the senders are gone
+fn serve_sse<S: Transport>(
+    stream: &mut S,
+    rsp: Response,
+    rsp_buf: &mut BytesMut,
+) -> io::Result<()> {
+    let events = response::encode_sse_head(rsp, rsp_buf);
+    stream.write_all(rsp_buf)?;
+    rsp_buf.clear();
+    while let Ok(event) = events.recv() {
+        stream.write_all(&event)?;
+        stream.flush()?;
+    }
+    stream.close();
+    Ok(())
+}
+
+/// send out the pending responses together with the `101` head,
+/// then hand the connection to the upgrade handler until it is done
+fn serve_upgrade<S: Transport>(
+    stream: &mut S,
+    rsp: Response,
+    rsp_buf: &mut BytesMut,
+    buffered: &[u8],
+) -> io::Result<()> {
+    let handler = response::encode_upgrade_head(rsp, rsp_buf);
+    stream.write_all(rsp_buf)?;
+    stream.flush()?;
+    rsp_buf.clear();
+    let ret = handler(Upgraded::new(stream, buffered));
+    stream.close();
+    ret
+}
+
+/// let the error handler answer a failed service call
+fn handle_error(config: &Config, ret: io::Result<()>, rsp: &mut Response) -> io::Result<()> {
+    match (ret, &config.error_handler) {
+        (Err(e), Some(handler)) if Rejection::of(&e).is_none() => {
+            error!("error in service: err = {:?}", e);
+            rsp.reset();
+            handler.0.handle(&e, rsp);
+            Ok(())
+        }
+        (ret, _) => ret,
     }
 }
 
@@ -123,102 +767,411 @@ fn reserve_buf(buf: &mut BytesMut) {
 ///
 pub struct HttpServer<T>(pub T);
 
+/// what `serve_next` did with the buffered bytes
+enum Next {
+    /// no complete request is buffered yet
+    Incomplete,
+    /// a request of this many bytes was served
+    Served(usize),
+    /// the connection is over, closed or handed to an event stream or an upgrade
+    Closed,
+}
+
+/// the header storage and the head progress of a connection
+struct Heads {
+    headers: Vec<MaybeUninit<httparse::Header<'static>>>,
+    // the progress of the pending request head
+    scan: HeadScan,
+}
+
+impl Heads {
+    fn new(config: &Config) -> Self {
+        Heads {
+            headers: vec![MaybeUninit::uninit(); config.max_headers],
+            scan: HeadScan::default(),
+        }
+    }
+}
+
+/// decode the next request of `req_buf`, run the service and queue the response
+///
+/// the per request part of both connection loops
+fn serve_next<S: Transport, T: HttpService>(
+    stream: &mut S,
+    service: &mut T,
+    conn: &mut ConnGuard,
+    req_buf: &BytesMut,
+    heads: &mut Heads,
+    rsp_buf: &mut BytesMut,
+    body_buf: &mut BytesMut,
+) -> io::Result<Next> {
+    let server = conn.server();
+    let config = &server.config;
+    let req = request::decode(
+        req_buf,
+        request_headers(&mut heads.headers),
+        &mut heads.scan,
+        config,
+    )
+    .map_err(|e| reject_request(stream, config, req_buf, rsp_buf, e))?;
+    let Some(mut req) = req else {
+        return Ok(Next::Incomplete);
+    };
+    if conn.info().tls && conn.info().tls_info.is_none() {
+        // the handshake is over once a request came through
+        conn.set_tls_info(stream.tls_info());
+    }
+    req.set_connection(conn.info().clone());
+    let len = req.len();
+    let mut body_left = req.body_left();
+    let bytes_in = (len + body_left.len) as u64;
+    if body_left.pending() {
+        if body_left.expect_continue && !rsp_buf.is_empty() {
+            // the interim response must not overtake the pending ones
+            stream.write_all(rsp_buf)?;
+            rsp_buf.clear();
+        }
+        req.stream_body(stream, &mut body_left);
+    }
+    let requests = conn.count_request();
+    let mut rsp = Response::new(body_buf, &config.server_header);
+    if req.version() == 0 {
+        rsp.set_http10();
+    }
+    if req.method() == Method::Head {
+        rsp.set_head();
+    }
+    if !req.keep_alive() || server.draining() || config.max_requests == Some(requests) {
+        rsp.set_close();
+    }
+    let if_none_match = req.not_modified_tags();
+    let access = config.access_log.as_ref().map(|_| Pending::new(&req));
+    let start = (config.metrics.is_some() || config.hooks.is_some()).then(Instant::now);
+    if let Some(ref hooks) = config.hooks {
+        hooks.0.on_request(&req);
+    }
+    let ret = match server.enter() {
+        Some(_in_flight) => call_service(service, req, &mut rsp, config),
+        None => Err(Rejection::error(503, "Service Unavailable")),
+    };
+    let ret = handle_error(config, ret, &mut rsp);
+    if let (Ok(()), Some(tags)) = (&ret, if_none_match) {
+        rsp.check_not_modified(tags);
+    }
+    if body_left.expect_continue {
+        // the body was never asked for, the client may or may not send it
+        rsp.set_close();
+    }
+    let close = rsp.is_close();
+    let status = match ret {
+        Ok(()) => rsp.code(),
+        Err(ref e) => response::error_status(e),
+    };
+    let bytes = match ret {
+        Ok(()) if rsp.is_sse() => {
+            let peer = conn.info().peer;
+            observe(config, peer, access, start, status, bytes_in, 0);
+            return serve_sse(stream, rsp, rsp_buf).map(|()| Next::Closed);
+        }
+        Ok(()) if rsp.is_upgrade() => {
+            let peer = conn.info().peer;
+            observe(config, peer, access, start, status, bytes_in, 0);
+            return serve_upgrade(stream, rsp, rsp_buf, &req_buf[len..]).map(|()| Next::Closed);
+        }
+        Ok(()) if rsp.is_stream() => response::encode_stream(rsp, rsp_buf, stream)?,
+        Ok(()) if rsp.is_file() => response::encode_file(rsp, rsp_buf, stream)?,
+        Ok(()) if rsp.is_shared() => response::encode_shared(rsp, rsp_buf, stream)?,
+        Ok(()) => response::encode(rsp, rsp_buf),
+        Err(e) => response::encode_error(e, &rsp, rsp_buf),
+    };
+    observe(
+        config,
+        conn.info().peer,
+        access,
+        start,
+        status,
+        bytes_in,
+        bytes,
+    );
+    if close {
+        return close_after(stream, rsp_buf).map(|()| Next::Closed);
+    }
+    if body_left.pending() {
+        drain_body(stream, &mut body_left, rsp_buf)?;
+    }
+    Ok(Next::Served(len))
+}
+
 #[cfg(unix)]
-fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T) -> io::Result<()> {
-    let mut req_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut rsp_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut body_buf = BytesMut::with_capacity(BUF_LEN);
+fn each_connection_loop<S: Transport, T: HttpService>(
+    stream: &mut S,
+    service: &mut T,
+    server: &ServerState,
+    conn: &mut ConnGuard,
+) -> io::Result<()> {
+    let config = &server.config;
+    let mut req_buf = server.buffers.get(conn.id());
+    let mut rsp_buf = server.buffers.get(conn.id());
+    let mut body_buf = server.buffers.get(conn.id());
+    let mut heads = Heads::new(config);
+    // requests were served since the last wait
+    let mut served = false;
+    // `100 Continue` was sent for the pending request
+    let mut continued = false;
+    // the parsing stopped on too many queued response bytes
+    let mut paused = false;
 
     loop {
         stream.reset_io();
-
-        let inner_stream = stream.inner_mut();
+        req_buf.ensure();
+        rsp_buf.ensure();
+        body_buf.ensure();
 
         // write out the responses
-        nonblock_write(inner_stream, &mut rsp_buf)?;
+        let written = stream.write_nonblock(&mut rsp_buf)?;
+        // whatever is left over hit `WouldBlock`
+        let blocked = !rsp_buf.is_empty() || stream.wants_write();
+
+        // hold off the requests until the client takes the queued responses
+        let backlog = is_backlog(&rsp_buf, config);
+        let resumed = paused && !backlog;
 
         // read the socket for requests
-        reserve_buf(&mut req_buf);
-        let read_cnt = nonblock_read(inner_stream, &mut req_buf)?;
+        let read_cnt = if backlog {
+            0
+        } else {
+            req_buf.make_room();
+            stream.read_nonblock(&mut req_buf)?
+        };
 
         // prepare the requests
-        if read_cnt > 0 {
-            let mut headers = unsafe { MaybeUninit::uninit().assume_init() };
-            while let Some(req) = request::decode(&req_buf, &mut headers)? {
-                let len = req.len();
-                let mut rsp = Response::new(&mut body_buf);
-                match service.call(req, &mut rsp) {
-                    Ok(()) => response::encode(rsp, &mut rsp_buf),
-                    Err(e) => response::encode_error(e, &mut rsp_buf),
-                }
-                headers = unsafe { std::mem::transmute(headers) };
+        if read_cnt > 0 || resumed {
+            paused = false;
+            while heads.scan.ready(&req_buf, config) {
+                let len = match serve_next(
+                    stream,
+                    service,
+                    conn,
+                    &req_buf,
+                    &mut heads,
+                    &mut rsp_buf,
+                    &mut body_buf,
+                )? {
+                    Next::Incomplete => break,
+                    Next::Served(len) => len,
+                    Next::Closed => return Ok(()),
+                };
                 req_buf.advance(len);
+                heads.scan.reset();
+                served = true;
+                continued = false;
+                if is_backlog(&rsp_buf, config) {
+                    paused = true;
+                    break;
+                }
+            }
+            if !paused
+                && !continued
+                && heads.scan.is_complete()
+                && request::wants_continue(&req_buf, request_headers(&mut heads.headers))
+            {
+                rsp_buf.extend_from_slice(response::CONTINUE);
+                continued = true;
             }
         }
 
-        if rsp_buf.is_empty() {
+        if rsp_buf.is_empty() && !stream.wants_write() {
+            // an idle connection woken up by the shutdown or its timer
+            let idle = req_buf.is_empty();
+            if server.draining() && idle {
+                stream.close();
+                return Ok(());
+            }
+            if !conn.before_wait(idle, served) {
+                if !idle {
+                    let server = &config.server_header;
+                    response::encode_rejection(408, "Request Timeout", server, &mut rsp_buf);
+                }
+                return close_after(stream, &rsp_buf);
+            }
+            served = false;
+            if idle {
+                // nothing to hold on to until the next request
+                req_buf.release();
+                rsp_buf.release();
+                body_buf.release();
+            }
+            stream.wait_io();
+        } else if read_cnt == 0 && !resumed {
+            if !blocked {
+                // the read queued bytes of its own (a TLS handshake), flush them first
+                continue;
+            }
+            // the socket takes no more bytes, wait until the client reads some
+            if !conn.before_write_wait(written > 0) {
+                stream.close();
+                return Ok(());
+            }
             stream.wait_io();
         }
     }
 }
 
+/// the loop of the platforms without `WaitIo`, the reads and writes block
+///
+/// each read is followed by a single write of all the pipelined responses,
+/// the connection timers bound the blocking reads instead of waking it up
 #[cfg(not(unix))]
-fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T) -> io::Result<()> {
-    let mut req_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut rsp_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut body_buf = BytesMut::with_capacity(BUF_LEN);
+fn each_connection_loop<S: Transport, T: HttpService>(
+    stream: &mut S,
+    service: &mut T,
+    server: &ServerState,
+    conn: &mut ConnGuard,
+) -> io::Result<()> {
+    let config = &server.config;
+    let mut req_buf = server.buffers.get(conn.id());
+    let mut rsp_buf = server.buffers.get(conn.id());
+    let mut body_buf = server.buffers.get(conn.id());
+    let mut heads = Heads::new(config);
+    // `100 Continue` was sent for the pending request
+    let mut continued = false;
+    // when the pending request head started to arrive
+    let mut head_since: Option<Instant> = None;
+    // the read timeout set on the socket
+    let mut read_timeout = None;
     loop {
+        req_buf.ensure();
+        rsp_buf.ensure();
+        body_buf.ensure();
+
+        // the connection can't be woken up, the timers bound the blocking read instead
+        let idle = req_buf.is_empty();
+        if server.draining() && idle {
+            stream.close();
+            return Ok(());
+        }
+        let timeout = if idle {
+            head_since = None;
+            config.idle_timeout
+        } else {
+            let since = *head_since.get_or_insert_with(Instant::now);
+            config.header_timeout.map(|t| {
+                t.saturating_sub(since.elapsed())
+                    .max(Duration::from_millis(1))
+            })
+        };
+        if timeout != read_timeout {
+            stream.set_read_timeout(timeout)?;
+            read_timeout = timeout;
+        }
+
         // read the socket for requests
-        reserve_buf(&mut req_buf);
+        req_buf.make_room();
         let read_buf: &mut [u8] = unsafe { std::mem::transmute(&mut *req_buf.chunk_mut()) };
-        let read_cnt = stream.read(read_buf)?;
-        if read_cnt == 0 {
+        let read_cnt = match stream.read(read_buf) {
             //connection was closed
-            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
-        }
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                if !idle {
+                    let server = &config.server_header;
+                    response::encode_rejection(408, "Request Timeout", server, &mut rsp_buf);
+                }
+                return close_after(stream, &rsp_buf);
+            }
+            Err(e) => return Err(e),
+        };
         unsafe { req_buf.advance_mut(read_cnt) };
 
         // prepare the requests
         if read_cnt > 0 {
-            let mut headers = [MaybeUninit::<httparse::Header>::uninit(); request::MAX_HEADERS];
-            while let Some(req) = request::decode(&req_buf, &mut headers)? {
-                let len = req.len();
-                let mut rsp = Response::new(&mut body_buf);
-                match service.call(req, &mut rsp) {
-                    Ok(()) => response::encode(rsp, &mut rsp_buf),
-                    Err(e) => response::encode_error(e, &mut rsp_buf),
-                }
-                headers = [MaybeUninit::<httparse::Header>::uninit(); request::MAX_HEADERS];
+            while heads.scan.ready(&req_buf, config) {
+                let len = match serve_next(
+                    stream,
+                    service,
+                    conn,
+                    &req_buf,
+                    &mut heads,
+                    &mut rsp_buf,
+                    &mut body_buf,
+                )? {
+                    Next::Incomplete => break,
+                    Next::Served(len) => len,
+                    Next::Closed => return Ok(()),
+                };
                 req_buf.advance(len);
+                heads.scan.reset();
+                continued = false;
+                head_since = None;
+                if is_backlog(&rsp_buf, config) {
+                    // the client takes the queued responses before the next ones
+                    stream.write_all(&rsp_buf)?;
+                    rsp_buf.clear();
+                }
+            }
+            if !continued
+                && heads.scan.is_complete()
+                && request::wants_continue(&req_buf, request_headers(&mut heads.headers))
+            {
+                rsp_buf.extend_from_slice(response::CONTINUE);
+                continued = true;
             }
         }
 
         // send the result back to client
-        stream.write_all(rsp_buf.as_ref())?;
+        stream.write_all(&rsp_buf)?;
+        rsp_buf.clear();
+    }
+}
+
+impl<T: HttpService + Clone + Send + Sync + 'static> HttpServiceFactory for HttpServer<T> {
+    type Service = T;
+
+    #[inline]
+    fn new_service(&self, _id: usize) -> T {
+        self.0.clone()
     }
 }
 
 impl<T: HttpService + Clone + Send + Sync + 'static> HttpServer<T> {
     /// Spawns the http service, binding to the given address
-    /// return a coroutine that you can cancel it when need to stop the service
-    pub fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<coroutine::JoinHandle<()>> {
-        let listener = TcpListener::bind(addr)?;
-        let service = self.0;
-        go!(
-            coroutine::Builder::new().name("TcpServer".to_owned()),
-            move || {
-                for stream in listener.incoming() {
-                    let mut stream = t_c!(stream);
-                    // t_c!(stream.set_nodelay(true));
-                    let service = service.clone();
-                    go!(
-                        move || if let Err(e) = each_connection_loop(&mut stream, service) {
-                            error!("service err = {:?}", e);
-                            stream.shutdown(std::net::Shutdown::Both).ok();
-                        }
-                    );
-                }
-            }
-        )
+    /// return a handle to wait for or shut down the server
+    pub fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerHandle> {
+        ServerBuilder::new().start(addr, self)
+    }
+
+    /// Spawns the http service on all the addresses
+    /// return a handle to wait for or shut down the server
+    ///
+    /// the addresses are TCP only, Unix domain sockets are not supported
+    pub fn start_all<I, L>(self, addrs: I) -> io::Result<ServerHandle>
+    where
+        I: IntoIterator<Item = L>,
+        L: ToSocketAddrs,
+    {
+        ServerBuilder::new().start_all(addrs, self)
+    }
+
+    /// Spawns the http service on a listener that is already bound
+    /// return a handle to wait for or shut down the server
+    pub fn start_with_listener(self, listener: std::net::TcpListener) -> io::Result<ServerHandle> {
+        ServerBuilder::new().start_with_listener(listener, self)
+    }
+
+    /// Spawns the https service, binding to the given address
+    /// each accepted connection is wrapped in a TLS session by the `acceptor`
+    /// return a handle to wait for or shut down the server
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub fn start_tls<L: ToSocketAddrs, A: TlsAcceptor>(
+        self,
+        addr: L,
+        acceptor: A,
+    ) -> io::Result<ServerHandle> {
+        ServerBuilder::new().start_tls(addr, self, acceptor)
     }
 }
@@ -0,0 +1,33 @@
+//! JSON bodies with serde
+
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::request::Request;
+use crate::response::{BodyWriter, Response};
+
+impl<'a, 'header> Request<'a, 'header> {
+    /// deserialize the JSON body, a streamed body is read from the socket
+    ///
+    /// a body that doesn't parse into `T` is an `InvalidData` error
+    pub fn json<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        serde_json::from_reader(self.body_reader()).map_err(|e| {
+            if e.is_io() {
+                e.into()
+            } else {
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            }
+        })
+    }
+}
+
+impl<'a> Response<'a> {
+    /// serialize the value as the body, with `Content-Type: application/json`
+    pub fn json<T: Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        self.header_replace("Content-Type", "application/json");
+        serde_json::to_writer(BodyWriter(self.body_mut()), value)?;
+        Ok(())
+    }
+}
@@ -1,11 +1,116 @@
 #[macro_use]
 extern crate log;
 
+mod access_log;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+mod acme;
+mod auth;
+mod balance;
+mod buf_pool;
+mod builder;
+pub mod client;
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+mod compression;
+mod connection;
+mod cookie;
 mod date;
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+mod decompression;
+mod error;
+mod error_handler;
+mod extensions;
+mod forwarded;
+mod handle;
+#[cfg(target_os = "linux")]
+pub mod handoff;
+mod headers;
+mod hooks;
+#[cfg(feature = "http")]
+mod http_compat;
 mod http_server;
+#[cfg(feature = "serde")]
+mod json;
+mod method;
+mod metrics;
+mod middleware;
+mod multipart;
+mod path;
+mod pool;
+#[cfg(target_os = "linux")]
+mod prefork;
+mod proxy;
+mod proxy_protocol;
+mod query;
 mod request;
+mod request_id;
 mod response;
+mod router;
+#[cfg(feature = "session")]
+mod session;
+mod socket;
+mod sse;
+mod state;
+mod static_files;
+mod status;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+mod tls;
+#[cfg(feature = "native-tls")]
+mod tls_native;
+#[cfg(feature = "rustls")]
+mod tls_rustls;
+#[cfg(feature = "tower")]
+mod tower;
+mod trace;
+mod trailers;
+mod upgrade;
+mod upstream;
+mod vhost;
 
+pub use access_log::{AccessLog, AccessRecord, WriterLog};
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub use acme::{Acme, AcmeCertificate, AcmeChallenges, AcmeIssuer, CertStore, DirStore};
+pub use auth::{BasicAuth, BearerAuth, TokenVerifier};
+pub use balance::Balance;
+pub use builder::ServerBuilder;
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+pub use compression::Compression;
+pub use connection::{ConnectionContext, ConnectionInfo, PeerCertificate, TlsInfo};
+pub use cookie::{Cookie, SameSite};
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+pub use decompression::Decompression;
+pub use error::HttpError;
+pub use error_handler::ErrorHandler;
+pub use extensions::Extensions;
+pub use forwarded::ForwardedFor;
+pub use handle::ServerHandle;
+pub use headers::{Authorization, ByteRange, EntityTags, MediaType};
+pub use hooks::{Disconnect, ServerHooks};
 pub use http_server::{HttpServer, HttpService, HttpServiceFactory};
-pub use request::Request;
-pub use response::{BodyWriter, Response};
+pub use method::Method;
+pub use metrics::Metrics;
+pub use middleware::{Chain, Middleware, Next};
+pub use multipart::{Multipart, Part};
+pub use path::DecodedPath;
+pub use proxy::ProxyService;
+pub use request::{BodyReader, Request};
+pub use request_id::RequestId;
+pub use response::{BodySender, BodyWriter, Response};
+pub use router::{Handler, Router};
+#[cfg(feature = "session")]
+pub use session::{Session, Sessions};
+pub use sse::SseSender;
+pub use state::WithState;
+pub use static_files::StaticFiles;
+pub use status::StatusCode;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub use tls::{ReloadableTls, TlsAcceptor};
+#[cfg(feature = "rustls")]
+pub use tls_rustls::SniResolver;
+#[cfg(feature = "tower")]
+pub use tower::TowerService;
+pub use trace::TraceContext;
+pub use trailers::Trailers;
+pub use upgrade::Upgraded;
+pub use vhost::VirtualHosts;
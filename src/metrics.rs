@@ -0,0 +1,166 @@
+//! server metrics in the prometheus text format
+
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http_server::HttpService;
+use crate::request::Request;
+use crate::response::Response;
+
+/// the upper bounds of the request duration histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// the counters of a server, see `ServerBuilder::metrics`
+///
+/// the same value is handed to the builder and mounted as the `/metrics` handler,
+/// it answers with the prometheus text format
+///
+/// ```ignore
+/// let metrics = Metrics::new();
+/// let router = Router::new()
+///     .get("/", index)
+///     .get("/metrics", { let m = metrics.clone(); move |req, rsp| m.serve(req, rsp) });
+/// ServerBuilder::new().metrics(metrics).start("0.0.0.0:8080", HttpServer(router))?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    // by status class, 1xx to 5xx
+    requests: [AtomicU64; 5],
+    // the requests in each bucket, the last one is `+Inf`
+    durations: [AtomicU64; BUCKETS.len() + 1],
+    duration_micros: AtomicU64,
+    connections: AtomicI64,
+    accepted: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// count a served request
+    pub(crate) fn observe(&self, status: usize, latency: Duration, bytes_in: u64, bytes_out: u64) {
+        let m = &self.0;
+        let class = (status / 100).clamp(1, 5) - 1;
+        m.requests[class].fetch_add(1, Ordering::Relaxed);
+        let secs = latency.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(BUCKETS.len());
+        m.durations[bucket].fetch_add(1, Ordering::Relaxed);
+        m.duration_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        m.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        m.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    /// count an open connection until the returned guard is dropped
+    pub(crate) fn connection(&self) -> OpenConnection {
+        self.0.accepted.fetch_add(1, Ordering::Relaxed);
+        self.0.connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self.clone())
+    }
+
+    /// the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let m = &self.0;
+        let mut out = String::with_capacity(2048);
+
+        out.push_str("# HELP http_requests_total Requests served by status class.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for (i, n) in m.requests.iter().enumerate() {
+            let n = n.load(Ordering::Relaxed);
+            writeln!(out, "http_requests_total{{class=\"{}xx\"}} {n}", i + 1).unwrap();
+        }
+
+        out.push_str("# HELP http_request_duration_seconds Time to handle a request.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        let mut count = 0;
+        for (i, n) in m.durations.iter().enumerate() {
+            count += n.load(Ordering::Relaxed);
+            match BUCKETS.get(i) {
+                Some(le) => writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{le=\"{le}\"}} {count}"
+                ),
+                None => writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+                ),
+            }
+            .unwrap();
+        }
+        let sum = m.duration_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "http_request_duration_seconds_sum {sum}").unwrap();
+        writeln!(out, "http_request_duration_seconds_count {count}").unwrap();
+
+        let scalars = [
+            (
+                "http_open_connections",
+                "gauge",
+                "Connections currently open.",
+                m.connections.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "http_connections_total",
+                "counter",
+                "Connections accepted.",
+                m.accepted.load(Ordering::Relaxed),
+            ),
+            (
+                "http_request_bytes_total",
+                "counter",
+                "Bytes of the request heads and bodies.",
+                m.bytes_in.load(Ordering::Relaxed),
+            ),
+            (
+                "http_response_body_bytes_total",
+                "counter",
+                "Bytes of the response bodies.",
+                m.bytes_out.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, kind, help, value) in scalars {
+            writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// answer with the current metrics
+    pub fn serve(&self, _req: Request, rsp: &mut Response) -> io::Result<()> {
+        rsp.header("Content-Type: text/plain; version=0.0.4");
+        rsp.body_vec(self.render().into_bytes());
+        Ok(())
+    }
+}
+
+impl HttpService for Metrics {
+    #[inline]
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        self.serve(req, rsp)
+    }
+}
+
+/// an open connection counted by the metrics
+pub(crate) struct OpenConnection(Metrics);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0 .0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,97 @@
+//! composable middleware around any `HttpService`
+
+use std::io;
+use std::sync::Arc;
+
+use crate::http_server::HttpService;
+use crate::request::Request;
+use crate::response::Response;
+
+/// a cross-cutting step that wraps the service call
+///
+/// call `next.run(req, rsp)` to pass the request on, or answer it directly
+/// to short-circuit the rest of the chain
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, req: Request, rsp: &mut Response, next: Next) -> io::Result<()>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, &mut Response, Next) -> io::Result<()> + Send + Sync + 'static,
+{
+    #[inline]
+    fn handle(&self, req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        self(req, rsp, next)
+    }
+}
+
+/// the rest of the middleware chain followed by the service
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Middleware>],
+    service: &'a mut dyn HttpService,
+}
+
+impl<'a> Next<'a> {
+    /// run the remaining middleware and the service
+    pub fn run(self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        match self.chain.split_first() {
+            Some((first, chain)) => first.handle(
+                req,
+                rsp,
+                Next {
+                    chain,
+                    service: self.service,
+                },
+            ),
+            None => self.service.call(req, rsp),
+        }
+    }
+}
+
+/// a service wrapped by a chain of middleware
+///
+/// the middleware run in the order they are added, the service comes last
+///
+/// ```ignore
+/// let service = Chain::new(HelloWorld).wrap(AccessLog).wrap(Auth::new(..));
+/// HttpServer(service).start("0.0.0.0:8080")?;
+/// ```
+#[derive(Clone)]
+pub struct Chain<S> {
+    chain: Arc<Vec<Arc<dyn Middleware>>>,
+    service: S,
+}
+
+impl<S: HttpService> Chain<S> {
+    pub fn new(service: S) -> Self {
+        Chain {
+            chain: Arc::new(Vec::new()),
+            service,
+        }
+    }
+
+    /// append a middleware to the chain
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Self {
+        Arc::make_mut(&mut self.chain).push(Arc::new(middleware));
+        self
+    }
+}
+
+impl<S: HttpService> HttpService for Chain<S> {
+    #[inline]
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        let next = Next {
+            chain: &self.chain,
+            service: &mut self.service,
+        };
+        next.run(req, rsp)
+    }
+
+    fn on_connection_start(&mut self) {
+        self.service.on_connection_start();
+    }
+
+    fn on_connection_end(&mut self) {
+        self.service.on_connection_end();
+    }
+}
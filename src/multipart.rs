@@ -0,0 +1,365 @@
+//! streaming `multipart/form-data` bodies
+
+use std::io::{self, Read};
+
+use crate::headers::{self, MediaType};
+use crate::request::{BodyReader, Request};
+
+// the size of the reads from the body
+const READ_LEN: usize = 4096 * 2;
+// the headers of a part can't be bigger
+const MAX_PART_HEAD: usize = 4096 * 2;
+
+/// the parts of a `multipart/form-data` body, read one after the other
+///
+/// the content of a part is streamed from the body, only a small window of it
+/// is held in memory, so big uploads can be written out as they arrive
+///
+/// ```ignore
+/// let mut form = Multipart::from_request(&mut req)?;
+/// while let Some(mut part) = form.next_part()? {
+///     // only the last component of the client's name, or better a generated one
+///     let path = part
+///         .filename()
+///         .and_then(|name| Path::new(name).file_name())
+///         .map(|name| dir.join(name));
+///     match path {
+///         Some(path) => io::copy(&mut part, &mut File::create(path)?)?,
+///         None => io::copy(&mut part, &mut io::sink())?,
+///     };
+/// }
+/// ```
+pub struct Multipart<R> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize,
+    // `\r\n--boundary`
+    delim: Vec<u8>,
+    state: State,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // in the preamble or the content of a part
+    Content,
+    // right after a delimiter
+    Delimiter,
+    Done,
+}
+
+impl<'r, 's> Multipart<BodyReader<'r, 's>> {
+    /// read the body of a `multipart/form-data` request
+    pub fn from_request(req: &'r mut Request<'_, 's>) -> io::Result<Self> {
+        let boundary = req
+            .content_type()
+            .filter(|t| t.is("multipart", "form-data"))
+            .and_then(|t| t.param("boundary"))
+            .ok_or_else(|| invalid("not a multipart/form-data request"))?;
+        let boundary = boundary.to_owned();
+        Ok(Multipart::new(req.body_reader(), &boundary))
+    }
+}
+
+impl<R: Read> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        let mut delim = b"\r\n--".to_vec();
+        delim.extend_from_slice(boundary.as_bytes());
+        Multipart {
+            reader,
+            // the first boundary is not preceded by a line break, this one
+            // lets it be found like the others
+            buf: b"\r\n".to_vec(),
+            pos: 0,
+            delim,
+            state: State::Content,
+        }
+    }
+
+    /// the next part, what is left of the previous one is skipped
+    pub fn next_part(&mut self) -> io::Result<Option<Part<'_, R>>> {
+        let mut scratch = [0; 512];
+        while self.state == State::Content {
+            self.read_content(&mut scratch)?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+        self.fill_to(2)?;
+        if self.buf[self.pos..].starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        // the rest of the boundary line, then the headers up to an empty line
+        let head_end = loop {
+            let rest = &self.buf[self.pos..];
+            let end = find(rest, b"\r\n\r\n");
+            if end.unwrap_or(rest.len()) > MAX_PART_HEAD {
+                return Err(invalid("multipart part headers too large"));
+            }
+            if let Some(i) = end {
+                break i;
+            }
+            self.fill()?;
+        };
+        let head = &self.buf[self.pos..self.pos + head_end];
+        let head = std::str::from_utf8(head).map_err(|_| invalid("bad multipart part headers"))?;
+        let mut lines = head.split("\r\n");
+        if !lines.next().unwrap_or("").trim().is_empty() {
+            return Err(invalid("bad multipart boundary"));
+        }
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| invalid("bad multipart part headers"))?;
+                Ok((name.trim().to_owned(), value.trim().to_owned()))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.pos += head_end + 4;
+        self.state = State::Content;
+        Ok(Some(Part {
+            multipart: self,
+            headers,
+        }))
+    }
+
+    /// read the content up to the next delimiter
+    fn read_content(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Content {
+            return Ok(0);
+        }
+        loop {
+            let rest = &self.buf[self.pos..];
+            // what can't be the start of a delimiter is content
+            let end = match find(rest, &self.delim) {
+                Some(0) => {
+                    self.pos += self.delim.len();
+                    self.state = State::Delimiter;
+                    return Ok(0);
+                }
+                Some(i) => i,
+                None => rest.len().saturating_sub(self.delim.len() - 1),
+            };
+            if end > 0 {
+                let n = end.min(out.len());
+                out[..n].copy_from_slice(&rest[..n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// read more of the body, the end of it is an error as the last boundary
+    /// was not seen yet
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + READ_LEN, 0);
+        let ret = loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                ret => break ret,
+            }
+        };
+        self.buf.truncate(len + ret.as_ref().map_or(0, |&n| n));
+        match ret? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "multipart body truncated",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn fill_to(&mut self, n: usize) -> io::Result<()> {
+        while self.buf.len() - self.pos < n {
+            self.fill()?;
+        }
+        Ok(())
+    }
+}
+
+/// a part of a multipart body, reading it gives its content
+pub struct Part<'m, R> {
+    multipart: &'m mut Multipart<R>,
+    headers: Vec<(String, String)>,
+}
+
+impl<'m, R: Read> Part<'m, R> {
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// the value of the named header, the name is matched ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// the form field name of `Content-Disposition`
+    pub fn name(&self) -> Option<&str> {
+        self.disposition_param("name")
+    }
+
+    /// the file name of an uploaded file, as sent by the client
+    ///
+    /// it is not safe to use as a path, it can hold separators or `..`
+    pub fn filename(&self) -> Option<&str> {
+        self.disposition_param("filename")
+    }
+
+    pub fn content_type(&self) -> Option<MediaType<'_>> {
+        MediaType::parse(self.header("content-type")?)
+    }
+
+    fn disposition_param(&self, name: &str) -> Option<&str> {
+        let disposition = self.header("content-disposition")?;
+        let (_, params) = disposition.split_once(';')?;
+        headers::params(params)
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+}
+
+impl<'m, R: Read> Read for Part<'m, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.multipart.read_content(buf)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// hands out the body `step` bytes at a time
+    struct Chunks<'a> {
+        body: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Chunks<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.body.len());
+            buf[..n].copy_from_slice(&self.body[..n]);
+            self.body = &self.body[n..];
+            Ok(n)
+        }
+    }
+
+    type Parts = Vec<(Option<String>, Option<String>, Vec<u8>)>;
+
+    fn parse(body: &[u8], step: usize) -> io::Result<Parts> {
+        let mut form = Multipart::new(Chunks { body, step }, "XyZ");
+        let mut parts = Vec::new();
+        while let Some(mut part) = form.next_part()? {
+            let name = part.name().map(str::to_owned);
+            let filename = part.filename().map(str::to_owned);
+            let mut content = Vec::new();
+            part.read_to_end(&mut content)?;
+            parts.push((name, filename, content));
+        }
+        Ok(parts)
+    }
+
+    const BODY: &[u8] = b"preamble, ignored\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"a\"\r\n\
+        \r\n\
+        one\r\n--XyX\r\n\
+        --XyZ \r\n\
+        Content-Disposition: form-data; name=\"f\"; filename=\"../x.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        \r\n\r\n\
+        --XyZ--\r\n\
+        epilogue, ignored";
+
+    #[test]
+    fn parts() {
+        let expected = vec![
+            (Some("a".into()), None, b"one\r\n--XyX".to_vec()),
+            (Some("f".into()), Some("../x.txt".into()), b"\r\n".to_vec()),
+        ];
+        // the boundary split across reads at every offset
+        for step in [1, 2, 3, 5, 7, 64, BODY.len()] {
+            assert_eq!(parse(BODY, step).unwrap(), expected, "step {step}");
+        }
+    }
+
+    #[test]
+    fn no_preamble() {
+        let body = b"--XyZ\r\n\r\nx\r\n--XyZ--";
+        assert_eq!(parse(body, 4).unwrap(), vec![(None, None, b"x".to_vec())]);
+        assert_eq!(parse(b"--XyZ--", 1).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn skipped_parts() {
+        let mut form = Multipart::new(
+            Chunks {
+                body: BODY,
+                step: 3,
+            },
+            "XyZ",
+        );
+        assert_eq!(form.next_part().unwrap().unwrap().name(), Some("a"));
+        let part = form.next_part().unwrap().unwrap();
+        assert_eq!(part.filename(), Some("../x.txt"));
+        assert_eq!(part.content_type().unwrap().essence(), ("text", "plain"));
+        assert!(form.next_part().unwrap().is_none());
+        assert!(form.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let eof = |body: &[u8]| parse(body, 3).unwrap_err().kind();
+        // no close delimiter
+        assert_eq!(eof(b"--XyZ\r\n\r\ncontent"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            eof(b"--XyZ\r\n\r\nx\r\n--XyZ"),
+            io::ErrorKind::UnexpectedEof
+        );
+        // in the part headers, in the preamble, empty
+        assert_eq!(
+            eof(b"--XyZ\r\nContent-Type: a"),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(eof(b"preamble"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(eof(b""), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn bad_part_head() {
+        let invalid = |body: &[u8]| parse(body, 1024).unwrap_err().kind();
+        let mut big = b"--XyZ\r\nX-Big: ".to_vec();
+        big.resize(MAX_PART_HEAD + 64, b'a');
+        big.extend_from_slice(b"\r\n\r\n\r\n--XyZ--");
+        assert_eq!(invalid(&big), io::ErrorKind::InvalidData);
+        let whole = parse(&big, big.len()).unwrap_err().kind();
+        assert_eq!(whole, io::ErrorKind::InvalidData);
+        assert_eq!(
+            invalid(b"--XyZ\r\nno colon\r\n\r\n--XyZ--"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            invalid(b"--XyZjunk\r\n\r\n--XyZ--"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            invalid(b"--XyZ\r\nA: \xff\r\n\r\n--XyZ--"),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! percent-decoded and normalized request paths
+
+use std::fmt;
+use std::ops::Deref;
+
+use crate::query::decode_segment;
+
+/// the path of a request with its `%XX` escapes decoded and its `.`, `..` and
+/// empty segments resolved, it always starts with `/` and has no query
+///
+/// `/a//b/./../c%20d/` is `/a/c d/`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedPath {
+    path: String,
+    traversal: bool,
+}
+
+impl DecodedPath {
+    pub(crate) fn new(raw: &str) -> Self {
+        let raw = raw.split(['?', '#']).next().unwrap_or_default();
+        let mut segments: Vec<String> = Vec::new();
+        let mut traversal = false;
+        // the path ends with a directory
+        let mut trailing = false;
+        for segment in raw.split('/') {
+            let segment = decode_segment(segment);
+            trailing = matches!(segment.as_ref(), "" | "." | "..");
+            match segment.as_ref() {
+                "" | "." => {}
+                ".." => traversal |= segments.pop().is_none(),
+                s => {
+                    // a decoded separator would be one more segment for the file system
+                    traversal |= s.contains(['/', '\\', '\0']);
+                    segments.push(segment.into_owned());
+                }
+            }
+        }
+        let mut path = String::with_capacity(raw.len());
+        for segment in &segments {
+            path.push('/');
+            path.push_str(segment);
+        }
+        if path.is_empty() || trailing {
+            path.push('/');
+        }
+        DecodedPath { path, traversal }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// the raw path tried to step out of the root, with a `..` past the first
+    /// segment or an encoded separator in a segment
+    ///
+    /// the normalized path stays below the root anyway, but such a request is
+    /// better refused than served
+    #[inline]
+    pub fn is_traversal(&self) -> bool {
+        self.traversal
+    }
+}
+
+impl Deref for DecodedPath {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl fmt::Display for DecodedPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(raw: &str) -> (String, bool) {
+        let path = DecodedPath::new(raw);
+        (path.to_string(), path.is_traversal())
+    }
+
+    #[test]
+    fn normalize() {
+        let cases = [
+            ("/", "/"),
+            ("", "/"),
+            ("/a/b", "/a/b"),
+            ("/a//b/./../c%20d/", "/a/c d/"),
+            ("/a/b/..", "/a/"),
+            ("/a/.", "/a/"),
+            ("/a+b", "/a+b"),
+            ("/%C3%A9t%C3%A9", "/été"),
+            ("/a?x=/../..", "/a"),
+            ("/a#/..", "/a"),
+            ("/%zz", "/%zz"),
+        ];
+        for (raw, path) in cases {
+            assert_eq!(decoded(raw), (path.to_string(), false), "{raw}");
+        }
+    }
+
+    #[test]
+    fn traversal() {
+        let cases = [
+            ("/..", "/"),
+            ("/a/../../etc/passwd", "/etc/passwd"),
+            ("/%2e%2e/x", "/x"),
+            ("/a%2F..%2Fb", "/a/../b"),
+            ("/a%5Cb", "/a\\b"),
+            ("/a%00", "/a\0"),
+        ];
+        for (raw, path) in cases {
+            assert_eq!(decoded(raw), (path.to_string(), true), "{raw}");
+        }
+    }
+
+    #[test]
+    fn deref() {
+        let path = DecodedPath::new("/a/b");
+        assert!(path.starts_with("/a"));
+        assert_eq!(path.as_str(), "/a/b");
+        assert_eq!(path, DecodedPath::new("/a/./b"));
+    }
+}
@@ -0,0 +1,89 @@
+//! keep-alive connections to upstream servers
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::upstream::Upstream;
+
+/// the idle connections kept for each upstream by default
+pub(crate) const MAX_IDLE: usize = 8;
+
+/// the idle connections to the upstreams, by address
+pub(crate) struct Pool {
+    idle: Mutex<HashMap<SocketAddr, Vec<Upstream>>>,
+    // the idle connections kept for each address, none when 0
+    max_idle: usize,
+    // a connection older than this is closed instead of reused
+    max_lifetime: Option<Duration>,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool::new(MAX_IDLE, None)
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("max_idle", &self.max_idle)
+            .field("max_lifetime", &self.max_lifetime)
+            .finish()
+    }
+}
+
+impl Pool {
+    pub(crate) fn new(max_idle: usize, max_lifetime: Option<Duration>) -> Self {
+        Pool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            max_lifetime,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn max_idle(&self) -> usize {
+        self.max_idle
+    }
+
+    #[inline]
+    pub(crate) fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+    }
+
+    /// the most recently used idle connection to `addr` that is still open
+    pub(crate) fn checkout(&self, addr: SocketAddr) -> Option<Upstream> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(&addr)?;
+        while let Some(mut conn) = conns.pop() {
+            if !self.expired(&conn) && conn.is_open() {
+                conn.reuse();
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    /// keep the connection for the next request to its upstream, it must be done
+    /// with its response and have nothing left to read
+    pub(crate) fn checkin(&self, conn: Upstream) {
+        if self.max_idle == 0 || self.expired(&conn) || !conn.buffer().is_empty() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(conn.addr()).or_default();
+        if conns.len() >= self.max_idle {
+            // the oldest one goes
+            conns.remove(0);
+        }
+        conns.push(conn);
+    }
+
+    #[inline]
+    fn expired(&self, conn: &Upstream) -> bool {
+        self.max_lifetime.is_some_and(|max| conn.age() >= max)
+    }
+}
@@ -0,0 +1,27 @@
+//! the prefork mode, worker processes sharing the listening sockets
+
+use std::io;
+
+/// fork `workers - 1` processes, the calling one is the first worker
+///
+/// it must run before the runtime has started any thread, a forked process
+/// only keeps the calling one. the other workers get `SIGTERM` when the
+/// first one exits, they are not restarted when they die
+pub(crate) fn fork_workers(workers: usize) -> io::Result<()> {
+    let first = std::process::id() as libc::pid_t;
+    for _ in 1..workers {
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM as u64) };
+                // the first worker was gone before the signal was asked for
+                if unsafe { libc::getppid() } != first {
+                    std::process::exit(0);
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,629 @@
+//! forwarding the requests to an upstream server
+
+use std::io::{self, Read, Write};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::balance::{self, Backend, Balance, Lease};
+use crate::error::HttpError;
+use crate::headers;
+use crate::http_server::HttpService;
+use crate::method::Method;
+use crate::pool::Pool;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+use crate::upstream::{header_of, read_head, Head, ResponseBody, Upstream};
+
+/// the failures in a row that eject an upstream by default
+const MAX_FAILS: u32 = 3;
+/// how long an upstream is ejected by default
+const EJECT_FOR: Duration = Duration::from_secs(10);
+
+/// the headers that only apply to a single connection, RFC 9110 section 7.6.1
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// forward the requests to an upstream server and stream its responses back
+///
+/// the request goes out with the `Host` of the upstream, the client address is
+/// appended to `X-Forwarded-For` and `X-Forwarded-Proto` / `X-Forwarded-Host` tell
+/// the scheme and host the client asked for. the hop-by-hop headers are dropped
+/// both ways. an upstream that can't be reached gets `502 Bad Gateway`, one that
+/// does not answer in time `504 Gateway Timeout`
+///
+/// the connections to the upstream are kept alive and reused by the next requests,
+/// up to `pool_max_idle` idle ones. a request on a reused connection that the
+/// upstream closed in the meantime is sent again on a new one, unless its body
+/// was streamed
+///
+/// more upstreams can be added, the requests are spread over them after the
+/// `Balance` strategy. an upstream failing `max_fails` times in a row, by not
+/// taking the connection or not answering, is left out for `eject_for`.
+/// a request whose upstream can't be reached is tried on another one
+///
+/// ```ignore
+/// let api = ProxyService::new("10.0.0.1:3000")?
+///     .upstream("10.0.0.2:3000")?
+///     .balance(Balance::LeastConnections)
+///     .timeout(Duration::from_secs(30));
+/// let router = Router::new()
+///     .get("/", index)
+///     .get("/api/*", { let api = api.clone(); move |req, rsp| api.forward(req, rsp) })
+///     .post("/api/*", move |req, rsp| api.forward(req, rsp));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProxyService {
+    inner: Arc<Inner>,
+    // shared by the clones
+    pool: Arc<Pool>,
+}
+
+#[derive(Debug, Clone)]
+struct Inner {
+    backends: Vec<Arc<Backend>>,
+    balance: Balance,
+    // the turn of the balancing, shared by the clones
+    next: Arc<AtomicUsize>,
+    max_fails: u32,
+    eject_for: Duration,
+    preserve_host: bool,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+}
+
+impl ProxyService {
+    /// forward to `host:port`, the name is resolved once here
+    pub fn new(upstream: &str) -> io::Result<Self> {
+        Ok(ProxyService {
+            inner: Arc::new(Inner {
+                backends: vec![backend(upstream, 1)?],
+                balance: Balance::default(),
+                next: Arc::new(AtomicUsize::new(0)),
+                max_fails: MAX_FAILS,
+                eject_for: EJECT_FOR,
+                preserve_host: false,
+                connect_timeout: None,
+                timeout: None,
+            }),
+            pool: Arc::new(Pool::default()),
+        })
+    }
+
+    /// add another upstream
+    pub fn upstream(self, upstream: &str) -> io::Result<Self> {
+        self.weighted_upstream(upstream, 1)
+    }
+
+    /// add another upstream with its weight, for `Balance::Weighted` and
+    /// `Balance::LeastConnections`. the first one has a weight of 1
+    pub fn weighted_upstream(mut self, upstream: &str, weight: u32) -> io::Result<Self> {
+        let backend = backend(upstream, weight)?;
+        Arc::make_mut(&mut self.inner).backends.push(backend);
+        Ok(self)
+    }
+
+    /// how the requests are spread over the upstreams, round robin by default
+    pub fn balance(mut self, balance: Balance) -> Self {
+        Arc::make_mut(&mut self.inner).balance = balance;
+        self
+    }
+
+    /// the failures in a row that get an upstream ejected, 3 by default. 0 never ejects
+    pub fn max_fails(mut self, max: u32) -> Self {
+        Arc::make_mut(&mut self.inner).max_fails = max;
+        self
+    }
+
+    /// how long an ejected upstream is left out, 10 seconds by default
+    pub fn eject_for(mut self, duration: Duration) -> Self {
+        Arc::make_mut(&mut self.inner).eject_for = duration;
+        self
+    }
+
+    /// send the `Host` of the client instead of the one of the upstream
+    pub fn preserve_host(mut self, on: bool) -> Self {
+        Arc::make_mut(&mut self.inner).preserve_host = on;
+        self
+    }
+
+    /// give up connecting to the upstream after this long
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.inner).connect_timeout = Some(timeout);
+        self
+    }
+
+    /// give up on an upstream that is silent for this long, while sending the
+    /// request or reading the response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.inner).timeout = Some(timeout);
+        self
+    }
+
+    /// the idle connections kept for each upstream address, 8 by default.
+    /// 0 closes each connection after its response
+    pub fn pool_max_idle(mut self, max: usize) -> Self {
+        self.pool = Arc::new(Pool::new(max, self.pool.max_lifetime()));
+        self
+    }
+
+    /// close the connections that are older than this instead of reusing them
+    pub fn pool_max_lifetime(mut self, max: Duration) -> Self {
+        self.pool = Arc::new(Pool::new(self.pool.max_idle(), Some(max)));
+        self
+    }
+
+    /// answer the request with the response of the upstream
+    pub fn forward(&self, mut req: Request, rsp: &mut Response) -> io::Result<()> {
+        let inner = &self.inner;
+        let (lease, mut upstream) = self
+            .choose()
+            .map_err(|e| gateway_error(e, "upstream unreachable"))?;
+        let backend = lease.backend();
+        let mut ret = self.exchange(&mut req, backend, &mut upstream, rsp);
+        if ret.is_err() && upstream.is_reused() && upstream.is_untouched() && !is_streamed(&req) {
+            // the upstream closed the idle connection as it was being reused
+            ret = match self.connect(backend, false) {
+                Ok(fresh) => {
+                    upstream = fresh;
+                    self.exchange(&mut req, backend, &mut upstream, rsp)
+                }
+                Err(e) => Err(e),
+            };
+        }
+        let head = match ret {
+            Ok(head) => head,
+            Err(e) => {
+                backend.failed(inner.max_fails, inner.eject_for);
+                return Err(gateway_error(e, "upstream failed"));
+            }
+        };
+        backend.succeeded();
+        let head_code = head.code;
+        let has_body = req.method() != Method::Head;
+        let body = ResponseBody::new(upstream, head, has_body, Some(self.pool.clone()));
+        if has_body && !matches!(head_code, 204 | 304) {
+            rsp.body_stream(Body {
+                body,
+                _lease: lease,
+            });
+        }
+        Ok(())
+    }
+
+    /// the upstream for the request and a connection to it,
+    /// the ones that can't be reached are left for the next one
+    fn choose(&self) -> io::Result<(Lease, Upstream)> {
+        let inner = &self.inner;
+        let mut tried = Vec::new();
+        let mut last = None;
+        while let Some(lease) = balance::pick(&inner.backends, inner.balance, &inner.next, &tried) {
+            match self.connect(lease.backend(), true) {
+                Ok(upstream) => return Ok((lease, upstream)),
+                Err(e) => {
+                    lease.backend().failed(inner.max_fails, inner.eject_for);
+                    tried.push(lease.index());
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| io::ErrorKind::NotConnected.into()))
+    }
+
+    /// an idle connection from the pool if `reuse` and there is one, else a new one
+    fn connect(&self, backend: &Backend, reuse: bool) -> io::Result<Upstream> {
+        let inner = &self.inner;
+        if reuse {
+            let idle = backend
+                .addrs()
+                .iter()
+                .find_map(|&addr| self.pool.checkout(addr));
+            if let Some(upstream) = idle {
+                return Ok(upstream);
+            }
+        }
+        let mut last = None;
+        for &addr in backend.addrs() {
+            match Upstream::connect(addr, inner.connect_timeout, inner.timeout) {
+                Ok(upstream) => return Ok(upstream),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| io::ErrorKind::NotConnected.into()))
+    }
+
+    /// send the request and read the head of the response into `rsp`
+    fn exchange(
+        &self,
+        req: &mut Request,
+        backend: &Backend,
+        upstream: &mut Upstream,
+        rsp: &mut Response,
+    ) -> io::Result<Head> {
+        self.send(req, backend, upstream)?;
+        let head = read_head(upstream, |headers| copy_headers(headers, rsp))?;
+        rsp.status_u16(head.code);
+        Ok(head)
+    }
+
+    /// write the request head and copy the body to the upstream
+    fn send(
+        &self,
+        req: &mut Request,
+        backend: &Backend,
+        upstream: &mut Upstream,
+    ) -> io::Result<()> {
+        // a streamed body is longer than the part already read, a de-chunked one
+        // has no `Content-Length` of its own and is chunked again when streamed
+        let buffered = req.body().len() as u64;
+        let len = req.content_length().map_or(buffered, |n| n.max(buffered));
+        let chunked = req.is_streamed() && req.content_length().is_none();
+        let head = self.encode_head(req, backend.authority(), len, chunked)?;
+        upstream.write_all(&head)?;
+
+        if !is_streamed(req) {
+            // written from the buffer, so it can be sent again on a retry
+            upstream.write_all(req.body())?;
+            return upstream.flush();
+        }
+        if chunked {
+            return send_chunked(&mut req.body_reader(), upstream);
+        }
+        let copied = io::copy(&mut req.body_reader().take(len), upstream)?;
+        if copied < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "request body truncated",
+            ));
+        }
+        upstream.flush()
+    }
+
+    /// the head of the request to the upstream at `authority`, with a body of
+    /// `len` bytes or `chunked`
+    fn encode_head(
+        &self,
+        req: &Request,
+        authority: &str,
+        len: u64,
+        chunked: bool,
+    ) -> io::Result<Vec<u8>> {
+        let mut head = Vec::with_capacity(1024);
+        write!(head, "{} {} HTTP/1.1\r\n", req.method(), req.path())?;
+
+        let host = req.host();
+        match host {
+            Some(host) if self.inner.preserve_host => write!(head, "Host: {host}\r\n")?,
+            _ => write!(head, "Host: {authority}\r\n")?,
+        }
+        let mut forwarded_for = None;
+        for h in req.headers() {
+            let value = match std::str::from_utf8(h.value) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            if h.name.eq_ignore_ascii_case("x-forwarded-for") {
+                forwarded_for = Some(match forwarded_for {
+                    Some(list) => format!("{list}, {value}"),
+                    None => value.to_owned(),
+                });
+                continue;
+            }
+            if skip_request_header(req, h.name) {
+                continue;
+            }
+            write!(head, "{}: {value}\r\n", h.name)?;
+        }
+        if let Some(peer) = req.remote_addr() {
+            let client = peer.ip();
+            match forwarded_for {
+                Some(list) => write!(head, "X-Forwarded-For: {list}, {client}\r\n")?,
+                None => write!(head, "X-Forwarded-For: {client}\r\n")?,
+            }
+        }
+        let proto = if req.connection().is_tls() {
+            "https"
+        } else {
+            "http"
+        };
+        write!(head, "X-Forwarded-Proto: {proto}\r\n")?;
+        if let Some(host) = host {
+            write!(head, "X-Forwarded-Host: {host}\r\n")?;
+        }
+
+        if chunked {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        } else if len > 0
+            || !matches!(
+                req.method(),
+                Method::Get | Method::Head | Method::Delete | Method::Options
+            )
+        {
+            write!(head, "Content-Length: {len}\r\n")?;
+        }
+        if self.pool.max_idle() == 0 {
+            head.extend_from_slice(b"Connection: close\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        Ok(head)
+    }
+}
+
+impl HttpService for ProxyService {
+    #[inline]
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        self.forward(req, rsp)
+    }
+}
+
+/// the part of the body is not read yet, the request can't be sent twice
+#[inline]
+fn is_streamed(req: &Request) -> bool {
+    req.is_streamed()
+}
+
+/// copy a body of unknown length to the upstream as chunks
+fn send_chunked(body: &mut impl Read, upstream: &mut Upstream) -> io::Result<()> {
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let n = match body.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write!(upstream, "{n:x}\r\n")?;
+        upstream.write_all(&buf[..n])?;
+        upstream.write_all(b"\r\n")?;
+    }
+    upstream.write_all(b"0\r\n\r\n")?;
+    upstream.flush()
+}
+
+/// copy the end to end headers of the upstream response
+fn copy_headers(headers: &[httparse::Header<'_>], rsp: &mut Response) {
+    let connection = header_of(headers, "connection").unwrap_or_default();
+    for h in headers {
+        // one the response can't carry as is is dropped
+        let value = match std::str::from_utf8(h.value) {
+            Ok(value) if headers::is_field_value(value) => value,
+            _ => continue,
+        };
+        // the framing and these two are set by the server
+        let own = ["content-length", "transfer-encoding", "server", "date"];
+        if own.iter().any(|n| h.name.eq_ignore_ascii_case(n)) || is_hop_by_hop(h.name, connection) {
+            continue;
+        }
+        rsp.append_header(h.name, value);
+    }
+}
+
+/// the upstream response body, the request counts against its upstream until it is sent
+struct Body {
+    body: ResponseBody,
+    _lease: Lease,
+}
+
+impl Read for Body {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+/// resolve an upstream `host:port`
+fn backend(upstream: &str, weight: u32) -> io::Result<Arc<Backend>> {
+    let addrs: Vec<_> = upstream.to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "upstream has no address",
+        ));
+    }
+    Ok(Arc::new(Backend::new(upstream.to_owned(), addrs, weight)))
+}
+
+/// the value of the named request header
+fn header<'r>(req: &'r Request, name: &str) -> Option<&'r str> {
+    header_of(req.headers(), name)
+}
+
+/// the request header is not passed on as it is
+fn skip_request_header(req: &Request, name: &str) -> bool {
+    let own = [
+        "host",
+        "content-length",
+        "transfer-encoding",
+        "expect",
+        "x-forwarded-proto",
+        "x-forwarded-host",
+    ];
+    own.iter().any(|n| name.eq_ignore_ascii_case(n))
+        || is_hop_by_hop(name, header(req, "connection").unwrap_or_default())
+}
+
+/// a header for this connection only, the fixed ones or those listed in `Connection`
+fn is_hop_by_hop(name: &str, connection: &str) -> bool {
+    HOP_BY_HOP.iter().any(|n| name.eq_ignore_ascii_case(n))
+        || connection
+            .split(',')
+            .any(|n| n.trim().eq_ignore_ascii_case(name))
+}
+
+/// the status of an upstream failure, the cause is kept as the source
+fn gateway_error(e: io::Error, msg: &'static str) -> io::Error {
+    if HttpError::of(&e).is_some() {
+        return e;
+    }
+    let status = match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => StatusCode::GatewayTimeout,
+        _ => StatusCode::BadGateway,
+    };
+    HttpError::new(status, msg).with_source(e).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionInfo;
+    use crate::request::with_request;
+    use bytes::BytesMut;
+
+    /// the head sent upstream for the raw request from 10.0.0.9
+    fn head(proxy: &ProxyService, raw: &str, tls: bool) -> String {
+        with_request(raw, |mut req| {
+            req.set_connection(ConnectionInfo {
+                peer: Some(([10, 0, 0, 9], 5000).into()),
+                tls,
+                ..Default::default()
+            });
+            let len = req.content_length().unwrap_or(0);
+            let head = proxy.encode_head(&req, "up.internal:3000", len, false);
+            String::from_utf8(head.unwrap()).unwrap()
+        })
+    }
+
+    #[test]
+    fn request_head() {
+        let proxy = ProxyService::new("127.0.0.1:3000").unwrap();
+        let raw = "GET /a?b HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\
+                   Connection: keep-alive, X-Hop\r\nX-Hop: 1\r\nKeep-Alive: 5\r\nTE: trailers\r\n\
+                   X-Forwarded-For: 1.1.1.1\r\nX-Forwarded-Proto: https\r\n\
+                   X-Forwarded-Host: evil.example\r\nX-Forwarded-For: 2.2.2.2\r\n\r\n";
+        assert_eq!(
+            head(&proxy, raw, false),
+            "GET /a?b HTTP/1.1\r\nHost: up.internal:3000\r\nAccept: */*\r\n\
+             X-Forwarded-For: 1.1.1.1, 2.2.2.2, 10.0.0.9\r\nX-Forwarded-Proto: http\r\n\
+             X-Forwarded-Host: example.com\r\n\r\n"
+        );
+
+        let proxy = proxy.preserve_host(true).pool_max_idle(0);
+        let raw = "POST /form HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\
+                   Expect: 100-continue\r\n\r\nabc";
+        assert_eq!(
+            head(&proxy, raw, true),
+            "POST /form HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.9\r\n\
+             X-Forwarded-Proto: https\r\nX-Forwarded-Host: example.com\r\n\
+             Content-Length: 3\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn request_framing() {
+        let proxy = ProxyService::new("127.0.0.1:3000").unwrap();
+        let framing = |method: &str, len, chunked| {
+            with_request(&format!("{method} / HTTP/1.1\r\nHost: a\r\n\r\n"), |req| {
+                let head = proxy.encode_head(&req, "up", len, chunked).unwrap();
+                let head = String::from_utf8(head).unwrap();
+                head.lines()
+                    .find(|l| l.starts_with("Content-Length") || l.starts_with("Transfer-Encoding"))
+                    .map(str::to_owned)
+            })
+        };
+        assert_eq!(framing("GET", 0, false), None);
+        assert_eq!(framing("DELETE", 0, false), None);
+        assert_eq!(
+            framing("GET", 2, false).as_deref(),
+            Some("Content-Length: 2")
+        );
+        assert_eq!(
+            framing("POST", 0, false).as_deref(),
+            Some("Content-Length: 0")
+        );
+        assert_eq!(
+            framing("PUT", 0, true).as_deref(),
+            Some("Transfer-Encoding: chunked")
+        );
+    }
+
+    #[test]
+    fn response_headers() {
+        let headers = [
+            ("Content-Type", &b"text/plain"[..]),
+            ("Content-Length", b"5"),
+            ("Transfer-Encoding", b"chunked"),
+            ("Server", b"upstream"),
+            ("Date", b"Thu, 01 Jan 1970 00:00:00 GMT"),
+            ("Connection", b"close, X-Private"),
+            ("X-Private", b"1"),
+            ("Keep-Alive", b"timeout=5"),
+            ("Upgrade", b"h2c"),
+            ("X-Bin", b"\xff"),
+            ("X-Bad", b"a\0b"),
+            ("Set-Cookie", b"a=1"),
+            ("Set-Cookie", b"b=2"),
+        ];
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|&(name, value)| httparse::Header { name, value })
+            .collect();
+        let mut body = BytesMut::new();
+        let mut rsp = Response::new(&mut body, b"");
+        copy_headers(&headers, &mut rsp);
+        let mut buf = BytesMut::new();
+        crate::response::encode(rsp, &mut buf);
+        let head = String::from_utf8(buf.to_vec()).unwrap();
+
+        assert!(head.contains("\r\nContent-Type: text/plain\r\n"), "{head}");
+        assert!(
+            head.contains("\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n"),
+            "{head}"
+        );
+        for dropped in [
+            "upstream",
+            "chunked",
+            "close",
+            "X-Private",
+            "timeout",
+            "h2c",
+            "X-Bin",
+            "X-Bad",
+        ] {
+            assert!(!head.contains(dropped), "{dropped} in {head}");
+        }
+    }
+
+    #[test]
+    fn hop_by_hop() {
+        assert!(is_hop_by_hop("Connection", ""));
+        assert!(is_hop_by_hop("proxy-authorization", ""));
+        assert!(is_hop_by_hop("X-Private", "keep-alive, x-private"));
+        assert!(!is_hop_by_hop("X-Private", "keep-alive, x-private-2"));
+        assert!(!is_hop_by_hop("Cookie", ""));
+    }
+
+    #[test]
+    fn gateway_errors() {
+        let status =
+            |e: io::Error| HttpError::of(&gateway_error(e, "upstream failed")).map(|e| e.status());
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(status(timed_out), Some(StatusCode::GatewayTimeout));
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(status(refused), Some(StatusCode::BadGateway));
+        // one with a status of its own is kept
+        let too_large = HttpError::new(StatusCode::PayloadTooLarge, "too large").into();
+        assert_eq!(status(too_large), Some(StatusCode::PayloadTooLarge));
+    }
+
+    #[test]
+    fn upstreams() {
+        let backend = backend("127.0.0.1:3000", 2).unwrap();
+        assert_eq!(backend.authority(), "127.0.0.1:3000");
+        assert_eq!(backend.addrs(), &[([127, 0, 0, 1], 3000).into()]);
+        assert!(ProxyService::new("127.0.0.1").is_err());
+        assert!(ProxyService::new("127.0.0.1:3000")
+            .unwrap()
+            .upstream("[::1]:x")
+            .is_err());
+    }
+}
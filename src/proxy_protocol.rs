@@ -0,0 +1,233 @@
+//! the PROXY protocol header sent by a load balancer ahead of the connection
+
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use may::net::TcpStream;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// `PROXY ` and the rest of the line
+const V1_MAX_LEN: usize = 107;
+
+/// the listeners whose connections start with a PROXY protocol header
+#[derive(Debug, Clone)]
+pub(crate) enum Trusted {
+    All,
+    Listeners(Vec<usize>),
+}
+
+impl Trusted {
+    #[inline]
+    pub(crate) fn trusts(&self, listener: usize) -> bool {
+        match self {
+            Trusted::All => true,
+            Trusted::Listeners(l) => l.contains(&listener),
+        }
+    }
+}
+
+/// read the header, version 1 or 2, and return the address of the client
+///
+/// `None` for a connection of the proxy itself, like a health check.
+/// it has to arrive within `timeout` if there is one
+pub(crate) fn read_source(
+    stream: &mut TcpStream,
+    timeout: Option<Duration>,
+) -> io::Result<Option<SocketAddr>> {
+    stream.set_read_timeout(timeout)?;
+    let ret = read_header(stream);
+    stream.set_read_timeout(None)?;
+    ret
+}
+
+/// only the header is read, what follows is left for the TLS handshake or the request
+fn read_header(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0; 16];
+    stream.read_exact(&mut head[..6])?;
+    if &head[..6] == b"PROXY " {
+        return read_v1(stream);
+    }
+    if head[..6] != V2_SIGNATURE[..6] {
+        return Err(invalid("no PROXY protocol header"));
+    }
+    stream.read_exact(&mut head[6..])?;
+    if head[..12] != V2_SIGNATURE[..] || head[12] >> 4 != 2 {
+        return Err(invalid("bad PROXY protocol v2 header"));
+    }
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut addrs = vec![0; len];
+    stream.read_exact(&mut addrs)?;
+    // LOCAL, the proxy speaking for itself
+    if head[12] & 0xf == 0 {
+        return Ok(None);
+    }
+    let a = &addrs[..];
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match head[13] {
+        // TCP over IPv4, source and destination addresses then ports
+        0x11 if a.len() >= 12 => {
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(&a[8..]))))
+        }
+        // TCP over IPv6
+        0x21 if a.len() >= 36 => {
+            let ip: [u8; 16] = a[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(ip)),
+                port(&a[32..]),
+            )))
+        }
+        // another family, the client is unknown
+        _ => Ok(None),
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`
+fn read_v1(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::with_capacity(64);
+    let mut b = [0];
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 6 >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        stream.read_exact(&mut b)?;
+        line.push(b[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("bad PROXY protocol v1 header"))?;
+    let mut parts = line.split(' ');
+    match parts.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("bad PROXY protocol v1 header")),
+    }
+    let (src, _dst, port) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(src), Some(dst), Some(port)) => (src, dst, port),
+        _ => return Err(invalid("bad PROXY protocol v1 header")),
+    };
+    match (src.parse::<IpAddr>(), port.parse::<u16>()) {
+        (Ok(ip), Ok(port)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(invalid("bad PROXY protocol v1 header")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the source address and what is left of the input after the header
+    fn read(input: &[u8]) -> io::Result<(Option<SocketAddr>, &[u8])> {
+        let mut rest = input;
+        let source = read_header(&mut rest)?;
+        Ok((source, rest))
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        header.extend_from_slice(addrs);
+        header.extend_from_slice(b"GET");
+        header
+    }
+
+    #[test]
+    fn version_1() {
+        let input = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET";
+        assert_eq!(read(input).unwrap(), (addr("192.0.2.1:56324"), &b"GET"[..]));
+        let input = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n";
+        assert_eq!(read(input).unwrap().0, addr("[2001:db8::1]:4000"));
+        let input = b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\nGET";
+        assert_eq!(read(input).unwrap(), (None, &b"GET"[..]));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").unwrap().0, None);
+    }
+
+    #[test]
+    fn bad_version_1() {
+        let bad: &[&[u8]] = &[
+            b"PROXY TCP5 192.0.2.1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1\r\n",
+            b"PROXY TCP4 host 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 2\r\n",
+            b"PROXY TCP4 \xff 198.51.100.1 1 2\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ];
+        for input in bad {
+            let err = read(input).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{input:?}");
+        }
+        // no line end within the limit
+        let long = [b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat();
+        assert_eq!(read(&long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let truncated = b"PROXY TCP4 192.0.2.1";
+        assert_eq!(
+            read(truncated).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn version_2() {
+        let v4 = [192, 0, 2, 1, 198, 51, 100, 1, 0x1f, 0x90, 0x01, 0xbb];
+        let input = v2(1, 0x11, &v4);
+        assert_eq!(read(&input).unwrap(), (addr("192.0.2.1:8080"), &b"GET"[..]));
+
+        let mut v6 = [0; 36];
+        v6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        assert_eq!(
+            read(&v2(1, 0x21, &v6)).unwrap().0,
+            addr("[2001:db8::1]:4000")
+        );
+
+        // TLVs after the addresses are skipped
+        let mut tlv = v4.to_vec();
+        tlv.extend_from_slice(&[0x04, 0, 2, 1, 2]);
+        assert_eq!(
+            read(&v2(1, 0x11, &tlv)).unwrap(),
+            (addr("192.0.2.1:8080"), &b"GET"[..])
+        );
+        // LOCAL, UNIX sockets and short address blocks have no client
+        assert_eq!(read(&v2(0, 0x11, &v4)).unwrap(), (None, &b"GET"[..]));
+        assert_eq!(read(&v2(1, 0x31, &[0; 216])).unwrap(), (None, &b"GET"[..]));
+        assert_eq!(read(&v2(1, 0x11, &v4[..8])).unwrap(), (None, &b"GET"[..]));
+    }
+
+    #[test]
+    fn bad_version_2() {
+        let mut version = v2(1, 0x11, &[0; 12]);
+        version[12] = 0x11;
+        assert_eq!(
+            read(&version).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let mut signature = v2(1, 0x11, &[0; 12]);
+        signature[8] = b'X';
+        assert_eq!(
+            read(&signature).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // the address block is shorter than announced
+        let truncated = &v2(1, 0x11, &[0; 12])[..20];
+        assert_eq!(
+            read(truncated).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn trusted_listeners() {
+        assert!(Trusted::All.trusts(3));
+        let some = Trusted::Listeners(vec![0, 2]);
+        assert!(some.trusts(2) && !some.trusts(1));
+    }
+}
@@ -0,0 +1,109 @@
+//! `application/x-www-form-urlencoded` query strings
+
+use std::borrow::Cow;
+
+/// the `key=value` pairs separated by `&`, decoded
+///
+/// a key without `=` gets an empty value, a repeated key is yielded each time
+pub(crate) fn pairs(query: &str) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+    query.split('&').filter(|p| !p.is_empty()).map(|p| {
+        let (k, v) = p.split_once('=').unwrap_or((p, ""));
+        (decode(k), decode(v))
+    })
+}
+
+/// `+` is a space and `%XX` a byte, a bad escape is kept as is
+/// and bytes that are not UTF-8 are replaced
+pub(crate) fn decode(s: &str) -> Cow<'_, str> {
+    unescape(s, true)
+}
+
+/// like `decode`, but a `+` in a path segment is itself
+pub(crate) fn decode_segment(s: &str) -> Cow<'_, str> {
+    unescape(s, false)
+}
+
+fn unescape(s: &str, plus_is_space: bool) -> Cow<'_, str> {
+    if !(s.contains('%') || plus_is_space && s.contains('+')) {
+        return Cow::Borrowed(s);
+    }
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_is_space => out.push(b' '),
+            b'%' => match (
+                bytes.get(i + 1).and_then(|&b| (b as char).to_digit(16)),
+                bytes.get(i + 2).and_then(|&b| (b as char).to_digit(16)),
+            ) {
+                (Some(hi), Some(lo)) => {
+                    out.push((hi << 4 | lo) as u8);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    match String::from_utf8(out) {
+        Ok(s) => Cow::Owned(s),
+        Err(e) => Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::with_request;
+
+    fn owned(query: &str) -> Vec<(String, String)> {
+        pairs(query).map(|(k, v)| (k.into(), v.into())).collect()
+    }
+
+    #[test]
+    fn query_pairs() {
+        let expected = [("a", "1"), ("b", ""), ("a", "2"), ("c d", "x=y&z")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(owned("a=1&b&&a=2&c+d=x%3Dy%26z&"), expected);
+        assert_eq!(owned(""), vec![]);
+        assert_eq!(owned("=v"), vec![(String::new(), "v".to_string())]);
+    }
+
+    #[test]
+    fn decoding() {
+        assert!(matches!(decode("plain"), Cow::Borrowed("plain")));
+        assert_eq!(decode("a+b%20c"), "a b c");
+        assert_eq!(decode("%C3%A9%e9"), "é\u{fffd}");
+        // bad escapes are kept
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("%%41"), "%A");
+        assert_eq!(decode("%2B"), "+");
+    }
+
+    #[test]
+    fn segments() {
+        assert!(matches!(decode_segment("a+b"), Cow::Borrowed("a+b")));
+        assert_eq!(decode_segment("a+b%20c%2F"), "a+b c/");
+    }
+
+    #[test]
+    fn request_query() {
+        with_request("GET /p?q=a+b&n=1&n=2 HTTP/1.1\r\nHost: a\r\n\r\n", |req| {
+            assert_eq!(req.query(), Some("q=a+b&n=1&n=2"));
+            assert_eq!(req.query_param("q").as_deref(), Some("a b"));
+            assert_eq!(req.query_param("n").as_deref(), Some("1"));
+            assert_eq!(req.query_param("x"), None);
+            assert_eq!(req.query_pairs().count(), 3);
+        });
+        with_request("GET /p HTTP/1.1\r\nHost: a\r\n\r\n", |req| {
+            assert_eq!(req.query(), None);
+            assert_eq!(req.query_pairs().count(), 0);
+        });
+    }
+}
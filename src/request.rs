@@ -1,25 +1,208 @@
 use bytes::BytesMut;
 
+use crate::builder::Config;
+use crate::connection::ConnectionInfo;
+use crate::extensions::Extensions;
+use crate::forwarded::ClientIp;
+use crate::headers::{Authorization, ByteRange, EntityTags, MediaType};
+use crate::http_server::Transport;
+use crate::method::Method;
+use crate::path::DecodedPath;
+use crate::response::CONTINUE;
+#[cfg(feature = "session")]
+use crate::session::Session;
+use crate::trace::TraceContext;
+
+use std::borrow::Cow;
+use std::io::Read;
 use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
 use std::{fmt, io};
 
 pub(crate) const MAX_HEADERS: usize = 16;
 
+/// the default limit of the request head size
+pub(crate) const MAX_HEAD_SIZE: usize = 4096 * 16;
+
+/// the start of the HTTP/2 connection preface, `PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n`
+pub(crate) const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+/// bodies bigger than this are not buffered,
+/// the service pulls them from the socket with `Request::body_reader`
+pub(crate) const MAX_BUFFERED_BODY: usize = 4096 * 16;
+
+/// the limit of a chunk size line, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+
 pub struct Request<'a, 'header> {
-    body: &'a [u8],
+    body: Cow<'a, [u8]>,
     req: httparse::Request<'header, 'a>,
     len: usize,
+    // body bytes still on the socket when the request is handed out
+    body_left: usize,
+    // the rest of a chunked body is on the socket
+    chunked: Option<ChunkedDecoder>,
+    // the client waits for `100 Continue` before sending them
+    expect_continue: bool,
+    // the consumed part of `body` by the body reader
+    body_pos: usize,
+    socket: Option<SocketBody<'header>>,
+    keep_alive: bool,
+    // the authority of an absolute-form target
+    authority: Option<&'a str>,
+    conn: ConnectionInfo,
+    request_id: Option<String>,
+    #[cfg(feature = "session")]
+    session: Option<Session>,
+    extensions: Extensions,
+}
+
+/// the part of a streamed body that is still on the socket
+pub(crate) struct BodyLeft {
+    pub(crate) len: usize,
+    // the state of a chunked body, its length is not known
+    chunked: Option<ChunkedDecoder>,
+    // `100 Continue` is sent with the first read
+    pub(crate) expect_continue: bool,
+}
+
+impl BodyLeft {
+    /// some body bytes are not read yet
+    #[inline]
+    pub(crate) fn pending(&self) -> bool {
+        self.len > 0 || self.chunked.as_ref().is_some_and(|c| !c.is_done())
+    }
+}
+
+/// the rest of a streamed body that is still on the socket
+pub(crate) struct SocketBody<'s> {
+    stream: &'s mut dyn Transport,
+    // shared with the connection loop so it can drain what is left unread
+    left: &'s mut BodyLeft,
+}
+
+impl<'s> SocketBody<'s> {
+    #[inline]
+    pub(crate) fn new(stream: &'s mut dyn Transport, left: &'s mut BodyLeft) -> Self {
+        SocketBody { stream, left }
+    }
+
+    fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "body truncated",
+            ));
+        }
+        Ok(n)
+    }
+
+    /// the data of the current chunk is read straight into `buf`, the lines
+    /// around it a byte at a time so nothing past the body is taken
+    fn read_chunked(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let chunked = match self.left.chunked {
+                Some(ref chunked) if !chunked.is_done() => chunked,
+                _ => return Ok(0),
+            };
+            let data = chunked.data_left();
+            if data > 0 {
+                let max = buf.len().min(usize::try_from(data).unwrap_or(usize::MAX));
+                let n = self.read_some(&mut buf[..max])?;
+                self.left.chunked.as_mut().unwrap().consume_data(n);
+                return Ok(n);
+            }
+            let mut byte = [0];
+            self.read_some(&mut byte)?;
+            let chunked = self.left.chunked.as_mut().unwrap();
+            chunked.feed(&byte, &mut Vec::new())?;
+        }
+    }
+}
+
+impl<'s> Read for SocketBody<'s> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.left.pending() || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left.expect_continue {
+            self.stream.write_all(CONTINUE)?;
+            self.stream.flush()?;
+            self.left.expect_continue = false;
+        }
+        if self.left.chunked.is_some() {
+            return self.read_chunked(buf);
+        }
+        let max = buf.len().min(self.left.len);
+        let n = self.read_some(&mut buf[..max])?;
+        self.left.len -= n;
+        Ok(n)
+    }
+}
+
+/// reader of the request body, see `Request::body_reader`
+pub struct BodyReader<'r, 's> {
+    body: &'r [u8],
+    pos: &'r mut usize,
+    socket: Option<&'r mut SocketBody<'s>>,
+}
+
+impl<'r, 's> Read for BodyReader<'r, 's> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if *self.pos < self.body.len() {
+            let n = (&self.body[*self.pos..]).read(buf)?;
+            *self.pos += n;
+            return Ok(n);
+        }
+        match self.socket {
+            Some(ref mut socket) => socket.read(buf),
+            None => Ok(0),
+        }
+    }
 }
 
 impl<'a, 'header> Request<'a, 'header> {
-    pub fn method(&self) -> &str {
-        self.req.method.unwrap()
+    pub fn method(&self) -> Method<'_> {
+        Method::new(self.req.method.unwrap())
     }
 
+    /// the path and query of the target, an absolute-form target
+    /// `http://host/path` has its authority split off, see `host`
     pub fn path(&self) -> &str {
         self.req.path.unwrap()
     }
 
+    /// the host the request is for, the authority of an absolute-form
+    /// target or else the `Host` header
+    pub fn host(&self) -> Option<&str> {
+        self.authority
+            .or_else(|| self.header_str("host").map(str::trim))
+    }
+
+    /// the path without its query, percent-decoded and normalized,
+    /// see `DecodedPath::is_traversal` before mapping it to files
+    pub fn decoded_path(&self) -> DecodedPath {
+        DecodedPath::new(self.path())
+    }
+
+    /// the raw query string after the `?` of the path
+    pub fn query(&self) -> Option<&str> {
+        self.path().split_once('?').map(|(_, q)| q)
+    }
+
+    /// the decoded `key=value` pairs of the query string, in order,
+    /// a repeated key comes up once for each value
+    pub fn query_pairs(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+        crate::query::pairs(self.query().unwrap_or(""))
+    }
+
+    /// the first value of the key in the query string
+    pub fn query_param(&self, key: &str) -> Option<Cow<'_, str>> {
+        self.query_pairs().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// the minor version, 0 for HTTP/1.0 and 1 for HTTP/1.1
     pub fn version(&self) -> u8 {
         self.req.version.unwrap()
     }
@@ -28,14 +211,209 @@ impl<'a, 'header> Request<'a, 'header> {
         self.req.headers
     }
 
+    /// the address of the client, `None` if the socket couldn't tell
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.peer
+    }
+
+    /// the address of the client behind the proxies trusted by the `ForwardedFor`
+    /// middleware, the one of the connection without it
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match self.extensions.get::<ClientIp>() {
+            Some(ip) => Some(ip.0),
+            None => self.remote_addr().map(|a| a.ip()),
+        }
+    }
+
+    /// the connection the request came in on
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.conn
+    }
+
+    #[inline]
+    pub(crate) fn set_connection(&mut self, conn: ConnectionInfo) {
+        self.conn = conn;
+    }
+
+    /// the value of the named header, the name is matched ignoring case
+    fn header_str(&self, name: &str) -> Option<&'a str> {
+        self.req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    }
+
+    /// the `Content-Length` header, not the size of the body that was read
+    pub fn content_length(&self) -> Option<u64> {
+        self.header_str("content-length")?.trim().parse().ok()
+    }
+
+    pub fn content_type(&self) -> Option<MediaType<'a>> {
+        MediaType::parse(self.header_str("content-type")?)
+    }
+
+    pub fn authorization(&self) -> Option<Authorization<'a>> {
+        Authorization::parse(self.header_str("authorization")?)
+    }
+
+    pub fn if_none_match(&self) -> Option<EntityTags<'a>> {
+        self.header_str("if-none-match").map(EntityTags::new)
+    }
+
+    /// the byte ranges of the `Range` header, `None` if it can't be understood
+    pub fn range(&self) -> Option<Vec<ByteRange>> {
+        ByteRange::parse(self.header_str("range")?)
+    }
+
+    /// the cookies sent by the client as name/value pairs
+    pub fn cookies(&self) -> impl Iterator<Item = (&str, &str)> {
+        crate::cookie::parse(self.req.headers)
+    }
+
+    /// the value of the named cookie
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies().find(|&(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    /// the W3C trace context of the `traceparent` and `tracestate` headers,
+    /// `None` without a valid `traceparent`
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        let values = |name: &'static str| {
+            self.req
+                .headers
+                .iter()
+                .filter(move |h| h.name.eq_ignore_ascii_case(name))
+                .filter_map(|h| std::str::from_utf8(h.value).ok())
+        };
+        let traceparent = values("traceparent").next()?;
+        // the list may be split over several headers
+        let tracestate = values("tracestate").collect::<Vec<_>>().join(",");
+        TraceContext::parse(traceparent, Some(&tracestate))
+    }
+
+    /// the id given to the request by the `RequestId` middleware
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub(crate) fn set_request_id(&mut self, id: String) {
+        self.request_id = Some(id);
+    }
+
+    /// the cookie session, set up by the `Sessions` middleware
+    #[cfg(feature = "session")]
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    #[cfg(feature = "session")]
+    pub(crate) fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    /// the values attached to the request by the middleware
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// the request payload, framed by `Content-Length` or de-chunked
+    ///
+    /// a body bigger than 64KiB is streamed instead of buffered,
+    /// for it this is only the part that has arrived along with the head
     pub fn body(&self) -> &[u8] {
         &self.body
     }
 
+    /// read the whole body, pulling any streamed part from the socket on demand
+    pub fn body_reader(&mut self) -> BodyReader<'_, 'header> {
+        BodyReader {
+            body: &self.body,
+            pos: &mut self.body_pos,
+            socket: self.socket.as_mut(),
+        }
+    }
+
+    /// the request content codings from `Content-Encoding`
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "br", feature = "zstd")),
+        allow(dead_code)
+    )]
+    pub(crate) fn content_encoding(&self) -> Option<&'a [u8]> {
+        self.req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("content-encoding"))
+            .map(|h| h.value)
+    }
+
+    /// replace the body by its decoded form, `Content-Encoding` becomes `identity`
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "br", feature = "zstd")),
+        allow(dead_code)
+    )]
+    pub(crate) fn set_decoded_body(&mut self, body: Vec<u8>) {
+        for h in self.req.headers.iter_mut() {
+            if h.name.eq_ignore_ascii_case("content-encoding") {
+                h.value = b"identity";
+            }
+        }
+        self.body = Cow::Owned(body);
+        self.body_pos = 0;
+        self.socket = None;
+    }
+
     #[inline]
     pub(crate) fn len(&self) -> usize {
         self.len
     }
+
+    /// the client is willing to send more requests over the connection
+    #[inline]
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// the `If-None-Match` of a `GET` or `HEAD` request
+    #[inline]
+    pub(crate) fn not_modified_tags(&self) -> Option<EntityTags<'a>> {
+        if !matches!(self.method(), Method::Get | Method::Head) {
+            return None;
+        }
+        self.if_none_match()
+    }
+
+    /// the body bytes that are still on the socket
+    #[inline]
+    pub(crate) fn body_left(&mut self) -> BodyLeft {
+        let chunked = self.chunked.take();
+        let pending = self.body_left > 0 || chunked.is_some();
+        BodyLeft {
+            len: self.body_left,
+            chunked,
+            expect_continue: self.expect_continue && pending,
+        }
+    }
+
+    /// part of the body is still on the socket, it can only be read once
+    #[inline]
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// hook up the socket for a streamed body, `left` is kept up to date
+    #[inline]
+    pub(crate) fn stream_body(
+        &mut self,
+        stream: &'header mut dyn Transport,
+        left: &'header mut BodyLeft,
+    ) {
+        self.socket = Some(SocketBody { stream, left });
+    }
 }
 
 impl<'a, 'header> fmt::Debug for Request<'a, 'header> {
@@ -44,26 +422,965 @@ impl<'a, 'header> fmt::Debug for Request<'a, 'header> {
     }
 }
 
+/// how far the pending request head was looked at, so a head arriving in
+/// pieces is parsed once it is complete instead of again on every read
+#[derive(Debug, Default)]
+pub(crate) struct HeadScan {
+    // the bytes searched for the end of the head
+    scanned: usize,
+    // the request line was handed to the parser, which checks it on its own
+    line: bool,
+    // the blank line ending the head was seen, the body may still be arriving
+    complete: bool,
+    // the chunked body decoded so far
+    chunked: Option<ChunkedDecoder>,
+    body: Vec<u8>,
+}
+
+impl HeadScan {
+    /// the buffer is worth decoding, it holds a whole head, a first line
+    /// to check or more than a head may take
+    pub(crate) fn ready(&mut self, buf: &[u8], config: &Config) -> bool {
+        if self.complete || buf.len() > config.max_header_size {
+            return true;
+        }
+        // the end of the head may straddle the previous read
+        let mut pos = self.scanned.saturating_sub(2);
+        self.scanned = buf.len();
+        let mut first_line = false;
+        while let Some(n) = buf[pos..].iter().position(|&b| b == b'\n') {
+            let nl = pos + n;
+            if !self.line {
+                self.line = true;
+                first_line = true;
+            }
+            if matches!(buf[nl + 1..], [b'\n', ..] | [b'\r', b'\n', ..]) {
+                self.complete = true;
+                return true;
+            }
+            pos = nl + 1;
+        }
+        first_line
+    }
+
+    /// the head is in the buffer, though the body may not be
+    #[inline]
+    pub(crate) fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// the request was taken off the buffer, the next one starts
+    #[inline]
+    pub(crate) fn reset(&mut self) {
+        *self = HeadScan::default();
+    }
+}
+
+/// decode the request at the start of `buf`, `scan` keeps the progress
+/// of a chunked body between the calls
 pub fn decode<'a, 'header>(
     buf: &'a BytesMut,
-    headers: &'header mut [MaybeUninit<httparse::Header<'a>>; MAX_HEADERS],
+    headers: &'header mut [MaybeUninit<httparse::Header<'a>>],
+    scan: &mut HeadScan,
+    config: &Config,
 ) -> io::Result<Option<Request<'a, 'header>>> {
     let mut req = httparse::Request::new(&mut []);
 
     let status = match req.parse_with_uninit_headers(buf, headers) {
         Ok(s) => s,
+        Err(httparse::Error::TooManyHeaders) => {
+            return Err(Rejection::error(431, "Request Header Fields Too Large"));
+        }
+        // a bare CR in the head, a peer may split the lines differently
+        Err(httparse::Error::NewLine | httparse::Error::HeaderValue) => {
+            return Err(Rejection::error(400, "Bad Request"));
+        }
+        Err(httparse::Error::Version) => {
+            return Err(Rejection::error(505, "HTTP Version Not Supported"));
+        }
+        // an HTTP/0.9 request line has no version
+        Err(_) if is_http09(buf) => {
+            return Err(Rejection::error(505, "HTTP Version Not Supported"));
+        }
         Err(e) => {
             let msg = format!("failed to parse http request: {e:?}");
-            return Err(io::Error::new(io::ErrorKind::Other, msg));
+            return Err(io::Error::other(msg));
         }
     };
 
+    // an unfinished head already bigger than the limit can't be parsed anymore
     let len = match status {
-        httparse::Status::Complete(amt) => amt,
-        httparse::Status::Partial => return Ok(None),
+        httparse::Status::Complete(amt) if amt <= config.max_header_size => amt,
+        httparse::Status::Partial if buf.len() <= config.max_header_size => return Ok(None),
+        // not even the request line is complete
+        httparse::Status::Partial if !buf.contains(&b'\n') => {
+            return Err(Rejection::error(414, "URI Too Long"));
+        }
+        _ => return Err(Rejection::error(431, "Request Header Fields Too Large")),
     };
 
-    let body = &buf[len..];
-    let len = len + body.len();
-    Ok(Some(Request { req, body, len }))
+    let authority = check_target(&mut req)?;
+
+    let mut body_left = 0;
+    let mut chunked = None;
+    let (framing, conflict) = body_framing(req.headers, config.lenient_framing)?;
+    let (body, len) = match framing {
+        Framing::Chunked => {
+            let decoder = scan
+                .chunked
+                .get_or_insert_with(|| ChunkedDecoder::new(config));
+            // only the bytes that arrived since the last call are decoded
+            let start = len + decoder.consumed;
+            decoder.feed(&buf[start..], &mut scan.body)?;
+            let end = len + decoder.consumed;
+            if decoder.is_done() {
+                (Cow::Owned(std::mem::take(&mut scan.body)), end)
+            } else if scan.body.len() > MAX_BUFFERED_BODY {
+                // hand out the request now, the rest is read from the socket
+                chunked = scan.chunked.take();
+                (Cow::Owned(std::mem::take(&mut scan.body)), end)
+            } else {
+                return Ok(None);
+            }
+        }
+        Framing::Length(n) if n > config.max_body_size => {
+            return Err(Rejection::error(413, "Payload Too Large"));
+        }
+        Framing::Length(n) => {
+            let end = len + n;
+            if buf.len() >= end {
+                (Cow::Borrowed(&buf[len..end]), end)
+            } else if n > MAX_BUFFERED_BODY {
+                // hand out the request now, the rest is read from the socket
+                body_left = end - buf.len();
+                (Cow::Borrowed(&buf[len..]), buf.len())
+            } else {
+                return Ok(None);
+            }
+        }
+    };
+    // HTTP/1.0 connections are only persistent on request
+    let keep_alive = !conflict
+        && match req.version {
+            Some(0) => connection_has(req.headers, b"keep-alive"),
+            _ => !connection_has(req.headers, b"close"),
+        };
+    let expect_continue = (body_left > 0 || chunked.is_some()) && expects_continue(&req);
+    Ok(Some(Request {
+        req,
+        body,
+        len,
+        body_left,
+        chunked,
+        body_pos: 0,
+        socket: None,
+        keep_alive,
+        expect_continue,
+        authority,
+        conn: ConnectionInfo::default(),
+        request_id: None,
+        #[cfg(feature = "session")]
+        session: None,
+        extensions: Extensions::new(),
+    }))
+}
+
+/// a request that the server answers on its own with an error status
+#[derive(Debug)]
+pub(crate) struct Rejection {
+    pub(crate) code: usize,
+    pub(crate) msg: &'static str,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request rejected: {} {}", self.code, self.msg)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl Rejection {
+    /// an error that is answered with the given status
+    #[cold]
+    pub(crate) fn error(code: usize, msg: &'static str) -> io::Error {
+        let kind = match code {
+            408 | 504 => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, Rejection { code, msg })
+    }
+
+    /// the rejection carried by `err`, if any
+    #[inline]
+    pub(crate) fn of(err: &io::Error) -> Option<&Rejection> {
+        err.get_ref().and_then(|e| e.downcast_ref::<Rejection>())
+    }
+}
+
+/// the request line is a method and a target only, `GET /index.html`
+fn is_http09(buf: &[u8]) -> bool {
+    let line = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => &buf[..end],
+        None => return false,
+    };
+    let mut parts = line.trim_ascii().split(|&b| b == b' ');
+    matches!(
+        (parts.next(), parts.next(), parts.next()),
+        (Some(method), Some(target), None) if !method.is_empty() && target.starts_with(b"/")
+    )
+}
+
+#[inline]
+/// split the authority off an absolute-form target and check the `Host`
+/// header, there must be exactly one in HTTP/1.1 and it must name the
+/// same authority as the target
+///
+/// the target of `CONNECT` is the authority alone, with a port, and `*`
+/// is only for `OPTIONS`
+fn check_target<'a>(req: &mut httparse::Request<'_, 'a>) -> io::Result<Option<&'a str>> {
+    let bad_request = || Rejection::error(400, "Bad Request");
+    let method = Method::new(req.method.unwrap());
+    let target = req.path.unwrap();
+    let authority = if method == Method::Connect {
+        if !is_authority_form(target) {
+            return Err(bad_request());
+        }
+        Some(target)
+    } else if target == "*" {
+        if method != Method::Options {
+            return Err(bad_request());
+        }
+        None
+    } else {
+        match absolute_target(target) {
+            Some(Some((authority, path))) => {
+                req.path = Some(path);
+                Some(authority)
+            }
+            Some(None) => return Err(bad_request()),
+            None => None,
+        }
+    };
+
+    let mut hosts = req
+        .headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("host"));
+    let host = hosts.next().map(|h| h.value.trim_ascii());
+    if hosts.next().is_some() {
+        return Err(bad_request());
+    }
+    match (host, authority) {
+        (None, _) if req.version == Some(1) => Err(bad_request()),
+        (Some(host), _) if !is_host(host) => Err(bad_request()),
+        (Some(host), Some(authority)) if !host.eq_ignore_ascii_case(authority.as_bytes()) => {
+            Err(bad_request())
+        }
+        _ => Ok(authority),
+    }
+}
+
+/// the authority and the path of an `http` or `https` absolute-form target,
+/// `None` for an origin-form target and `Some(None)` for anything else
+fn absolute_target(target: &str) -> Option<Option<(&str, &str)>> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let Some((scheme, rest)) = target.split_once("://") else {
+        return Some(None);
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return Some(None);
+    }
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    // no user info in an http URI
+    if !is_host(authority.as_bytes()) {
+        return Some(None);
+    }
+    let path = if path.is_empty() { "/" } else { path };
+    Some(Some((authority, path)))
+}
+
+/// the target of `CONNECT`, a host and a port
+#[inline]
+fn is_authority_form(target: &str) -> bool {
+    is_host(target.as_bytes())
+        && target
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.is_empty() && !port.contains(']'))
+}
+
+/// a `Host` value is an authority without user info, a host name or
+/// an IP literal and an optional port
+#[inline]
+fn is_host(value: &[u8]) -> bool {
+    let host = match value.iter().rposition(|&b| b == b':') {
+        // the colons of an IPv6 literal are inside the brackets
+        Some(i) if !value[i..].contains(&b']') => {
+            if !value[i + 1..].iter().all(u8::is_ascii_digit) {
+                return false;
+            }
+            &value[..i]
+        }
+        _ => value,
+    };
+    match host {
+        [b'[', ip @ .., b']'] => {
+            !ip.is_empty()
+                && ip
+                    .iter()
+                    .all(|&b| b.is_ascii_hexdigit() || b":.".contains(&b))
+        }
+        _ => {
+            !host.is_empty()
+                && !host.iter().any(|&b| {
+                    b.is_ascii_whitespace() || b.is_ascii_control() || b"/?#@,\\:[]".contains(&b)
+                })
+        }
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// how the request body is delimited
+#[derive(Debug, PartialEq)]
+enum Framing {
+    Chunked,
+    Length(usize),
+}
+
+/// find out the body framing from `Transfer-Encoding` or `Content-Length`,
+/// without either of them the request has no body
+///
+/// a request that could be framed in two ways is rejected with `400 Bad Request`
+/// as the peers in front may read it another way, see RFC 9112 section 6.3.
+/// `lenient` frames it anyway, `Transfer-Encoding` first then the last
+/// `Content-Length`, and tells the connection must be closed after it.
+/// `chunked` anywhere but last is always rejected
+fn body_framing(headers: &[httparse::Header], lenient: bool) -> io::Result<(Framing, bool)> {
+    let mut chunked = None;
+    let mut length = None;
+    let mut conflict = false;
+    let mut misplaced = false;
+    for h in headers {
+        if h.name.eq_ignore_ascii_case("transfer-encoding") {
+            // the codings of all the fields make one list, only the final one frames
+            let mut codings = h.value.rsplit(|&b| b == b',').map(|c| c.trim_ascii());
+            misplaced |= chunked == Some(true);
+            chunked = Some(
+                codings
+                    .next()
+                    .unwrap_or_default()
+                    .eq_ignore_ascii_case(b"chunked"),
+            );
+            misplaced |= codings.any(|c| c.eq_ignore_ascii_case(b"chunked"));
+        } else if h.name.eq_ignore_ascii_case("content-length") {
+            // `Content-Length: 5, 5` is the same length sent twice
+            for v in h.value.split(|&b| b == b',') {
+                let n = parse_length(v).ok_or_else(|| invalid_data("invalid content length"))?;
+                conflict |= length.is_some_and(|len| len != n);
+                length = Some(n);
+            }
+        }
+    }
+    conflict |= chunked.is_some() && length.is_some();
+    if misplaced || (conflict && !lenient) {
+        return Err(Rejection::error(400, "Bad Request"));
+    }
+    let framing = match (chunked, length) {
+        (Some(true), _) => Framing::Chunked,
+        // any other final coding can't be framed
+        (Some(false), _) => return Err(Rejection::error(501, "Not Implemented")),
+        (None, n) => Framing::Length(n.unwrap_or_default()),
+    };
+    Ok((framing, conflict))
+}
+
+/// the head of the pending request in `buf` is complete and
+/// the client waits for `100 Continue` before sending the body
+pub(crate) fn wants_continue<'a>(
+    buf: &'a [u8],
+    headers: &mut [MaybeUninit<httparse::Header<'a>>],
+) -> bool {
+    let mut req = httparse::Request::new(&mut []);
+    match req.parse_with_uninit_headers(buf, headers) {
+        Ok(httparse::Status::Complete(_)) => expects_continue(&req),
+        _ => false,
+    }
+}
+
+/// `Expect: 100-continue` is only defined for HTTP/1.1
+#[inline]
+fn expects_continue(req: &httparse::Request) -> bool {
+    req.version == Some(1)
+        && req.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("expect")
+                && h.value.trim_ascii().eq_ignore_ascii_case(b"100-continue")
+        })
+}
+
+/// the `Connection` header lists the `option`
+fn connection_has(headers: &[httparse::Header], option: &[u8]) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+        .flat_map(|h| h.value.split(|&b| b == b','))
+        .any(|v| v.trim_ascii().eq_ignore_ascii_case(option))
+}
+
+#[inline]
+fn parse_length(v: &[u8]) -> Option<usize> {
+    let v = v.trim_ascii();
+    if v.is_empty() {
+        return None;
+    }
+    v.iter().try_fold(0usize, |n, &b| {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        n.checked_mul(10)?.checked_add((b - b'0') as usize)
+    })
+}
+
+/// the incremental decoder of a chunked body, it picks up where the last
+/// input ended so a body arriving in pieces is only looked at once
+#[derive(Debug)]
+pub(crate) struct ChunkedDecoder {
+    state: Chunk,
+    // the pending size or trailer line
+    line: Vec<u8>,
+    // the input bytes taken so far
+    consumed: usize,
+    // the size of the chunks seen so far
+    body_len: usize,
+    trailers_len: usize,
+    max_body_size: usize,
+    max_trailers_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Chunk {
+    Size,
+    // the data bytes left of the chunk
+    Data(u64),
+    // the CRLF after the data
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+impl ChunkedDecoder {
+    /// a body growing past `max_body_size` is refused with `413 Payload Too Large`
+    /// as soon as the chunk size is seen, a trailer section bigger than a head
+    /// may be with `431 Request Header Fields Too Large`
+    pub(crate) fn new(config: &Config) -> Self {
+        ChunkedDecoder {
+            state: Chunk::Size,
+            line: Vec::new(),
+            consumed: 0,
+            body_len: 0,
+            trailers_len: 0,
+            max_body_size: config.max_body_size,
+            max_trailers_size: config.max_header_size,
+        }
+    }
+
+    /// the body and its trailers are complete
+    #[inline]
+    pub(crate) fn is_done(&self) -> bool {
+        self.state == Chunk::Done
+    }
+
+    /// the data bytes left of the current chunk
+    #[inline]
+    fn data_left(&self) -> u64 {
+        match self.state {
+            Chunk::Data(left) => left,
+            _ => 0,
+        }
+    }
+
+    /// `n` data bytes were taken by the caller
+    #[inline]
+    fn consume_data(&mut self, n: usize) {
+        let left = self.data_left() - n as u64;
+        self.state = if left == 0 {
+            Chunk::DataEnd
+        } else {
+            Chunk::Data(left)
+        };
+        self.consumed += n;
+    }
+
+    /// decode the chunked input in `buf` and append the data to `body`,
+    /// return the bytes taken which stop at the end of the body
+    pub(crate) fn feed(&mut self, buf: &[u8], body: &mut Vec<u8>) -> io::Result<usize> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.state {
+                Chunk::Done => break,
+                Chunk::Data(left) => {
+                    let n = left.min((buf.len() - pos) as u64) as usize;
+                    body.extend_from_slice(&buf[pos..pos + n]);
+                    self.consume_data(n);
+                    pos += n;
+                }
+                _ => {
+                    let rest = &buf[pos..];
+                    let (n, eol) = match rest.iter().position(|&b| b == b'\n') {
+                        Some(i) => (i + 1, true),
+                        None => (rest.len(), false),
+                    };
+                    self.line.extend_from_slice(&rest[..n]);
+                    self.consumed += n;
+                    pos += n;
+                    self.check_line()?;
+                    if eol {
+                        self.end_line()?;
+                    }
+                }
+            }
+        }
+        Ok(pos)
+    }
+
+    /// refuse a pending line as soon as it can't be valid anymore
+    fn check_line(&mut self) -> io::Result<()> {
+        match self.state {
+            Chunk::Size if self.line.len() > MAX_CHUNK_LINE => {
+                Err(invalid_data("invalid chunk size"))
+            }
+            Chunk::DataEnd if !b"\r\n".starts_with(&self.line) => {
+                Err(invalid_data("invalid chunk data"))
+            }
+            Chunk::Trailers if self.trailers_len + self.line.len() > self.max_trailers_size => {
+                Err(Rejection::error(431, "Request Header Fields Too Large"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn end_line(&mut self) -> io::Result<()> {
+        match self.state {
+            Chunk::Size => {
+                let size = match httparse::parse_chunk_size(&self.line) {
+                    Ok(httparse::Status::Complete((_, size))) => size,
+                    _ => return Err(invalid_data("invalid chunk size")),
+                };
+                if size == 0 {
+                    self.state = Chunk::Trailers;
+                } else if size > (self.max_body_size - self.body_len) as u64 {
+                    return Err(Rejection::error(413, "Payload Too Large"));
+                } else {
+                    self.body_len += size as usize;
+                    self.state = Chunk::Data(size);
+                }
+            }
+            Chunk::DataEnd => self.state = Chunk::Size,
+            // a blank line ends the trailer section
+            _ if matches!(self.line[..], [b'\n'] | [b'\r', b'\n']) => self.state = Chunk::Done,
+            _ => self.trailers_len += self.line.len(),
+        }
+        self.line.clear();
+        Ok(())
+    }
+}
+
+/// decode the request in `buf` and hand it to `f`
+#[cfg(test)]
+pub(crate) fn with_request<R>(buf: &str, f: impl FnOnce(Request) -> R) -> R {
+    let config = Config::default();
+    let buf = BytesMut::from(buf);
+    let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+    let mut scan = HeadScan::default();
+    let req = decode(&buf, &mut headers, &mut scan, &config).unwrap();
+    f(req.expect("incomplete request"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(err: &io::Error) -> Option<usize> {
+        Rejection::of(err).map(|r| r.code)
+    }
+
+    /// feed the input in the pieces given, each from where the decoder stopped
+    fn dechunk(pieces: &[&[u8]], config: &Config) -> io::Result<(Vec<u8>, usize, bool)> {
+        let mut decoder = ChunkedDecoder::new(config);
+        let mut body = Vec::new();
+        let mut buf = Vec::new();
+        for piece in pieces {
+            buf.extend_from_slice(piece);
+            decoder.feed(&buf[decoder.consumed..], &mut body)?;
+        }
+        Ok((body, decoder.consumed, decoder.is_done()))
+    }
+
+    /// decode a whole buffer, the length of the request or the status it is refused with
+    fn decode_len(buf: &[u8], config: &Config) -> Result<Option<usize>, Option<usize>> {
+        let buf = BytesMut::from(buf);
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let mut scan = HeadScan::default();
+        match decode(&buf, &mut headers, &mut scan, config) {
+            Ok(req) => Ok(req.map(|req| req.len())),
+            Err(e) => Err(status(&e)),
+        }
+    }
+
+    const HEAD: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: a\r\n\r\n";
+
+    #[test]
+    fn head_split_at_each_byte() {
+        let config = Config::default();
+        let line_end = HEAD.iter().position(|&b| b == b'\n').unwrap();
+        for i in 1..HEAD.len() {
+            let mut scan = HeadScan::default();
+            // only the first line is worth a look until the head is complete
+            assert_eq!(
+                scan.ready(&HEAD[..i], &config),
+                i > line_end,
+                "split at {i}"
+            );
+            assert!(!scan.is_complete(), "split at {i}");
+            assert!(scan.ready(HEAD, &config), "split at {i}");
+            assert!(scan.is_complete(), "split at {i}");
+        }
+
+        let mut scan = HeadScan::default();
+        let ready: Vec<usize> = (1..=HEAD.len())
+            .filter(|&i| scan.ready(&HEAD[..i], &config))
+            .collect();
+        assert_eq!(ready, [line_end + 1, HEAD.len()]);
+    }
+
+    #[test]
+    fn head_split_in_blank_line() {
+        let config = Config::default();
+        for head in [HEAD, b"GET / HTTP/1.1\nHost: a\n\n"] {
+            let end = head.len();
+            let blank = if head.ends_with(b"\r\n\r\n") { 4 } else { 2 };
+            for cut in end - blank + 1..end {
+                let mut scan = HeadScan::default();
+                scan.ready(&head[..cut], &config);
+                assert!(!scan.is_complete(), "cut at {cut}");
+                assert!(scan.ready(head, &config), "cut at {cut}");
+                assert!(scan.is_complete(), "cut at {cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn head_too_large() {
+        let config = Config {
+            max_header_size: 32,
+            ..Config::default()
+        };
+        let head = format!("GET / HTTP/1.1\r\nX-Long: {}", "a".repeat(32));
+        let mut scan = HeadScan::default();
+        assert!(scan.ready(&head.as_bytes()[..20], &config));
+        assert!(!scan.ready(&head.as_bytes()[..32], &config));
+        // past the limit the head is handed to the decoder to be refused
+        assert!(scan.ready(head.as_bytes(), &config));
+        assert!(!scan.is_complete());
+        assert_eq!(decode_len(head.as_bytes(), &config), Err(Some(431)));
+        // a request line that doesn't fit
+        let line = format!("GET /{} HTTP/1.1", "a".repeat(32));
+        assert_eq!(decode_len(line.as_bytes(), &config), Err(Some(414)));
+    }
+
+    #[test]
+    fn head_reset_between_requests() {
+        let config = Config::default();
+        let mut buf = HEAD.to_vec();
+        buf.extend_from_slice(b"GET /next HTTP/1.1\r\n");
+        let mut scan = HeadScan::default();
+        assert!(scan.ready(&buf, &config));
+        assert!(scan.is_complete());
+        assert_eq!(decode_len(&buf, &config), Ok(Some(HEAD.len())));
+
+        // the first request is taken off the buffer
+        let rest = &buf[HEAD.len()..];
+        scan.reset();
+        assert!(scan.ready(rest, &config));
+        assert!(!scan.is_complete());
+        let mut rest = rest.to_vec();
+        rest.extend_from_slice(b"Host: a\r\n\r\n");
+        assert!(scan.ready(&rest, &config));
+        assert!(scan.is_complete());
+    }
+
+    #[test]
+    fn framing() {
+        use Framing::{Chunked, Length};
+        let te = "transfer-encoding";
+        let cl = "content-length";
+        // the header fields, lenient, the framing or the status of the refusal
+        type Case<'a> = (
+            &'a [(&'a str, &'a str)],
+            bool,
+            Result<(Framing, bool), usize>,
+        );
+        #[rustfmt::skip]
+        let cases: &[Case] = &[
+            (&[], false, Ok((Length(0), false))),
+            (&[(cl, "5")], false, Ok((Length(5), false))),
+            (&[(cl, "5, 5")], false, Ok((Length(5), false))),
+            (&[(cl, "5"), ("Content-Length", "5")], false, Ok((Length(5), false))),
+            (&[(te, "chunked")], false, Ok((Chunked, false))),
+            (&[(te, "gzip, Chunked")], false, Ok((Chunked, false))),
+            (&[(te, "gzip"), (te, "chunked")], false, Ok((Chunked, false))),
+            // CL and TE
+            (&[(cl, "5"), (te, "chunked")], false, Err(400)),
+            (&[(te, "chunked"), (cl, "5")], false, Err(400)),
+            // conflicting CL
+            (&[(cl, "5, 6")], false, Err(400)),
+            (&[(cl, "5"), (cl, "6")], false, Err(400)),
+            (&[(cl, "5x")], false, Err(400)),
+            (&[(cl, "")], false, Err(400)),
+            (&[(cl, "-1")], false, Err(400)),
+            // non-final chunked
+            (&[(te, "chunked, gzip")], false, Err(400)),
+            (&[(te, "chunked"), (te, "gzip")], false, Err(400)),
+            (&[(te, "chunked, chunked")], false, Err(400)),
+            (&[(te, "chunked, gzip")], true, Err(400)),
+            // an unknown final coding
+            (&[(te, "gzip")], false, Err(501)),
+            // lenient
+            (&[(cl, "5"), (te, "chunked")], true, Ok((Chunked, true))),
+            (&[(cl, "5"), (cl, "6")], true, Ok((Length(6), true))),
+            (&[(cl, "5x")], true, Err(400)),
+        ];
+        for (fields, lenient, expected) in cases {
+            let headers: Vec<_> = fields
+                .iter()
+                .map(|&(name, value)| httparse::Header {
+                    name,
+                    value: value.as_bytes(),
+                })
+                .collect();
+            // an error without a status is answered with 400
+            let framing = body_framing(&headers, *lenient).map_err(|e| status(&e).unwrap_or(400));
+            assert_eq!(&framing, expected, "{fields:?} lenient: {lenient}");
+        }
+    }
+
+    /// check a request target, the authority and the path or the status of the refusal
+    fn target<'a>(
+        method: &'a str,
+        target: &'a str,
+        host: Option<&'a str>,
+    ) -> Result<(Option<&'a str>, &'a str), usize> {
+        let mut headers: Vec<_> = host
+            .map(|host| httparse::Header {
+                name: "Host",
+                value: host.as_bytes(),
+            })
+            .into_iter()
+            .collect();
+        let mut req = httparse::Request::new(&mut headers);
+        req.method = Some(method);
+        req.path = Some(target);
+        req.version = Some(1);
+        match check_target(&mut req) {
+            Ok(authority) => Ok((authority, req.path.unwrap())),
+            Err(e) => Err(status(&e).unwrap()),
+        }
+    }
+
+    #[test]
+    fn origin_form() {
+        assert_eq!(target("GET", "/a?b", Some("a")), Ok((None, "/a?b")));
+        assert_eq!(target("GET", "a/b", Some("a")), Err(400));
+        // HTTP/1.1 requires a single Host
+        assert_eq!(target("GET", "/", None), Err(400));
+    }
+
+    #[test]
+    fn absolute_form() {
+        let ok = |t, host| target("GET", t, Some(host));
+        assert_eq!(ok("http://a/b?c", "a"), Ok((Some("a"), "/b?c")));
+        assert_eq!(ok("HTTPS://a:8443", "a:8443"), Ok((Some("a:8443"), "/")));
+        assert_eq!(
+            ok("http://[::1]:80/", "[::1]:80"),
+            Ok((Some("[::1]:80"), "/"))
+        );
+        // the Host must name the same authority
+        assert_eq!(ok("http://a:8080/", "a"), Err(400));
+        assert_eq!(ok("http://a/", "b"), Err(400));
+        assert_eq!(target("GET", "http://a/", None), Err(400));
+        assert_eq!(ok("ftp://a/", "a"), Err(400));
+        assert_eq!(ok("http:///b", "a"), Err(400));
+        assert_eq!(ok("http://u@a/", "a"), Err(400));
+        assert_eq!(ok("http://a:b/", "a:b"), Err(400));
+    }
+
+    #[test]
+    fn authority_form() {
+        let connect = |t| target("CONNECT", t, Some(t));
+        assert_eq!(connect("a:443"), Ok((Some("a:443"), "a:443")));
+        assert_eq!(connect("[::1]:443"), Ok((Some("[::1]:443"), "[::1]:443")));
+        assert_eq!(connect("a"), Err(400));
+        assert_eq!(connect("a:"), Err(400));
+        assert_eq!(connect("[::1]"), Err(400));
+        assert_eq!(connect("u@a:443"), Err(400));
+        assert_eq!(target("CONNECT", "/a", Some("a")), Err(400));
+        assert_eq!(target("CONNECT", "http://a:443/", Some("a:443")), Err(400));
+        // only for CONNECT
+        assert_eq!(target("GET", "a:443", Some("a:443")), Err(400));
+    }
+
+    #[test]
+    fn asterisk_form() {
+        assert_eq!(target("OPTIONS", "*", Some("a")), Ok((None, "*")));
+        assert_eq!(target("GET", "*", Some("a")), Err(400));
+        assert_eq!(target("CONNECT", "*", Some("a")), Err(400));
+    }
+
+    #[test]
+    fn host() {
+        for host in [
+            "a",
+            "a.b",
+            "a:80",
+            "a:",
+            "10.0.0.1:8080",
+            "[::1]",
+            "[::1]:80",
+        ] {
+            assert!(is_host(host.as_bytes()), "{host}");
+        }
+        let bad = [
+            "", ":80", "u@a", "u:p@a", "a b", "a/b", "a?b", "a#b", "a,b", "a\\b", "a\tb", "a:b",
+            "a:80:80", "[::1", "::1]", "[]", "[a:z]", "[::1]x",
+        ];
+        for host in bad {
+            assert!(!is_host(host.as_bytes()), "{host}");
+        }
+        assert_eq!(target("GET", "/", Some("u@a")), Err(400));
+        assert_eq!(target("GET", "/", Some("")), Err(400));
+    }
+
+    const BODY: &[u8] = b"5;name=\"v\"\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\nGET";
+
+    #[test]
+    fn chunked_body() {
+        let config = Config::default();
+        let (body, len, done) = dechunk(&[BODY], &config).unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(len, BODY.len() - 3);
+        assert!(done);
+    }
+
+    #[test]
+    fn chunked_extensions() {
+        let config = Config::default();
+        let input = b"3;a=1;b\r\nabc\r\n0;last\r\n\r\n";
+        let (body, len, done) = dechunk(&[input], &config).unwrap();
+        assert_eq!(body, b"abc");
+        assert_eq!(len, input.len());
+        assert!(done);
+    }
+
+    #[test]
+    fn chunked_partial_at_each_boundary() {
+        let config = Config::default();
+        for i in 0..=BODY.len() {
+            let (body, len, done) = dechunk(&[&BODY[..i], &BODY[i..]], &config).unwrap();
+            assert_eq!(body, b"hello world", "split at {i}");
+            assert_eq!(len, BODY.len() - 3, "split at {i}");
+            assert!(done, "split at {i}");
+        }
+        let bytes: Vec<&[u8]> = BODY.chunks(1).collect();
+        let (body, _, done) = dechunk(&bytes, &config).unwrap();
+        assert_eq!(body, b"hello world");
+        assert!(done);
+    }
+
+    #[test]
+    fn chunked_incomplete() {
+        let config = Config::default();
+        for input in [
+            &b"5\r\nhel"[..],
+            b"5\r\nhello\r\n",
+            b"5\r\nhello\r\n0\r\nX-Sum: 1\r\n",
+        ] {
+            let (_, len, done) = dechunk(&[input], &config).unwrap();
+            assert_eq!(len, input.len());
+            assert!(!done);
+        }
+    }
+
+    #[test]
+    fn chunked_missing_crlf_after_data() {
+        let config = Config::default();
+        for input in [
+            &b"3\r\nabcd\r\n0\r\n\r\n"[..],
+            b"3\r\nabc\n0\r\n\r\n",
+            b"3\r\nabc\rx",
+        ] {
+            let err = dechunk(&[input], &config).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(status(&err), None);
+        }
+    }
+
+    #[test]
+    fn chunked_size_overflow() {
+        let config = Config::default();
+        for input in [
+            &b"10000000000000000\r\n"[..],
+            b"fffffffffffffffff\r\n",
+            b"zz\r\n",
+        ] {
+            let err = dechunk(&[input], &config).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // a size line that never ends
+        let line = vec![b'1'; MAX_CHUNK_LINE + 1];
+        assert!(dechunk(&[b"1;", &line], &config).is_err());
+    }
+
+    #[test]
+    fn chunked_too_large() {
+        let config = Config {
+            max_body_size: 4,
+            ..Config::default()
+        };
+        let (body, _, done) = dechunk(&[b"3\r\nabc\r\n1\r\nd\r\n0\r\n\r\n"], &config).unwrap();
+        assert_eq!(body, b"abcd");
+        assert!(done);
+        // refused on the size line, before the data arrives
+        let err = dechunk(&[b"3\r\nabc\r\n2\r\n"], &config).unwrap_err();
+        assert_eq!(status(&err), Some(413));
+        let err = dechunk(&[b"5\r\n"], &config).unwrap_err();
+        assert_eq!(status(&err), Some(413));
+    }
+
+    #[test]
+    fn chunked_trailers() {
+        let config = Config {
+            max_header_size: 32,
+            ..Config::default()
+        };
+        let input = b"0\r\nX-A: 1\r\nX-B: 2\r\n\r\n";
+        let (body, len, done) = dechunk(&[input], &config).unwrap();
+        assert!(body.is_empty());
+        assert_eq!(len, input.len());
+        assert!(done);
+        // a bare LF ends the section too
+        let (_, _, done) = dechunk(&[b"0\n\n"], &config).unwrap();
+        assert!(done);
+
+        let trailer = format!("0\r\nX-Long: {}\r\n\r\n", "a".repeat(32));
+        let err = dechunk(&[trailer.as_bytes()], &config).unwrap_err();
+        assert_eq!(status(&err), Some(431));
+        // the search is bounded even when no line ever ends
+        let err = dechunk(&[b"0\r\n", &[b'a'; 64]], &config).unwrap_err();
+        assert_eq!(status(&err), Some(431));
+    }
 }
@@ -0,0 +1,85 @@
+//! request ids
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+
+/// the longest incoming id that is reused
+const MAX_ID_LEN: usize = 128;
+
+// the ids of this process start with a prefix taken from the start time and pid
+static PREFIX: Lazy<u64> = Lazy::new(|| {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    nanos ^ (std::process::id() as u64).rotate_left(32)
+});
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// give each request an id, see `Request::request_id`
+///
+/// the id sent by the client (or a proxy in front) in the header is kept when it
+/// is a short printable token, otherwise a new one unique to the process run is
+/// made up. the id is sent back in the same header of the response
+///
+/// ```ignore
+/// let service = Chain::new(App).wrap(RequestId::new());
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequestId {
+    header: String,
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId {
+            header: "X-Request-Id".to_owned(),
+        }
+    }
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the header carrying the id, `X-Request-Id` by default
+    pub fn header(mut self, name: impl Into<String>) -> Self {
+        self.header = name.into();
+        self
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, mut req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        let incoming = req
+            .headers()
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(&self.header))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+            .filter(|id| valid(id));
+        let id = match incoming {
+            Some(id) => id.to_owned(),
+            None => generate(),
+        };
+        rsp.header(format!("{}: {}", self.header, id));
+        req.set_request_id(id);
+        next.run(req, rsp)
+    }
+}
+
+/// a non empty printable token that can't break the response header
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn generate() -> String {
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}-{:08x}", *PREFIX, n)
+}
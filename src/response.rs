@@ -1,57 +1,273 @@
-use bytes::BytesMut;
+use bytes::{BufMut, Bytes, BytesMut};
+use may::sync::mpsc;
+use smallvec::SmallVec;
 
-use crate::request::MAX_HEADERS;
+use crate::cookie::Cookie;
+use crate::error::HttpError;
+use crate::headers::{self, ByteRange, EntityTags};
+use crate::http_server::Transport;
+use crate::request::{Rejection, MAX_HEADERS};
+use crate::sse::SseSender;
+use crate::status::StatusCode;
+use crate::trailers::Trailers;
+use crate::upgrade::{OnUpgrade, Upgraded};
 
-use std::io;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Seek, Write};
+
+// size of the chunks read from a streamed body
+const CHUNK_LEN: usize = 4096 * 4;
+// the buffered response data is written out once it grows past this
+const FLUSH_LEN: usize = 4096 * 8;
+
+/// the interim response to `Expect: 100-continue`
+pub(crate) const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// an empty SETTINGS frame (the server connection preface) followed by
+/// GOAWAY with last stream id 0 and error code HTTP_1_1_REQUIRED
+pub(crate) const H2_GOAWAY: &[u8] = &[
+    0, 0, 0, 0x4, 0, 0, 0, 0, 0, // SETTINGS
+    0, 0, 8, 0x7, 0, 0, 0, 0, 0, // GOAWAY
+    0, 0, 0, 0, // last stream id
+    0, 0, 0, 0xd, // HTTP_1_1_REQUIRED
+];
+
+/// a shared body from this size on is not copied into the response buffer,
+/// below it the copy costs less than the extra write
+const ZERO_COPY_LEN: usize = 16 * 1024;
 
 pub struct Response<'a> {
-    headers: [&'static str; MAX_HEADERS],
-    headers_len: usize,
+    // inline up to the usual count, more spill to the heap
+    headers: SmallVec<[Cow<'static, str>; MAX_HEADERS]>,
     status_message: StatusMessage,
     body: Body,
     rsp_buf: &'a mut BytesMut,
+    // the `Server` header line from the config
+    server: &'a [u8],
+    sse: Option<mpsc::Receiver<Vec<u8>>>,
+    upgrade: Option<OnUpgrade>,
+    close: bool,
+    http10: bool,
+    // answering a `HEAD` request, the head is sent without the body
+    head: bool,
+    length: Length,
+    etag: Option<Cow<'static, str>>,
+    // the encoded informational responses that go before this one
+    interim: Vec<u8>,
+    trailers: Option<Trailers>,
 }
 
 enum Body {
     Str(&'static str),
     Vec(Vec<u8>),
+    // shared, a large one is written from where it is
+    Bytes(Bytes),
+    Stream(Box<dyn Read>),
+    // pushed by a `BodySender`, `None` asks for a flush
+    Channel(mpsc::Receiver<Option<Vec<u8>>>),
+    // the file and the bytes to send from its current position
+    File(File, u64),
     Dummy,
 }
 
+// how the end of the body is told to the client
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Length {
+    // from the body, chunked when it is streamed
+    Auto,
+    // set by the service
+    Fixed(u64),
+    // no length, the connection is closed after the body
+    Close,
+}
+
 struct StatusMessage {
     code: usize,
     msg: &'static str,
 }
 
 impl<'a> Response<'a> {
-    pub(crate) fn new(rsp_buf: &'a mut BytesMut) -> Response {
-        let headers: [&'static str; 16] = [""; 16];
-
+    pub(crate) fn new(rsp_buf: &'a mut BytesMut, server: &'a [u8]) -> Response<'a> {
         Response {
-            headers,
-            headers_len: 0,
+            headers: SmallVec::new(),
             body: Body::Dummy,
             status_message: StatusMessage {
                 code: 200,
                 msg: "Ok",
             },
             rsp_buf,
+            server,
+            sse: None,
+            upgrade: None,
+            close: false,
+            http10: false,
+            head: false,
+            length: Length::Auto,
+            etag: None,
+            interim: Vec::new(),
+            trailers: None,
         }
     }
 
+    /// set the status line from the code and the reason, prefer `status`
+    /// or `status_u16` which can't get them out of sync
     #[inline]
     pub fn status_code(&mut self, code: usize, msg: &'static str) -> &mut Self {
         self.status_message = StatusMessage { code, msg };
         self
     }
 
+    /// set a standard status with its reason phrase
+    #[inline]
+    pub fn status(&mut self, status: StatusCode) -> &mut Self {
+        self.status_code(status.as_u16() as usize, status.reason())
+    }
+
+    /// set any status code, a standard one gets its reason phrase
+    /// and the others an empty one
     #[inline]
-    pub fn header(&mut self, header: &'static str) -> &mut Self {
-        self.headers[self.headers_len] = header;
-        self.headers_len += 1;
+    pub fn status_u16(&mut self, code: u16) -> &mut Self {
+        let reason = StatusCode::from_u16(code).map_or("", StatusCode::reason);
+        self.status_code(code as usize, reason)
+    }
+
+    /// add a full header line like `"Content-Type: text/plain"`,
+    /// a `String` can be passed for a computed one
+    #[inline]
+    pub fn header(&mut self, header: impl Into<Cow<'static, str>>) -> &mut Self {
+        self.headers.push(header.into());
+        self
+    }
+
+    /// send an informational `1xx` response with its headers ahead of this one
+    ///
+    /// several can be sent, in order. they go out with the head of the final
+    /// response, which makes them useful in front of a streamed or file body.
+    /// an HTTP/1.0 client gets none, `101` is sent with `upgrade`
+    ///
+    /// panics on a header name that is not a token or a value with a line break
+    pub fn interim(&mut self, status: StatusCode, headers: &[(&str, &str)]) -> &mut Self {
+        let code = status.as_u16();
+        debug_assert!(
+            (100..200).contains(&code) && code != 101,
+            "not an interim status {code}"
+        );
+        if self.http10 {
+            return self;
+        }
+        self.interim.extend_from_slice(b"HTTP/1.1 ");
+        self.interim
+            .extend_from_slice(itoa::Buffer::new().format(code).as_bytes());
+        self.interim.push(b' ');
+        self.interim.extend_from_slice(status.reason().as_bytes());
+        for (name, value) in headers {
+            assert_header(name, value);
+            self.interim.extend_from_slice(b"\r\n");
+            self.interim.extend_from_slice(name.as_bytes());
+            self.interim.extend_from_slice(b": ");
+            self.interim.extend_from_slice(value.as_bytes());
+        }
+        self.interim.extend_from_slice(b"\r\n\r\n");
+        self
+    }
+
+    /// send a `103 Early Hints` with a `Link` header for each of the links, like
+    /// `</style.css>; rel=preload; as=style`, so the client can start fetching them
+    pub fn early_hints(&mut self, links: &[&str]) -> &mut Self {
+        let headers: Vec<_> = links.iter().map(|link| ("Link", *link)).collect();
+        self.interim(StatusCode::EarlyHints, &headers)
+    }
+
+    /// add the header `name: value`, after any other of the same name
+    ///
+    /// panics on a name that is not a token or a value with a line break
+    /// or another control character, they would split the response
+    pub fn append_header(&mut self, name: &str, value: &str) -> &mut Self {
+        assert_header(name, value);
+        self.header(format!("{name}: {value}"))
+    }
+
+    /// set the header `name: value` in place of the ones of the same name
+    pub fn header_replace(&mut self, name: &str, value: &str) -> &mut Self {
+        self.remove_header(name);
+        self.append_header(name, value)
+    }
+
+    /// drop the headers of this name, the name is matched ignoring case
+    pub fn remove_header(&mut self, name: &str) -> &mut Self {
+        self.headers.retain(|h| !header_named(h, name));
+        self
+    }
+
+    /// redirect to `location` with `302 Found`, the body is emptied
+    ///
+    /// control characters in `location` are percent-encoded, so a CR/LF coming
+    /// from the request can't end the header line
+    pub fn redirect(&mut self, location: &str) -> &mut Self {
+        self.redirect_with(StatusCode::Found, location)
+    }
+
+    /// `301 Moved Permanently`, a `POST` may be followed by a `GET`
+    pub fn redirect_moved(&mut self, location: &str) -> &mut Self {
+        self.redirect_with(StatusCode::MovedPermanently, location)
+    }
+
+    /// `303 See Other`, the client follows it with a `GET`
+    pub fn redirect_see_other(&mut self, location: &str) -> &mut Self {
+        self.redirect_with(StatusCode::SeeOther, location)
+    }
+
+    /// `307 Temporary Redirect`, the client keeps the method and body
+    pub fn redirect_temporary(&mut self, location: &str) -> &mut Self {
+        self.redirect_with(StatusCode::TemporaryRedirect, location)
+    }
+
+    /// `308 Permanent Redirect`, the client keeps the method and body
+    pub fn redirect_permanent(&mut self, location: &str) -> &mut Self {
+        self.redirect_with(StatusCode::PermanentRedirect, location)
+    }
+
+    fn redirect_with(&mut self, status: StatusCode, location: &str) -> &mut Self {
+        self.body = Body::Dummy;
+        self.rsp_buf.clear();
+        let location = escape_controls(location);
+        self.status(status).header_replace("Location", &location)
+    }
+
+    /// set the `ETag` of the response, a quoted tag like `"v1"` or `W/"v1"`
+    ///
+    /// when it matches the `If-None-Match` of a `GET` or `HEAD` request the server
+    /// answers `304 Not Modified` instead, the body set by the handler is dropped
+    pub fn etag(&mut self, tag: impl Into<Cow<'static, str>>) -> &mut Self {
+        let tag = tag.into();
+        self.header(format!("ETag: {tag}"));
+        self.etag = Some(tag);
         self
     }
 
+    /// turn a successful response into `304 Not Modified`
+    /// if its etag is listed in `If-None-Match`
+    pub(crate) fn check_not_modified(&mut self, if_none_match: EntityTags) {
+        let etag = match self.etag {
+            Some(ref etag) => etag,
+            None => return,
+        };
+        let code = self.status_message.code;
+        if !(200..300).contains(&code) || !if_none_match.matches(etag) {
+            return;
+        }
+        self.status(StatusCode::NotModified);
+        self.body = Body::Dummy;
+        self.rsp_buf.clear();
+    }
+
+    /// add a `Set-Cookie` header
+    #[inline]
+    pub fn set_cookie(&mut self, cookie: Cookie) -> &mut Self {
+        self.header(format!("Set-Cookie: {cookie}"))
+    }
+
     #[inline]
     pub fn body(&mut self, s: &'static str) {
         self.body = Body::Str(s);
@@ -62,10 +278,174 @@ impl<'a> Response<'a> {
         self.body = Body::Vec(v);
     }
 
+    /// a body that lives for the whole program, like an embedded page
+    ///
+    /// from 16KiB on it is written to the socket next to the head instead of
+    /// being copied into the response buffer
+    #[inline]
+    pub fn body_static(&mut self, b: &'static [u8]) {
+        self.body = Body::Bytes(Bytes::from_static(b));
+    }
+
+    /// a body shared with other responses, like a cached payload,
+    /// a large one is not copied either, see `body_static`
+    #[inline]
+    pub fn body_bytes(&mut self, b: Bytes) {
+        self.body = Body::Bytes(b);
+    }
+
+    /// stream the body from a reader of unknown length
+    ///
+    /// the response is sent with `Transfer-Encoding: chunked` and the reader
+    /// is drained chunk by chunk, so the whole body is never buffered
+    #[inline]
+    pub fn body_stream<R: Read + 'static>(&mut self, r: R) {
+        self.body = Body::Stream(Box::new(r));
+    }
+
+    /// send this `Content-Length` instead of the chunked transfer coding
+    ///
+    /// for a streamed body of a known size, which is then sent as is. the
+    /// body is cut at that length, and the connection is closed if it ends
+    /// short. a buffered body always sends its own length, except for the
+    /// answer to a `HEAD` request which can tell the length of the body left out
+    #[inline]
+    pub fn content_length(&mut self, len: u64) -> &mut Self {
+        self.length = Length::Fixed(len);
+        self
+    }
+
+    /// send the body without any length and close the connection after it,
+    /// the only framing some legacy clients understand
+    #[inline]
+    pub fn close_delimited(&mut self) -> &mut Self {
+        self.length = Length::Close;
+        self
+    }
+
+    /// stream the body from a writer handed to another coroutine
+    ///
+    /// the head goes out as soon as the service returns, the bytes written
+    /// to the sender follow with the chunked transfer coding and are pushed
+    /// to the socket on every `flush`. this fits progress reports or long
+    /// running generation where the client should not wait for the end.
+    /// the body ends once all the senders are dropped
+    ///
+    /// ```ignore
+    /// let mut body = rsp.body_sender();
+    /// may::go!(move || {
+    ///     for step in report.steps() {
+    ///         writeln!(body, "{}", step.run()).ok();
+    ///         body.flush().ok();
+    ///     }
+    /// });
+    /// ```
+    pub fn body_sender(&mut self) -> BodySender {
+        let (tx, rx) = mpsc::channel();
+        self.body = Body::Channel(rx);
+        BodySender {
+            tx,
+            buf: Vec::new(),
+        }
+    }
+
+    /// declare the trailer fields sent after a streamed body, as the `Trailer` header
+    ///
+    /// the values are set through the returned handle while the body is read,
+    /// typically by the reader given to `body_stream` once it reaches the end
+    /// (a checksum, the time it took, ...). the fields only go out with a chunked
+    /// body, an HTTP/1.0 client or a body of another kind gets none
+    ///
+    /// ```ignore
+    /// let trailers = rsp.trailers(&["X-Checksum"]);
+    /// rsp.body_stream(Checksummed::new(file, trailers));
+    /// ```
+    pub fn trailers(&mut self, names: &[&str]) -> Trailers {
+        self.append_header("Trailer", &names.join(", "));
+        self.trailers.get_or_insert_with(Trailers::default).clone()
+    }
+
+    /// send the file from its current position to the end
+    ///
+    /// the length is known up front so the response has a `Content-Length`,
+    /// on linux the file is handed to the socket with `sendfile` without
+    /// passing through the response buffer
+    pub fn file(&mut self, mut file: File) -> io::Result<()> {
+        let len = file.metadata()?.len();
+        let pos = file.stream_position()?;
+        self.body = Body::File(file, len.saturating_sub(pos));
+        Ok(())
+    }
+
+    /// send `len` bytes of the file starting at `offset`
+    ///
+    /// the bytes are streamed by the connection loop after the head, with
+    /// `sendfile` on linux and through the response buffer elsewhere, so a large
+    /// file is never held in memory. a range past the end of the file is an
+    /// `InvalidInput` error
+    pub fn send_file(&mut self, mut file: File, offset: u64, len: u64) -> io::Result<()> {
+        let size = file.metadata()?.len();
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range past the end of the file",
+            ));
+        }
+        file.seek(io::SeekFrom::Start(offset))?;
+        self.body = Body::File(file, len);
+        Ok(())
+    }
+
+    /// send the part of the file asked for by the `Range` header of the request
+    ///
+    /// a single `bytes=` range is answered with `206 Partial Content` and its
+    /// `Content-Range`, one past the end of the file with `416 Range Not Satisfiable`.
+    /// without a range, or with one that can't be served as a single part,
+    /// the whole file is sent. either way `Accept-Ranges: bytes` is advertised
+    pub fn file_range(&mut self, mut file: File, range: Option<&str>) -> io::Result<()> {
+        let len = file.metadata()?.len();
+        self.header("Accept-Ranges: bytes");
+        let (start, end) = match range.map(|r| parse_range(r, len)) {
+            Some(Ok(Some(range))) => range,
+            Some(Err(Unsatisfiable)) => {
+                self.status(StatusCode::RangeNotSatisfiable)
+                    .header(format!("Content-Range: bytes */{len}"));
+                return Ok(());
+            }
+            _ => {
+                file.rewind()?;
+                self.body = Body::File(file, len);
+                return Ok(());
+            }
+        };
+        file.seek(io::SeekFrom::Start(start))?;
+        self.status(StatusCode::PartialContent)
+            .header(format!("Content-Range: bytes {start}-{end}/{len}"));
+        self.body = Body::File(file, end - start + 1);
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_file(&self) -> bool {
+        matches!(self.body, Body::File(..))
+    }
+
+    /// a body large enough to be written without a copy
+    #[inline]
+    pub(crate) fn is_shared(&self) -> bool {
+        matches!(self.body, Body::Bytes(ref b) if b.len() >= ZERO_COPY_LEN)
+    }
+
+    #[inline]
+    pub(crate) fn is_stream(&self) -> bool {
+        matches!(self.body, Body::Stream(_) | Body::Channel(_))
+    }
+
     #[inline]
     pub fn body_mut(&mut self) -> &mut BytesMut {
         match self.body {
             Body::Dummy => {}
+            Body::Stream(_) | Body::Channel(_) | Body::File(..) => self.body = Body::Dummy,
             Body::Str(s) => {
                 self.rsp_buf.extend_from_slice(s.as_bytes());
                 self.body = Body::Dummy;
@@ -74,16 +454,158 @@ impl<'a> Response<'a> {
                 self.rsp_buf.extend_from_slice(v);
                 self.body = Body::Dummy;
             }
+            Body::Bytes(ref b) => {
+                self.rsp_buf.extend_from_slice(b);
+                self.body = Body::Dummy;
+            }
         }
         self.rsp_buf
     }
 
+    /// switch the response into a `text/event-stream`
+    ///
+    /// the head is sent once the service returns, after that every event pushed
+    /// through the returned sender is flushed to the client right away.
+    /// the connection is closed when all the senders are dropped
+    pub fn sse(&mut self) -> SseSender {
+        let (tx, rx) = mpsc::channel();
+        self.sse = Some(rx);
+        SseSender(tx)
+    }
+
+    #[inline]
+    pub(crate) fn is_sse(&self) -> bool {
+        self.sse.is_some()
+    }
+
+    /// switch the connection to another protocol once the service returns
+    ///
+    /// the response goes out as `101 Switching Protocols` with `Upgrade: <protocol>`,
+    /// then `handler` runs the connection until it returns and the connection is
+    /// closed. the service checks the `Upgrade` request header and adds the headers
+    /// of the handshake the protocol needs (`Sec-WebSocket-Accept`, ...) before this.
+    /// a request body must be read by the service, what follows it is the new protocol
+    ///
+    /// ```ignore
+    /// rsp.append_header("Sec-WebSocket-Accept", &accept_key(&req));
+    /// rsp.upgrade("websocket", |mut io| {
+    ///     let mut frame = [0; 1024];
+    ///     loop {
+    ///         let n = io.read(&mut frame)?;
+    ///         ...
+    ///     }
+    /// });
+    /// ```
+    pub fn upgrade<F>(&mut self, protocol: &str, handler: F) -> &mut Self
+    where
+        F: FnOnce(Upgraded) -> io::Result<()> + Send + 'static,
+    {
+        self.status(StatusCode::SwitchingProtocols)
+            .append_header("Upgrade", protocol)
+            .append_header("Connection", "Upgrade");
+        self.upgrade = Some(Box::new(handler));
+        self
+    }
+
+    #[inline]
+    pub(crate) fn is_upgrade(&self) -> bool {
+        self.upgrade.is_some()
+    }
+
+    /// drop what the service had set for a fresh response
+    pub(crate) fn reset(&mut self) {
+        self.headers.clear();
+        self.status_message = StatusMessage {
+            code: 200,
+            msg: "Ok",
+        };
+        self.body = Body::Dummy;
+        self.rsp_buf.clear();
+        self.sse = None;
+        self.upgrade = None;
+        self.etag = None;
+        self.length = Length::Auto;
+        self.interim.clear();
+        self.trailers = None;
+    }
+
+    /// send `Connection: close` and shut down the connection after this response
+    #[inline]
+    pub(crate) fn set_close(&mut self) {
+        self.close = true;
+    }
+
+    /// the connection is closed after this response,
+    /// a streamed body sent to an HTTP/1.0 client can only be delimited by the close
+    #[inline]
+    pub(crate) fn is_close(&self) -> bool {
+        let body_sent = !self.head && !self.is_bodiless();
+        let unframed = self.http10 && self.is_stream() && self.length == Length::Auto;
+        self.close || self.length == Length::Close || (unframed && body_sent)
+    }
+
+    /// answer an HTTP/1.0 request
+    #[inline]
+    pub(crate) fn set_http10(&mut self) {
+        self.http10 = true;
+    }
+
+    /// answer a `HEAD` request, the headers describe the body that is not sent
+    #[inline]
+    pub(crate) fn set_head(&mut self) {
+        self.head = true;
+    }
+
+    /// a `1xx`, `204` or `304` response never has a body nor a length,
+    /// whatever the service set is dropped
+    #[inline]
+    fn is_bodiless(&self) -> bool {
+        let code = self.status_message.code;
+        code < 200 || code == 204 || code == 304
+    }
+
+    /// the value of a header added by the handler
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "br", feature = "zstd")),
+        allow(dead_code)
+    )]
+    pub(crate) fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| header_named(h, name))
+            .and_then(|h| h.split_once(':'))
+            .map(|(_, v)| v.trim())
+    }
+
+    /// the status code set by the handler
+    #[inline]
+    pub(crate) fn code(&self) -> usize {
+        self.status_message.code
+    }
+
+    /// the body when it is held in memory, not for a stream or a file
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "br", feature = "zstd")),
+        allow(dead_code)
+    )]
+    pub(crate) fn buffered_body(&self) -> Option<&[u8]> {
+        match self.body {
+            Body::Dummy => Some(self.rsp_buf.as_ref()),
+            Body::Str(s) => Some(s.as_bytes()),
+            Body::Vec(ref v) => Some(v),
+            Body::Bytes(ref b) => Some(b),
+            Body::Stream(_) | Body::Channel(_) | Body::File(..) => None,
+        }
+    }
+
     #[inline]
     fn body_len(&self) -> usize {
         match self.body {
             Body::Dummy => self.rsp_buf.len(),
             Body::Str(s) => s.len(),
             Body::Vec(ref v) => v.len(),
+            Body::Bytes(ref b) => b.len(),
+            Body::Stream(_) | Body::Channel(_) | Body::File(..) => 0,
         }
     }
 
@@ -93,6 +615,8 @@ impl<'a> Response<'a> {
             Body::Dummy => self.rsp_buf.as_ref(),
             Body::Str(s) => s.as_bytes(),
             Body::Vec(ref v) => v,
+            Body::Bytes(ref b) => b,
+            Body::Stream(_) | Body::Channel(_) | Body::File(..) => &[],
         }
     }
 }
@@ -103,46 +627,460 @@ impl<'a> Drop for Response<'a> {
     }
 }
 
-pub fn encode(mut rsp: Response, buf: &mut BytesMut) {
-    if rsp.status_message.code == 200 {
-        buf.extend_from_slice(b"HTTP/1.1 200 Ok\r\nServer: M\r\nDate: ");
+/// the header line is `name: ...`
+#[inline]
+fn header_named(line: &str, name: &str) -> bool {
+    line.split_once(':')
+        .is_some_and(|(n, _)| n.trim_end().eq_ignore_ascii_case(name))
+}
+
+/// a header set from its name and value can't split the response
+#[inline]
+fn assert_header(name: &str, value: &str) {
+    assert!(headers::is_token(name), "invalid header name {name:?}");
+    assert!(
+        headers::is_field_value(value),
+        "invalid value of header {name}: {value:?}"
+    );
+}
+
+/// percent-encode the control characters and spaces of a URI
+fn escape_controls(uri: &str) -> Cow<'_, str> {
+    let escape = |b: u8| b <= b' ' || b == 0x7f;
+    if !uri.bytes().any(escape) {
+        return Cow::Borrowed(uri);
+    }
+    let mut escaped = String::with_capacity(uri.len() + 8);
+    for c in uri.chars() {
+        match u8::try_from(c) {
+            Ok(b) if escape(b) => escaped.push_str(&format!("%{b:02X}")),
+            _ => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// no byte of the range is in the body
+struct Unsatisfiable;
+
+/// the first and last byte of a single `bytes=` range over a body of `len` bytes
+///
+/// `None` for a header that is not understood or asks for several ranges,
+/// these are ignored and the full body is sent
+fn parse_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, Unsatisfiable> {
+    match ByteRange::parse(range).as_deref() {
+        Some(&[range]) => range.resolve(len).map(Some).ok_or(Unsatisfiable),
+        _ => Ok(None),
+    }
+}
+
+#[inline]
+fn encode_status(rsp: &Response, buf: &mut BytesMut) {
+    buf.extend_from_slice(&rsp.interim);
+    if rsp.status_message.code == 200 && !rsp.http10 {
+        buf.extend_from_slice(b"HTTP/1.1 200 Ok");
     } else {
-        buf.extend_from_slice(b"HTTP/1.1 ");
+        buf.extend_from_slice(version(rsp.http10));
         let mut code = itoa::Buffer::new();
         buf.extend_from_slice(code.format(rsp.status_message.code).as_bytes());
         buf.extend_from_slice(b" ");
         buf.extend_from_slice(rsp.status_message.msg.as_bytes());
-        buf.extend_from_slice(b"\r\nServer: M\r\nDate: ");
     }
+    buf.extend_from_slice(rsp.server);
+    buf.extend_from_slice(b"\r\nDate: ");
     crate::date::append_date(buf);
+}
+
+#[inline]
+fn version(http10: bool) -> &'static [u8] {
+    if http10 {
+        b"HTTP/1.0 "
+    } else {
+        b"HTTP/1.1 "
+    }
+}
+
+/// the `Connection` header when the default of the protocol version doesn't apply
+#[inline]
+fn encode_connection(close: bool, http10: bool, buf: &mut BytesMut) {
+    if close {
+        buf.extend_from_slice(b"\r\nConnection: close");
+    } else if http10 {
+        buf.extend_from_slice(b"\r\nConnection: keep-alive");
+    }
+}
+
+#[inline]
+fn encode_headers(rsp: &Response, buf: &mut BytesMut) {
+    for h in &rsp.headers {
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(h.as_bytes());
+    }
+    encode_connection(rsp.is_close(), rsp.http10, buf);
+}
+
+/// return the body length
+pub fn encode(mut rsp: Response, buf: &mut BytesMut) -> u64 {
+    encode_status(&rsp, buf);
+    // a 304 has no body, its length would be the one of the full response
+    let bodiless = rsp.is_bodiless();
+    if !bodiless {
+        encode_length(&rsp, rsp.body_len() as u64, buf);
+    }
+    encode_headers(&rsp, buf);
+
+    buf.extend_from_slice(b"\r\n\r\n");
+    // the length of a `HEAD` response is the one a `GET` would get
+    if bodiless || rsp.head {
+        return 0;
+    }
+    let body = rsp.get_body();
+    buf.extend_from_slice(body);
+    body.len() as u64
+}
+
+/// the `Content-Length` of a body of `len` bytes, a `HEAD` response
+/// has the one set by the service if any
+fn encode_length(rsp: &Response, len: u64, buf: &mut BytesMut) {
+    let len = match rsp.length {
+        Length::Close => return,
+        Length::Fixed(n) if rsp.head => n,
+        _ => len,
+    };
     buf.extend_from_slice(b"\r\nContent-Length: ");
     let mut length = itoa::Buffer::new();
-    buf.extend_from_slice(length.format(rsp.body_len()).as_bytes());
+    buf.extend_from_slice(length.format(len).as_bytes());
+}
+
+/// encode a response with a streamed body using the chunked transfer coding,
+/// an HTTP/1.0 client gets the raw body delimited by the connection close,
+/// unless the service set the length or asked for the close itself
+///
+/// the buffered data is written to `out` whenever it grows too big, the tail
+/// is left in `buf` for the connection loop to send. return the body length
+pub(crate) fn encode_stream(
+    mut rsp: Response,
+    buf: &mut BytesMut,
+    out: &mut impl Write,
+) -> io::Result<u64> {
+    let body = std::mem::replace(&mut rsp.body, Body::Dummy);
+    if rsp.is_bodiless() {
+        return Ok(encode(rsp, buf));
+    }
+    let (chunked, limit) = match rsp.length {
+        Length::Auto => (!rsp.http10, None),
+        Length::Fixed(n) => (false, Some(n)),
+        Length::Close => (false, None),
+    };
 
-    // SAFETY: we already have bound check when insert headers
-    let headers = unsafe { rsp.headers.get_unchecked(..rsp.headers_len) };
-    for h in headers {
+    encode_status(&rsp, buf);
+    if chunked {
+        buf.extend_from_slice(b"\r\nTransfer-Encoding: chunked");
+    } else if let Some(n) = limit {
+        encode_length(&rsp, n, buf);
+    }
+    encode_headers(&rsp, buf);
+    buf.extend_from_slice(b"\r\n\r\n");
+    if rsp.head {
+        // dropping the reader or the channel ends the body at the source
+        return Ok(0);
+    }
+
+    let total = match body {
+        Body::Stream(reader) => stream_reader(reader, chunked, limit, buf, out)?,
+        Body::Channel(rx) => stream_channel(rx, chunked, limit, buf, out)?,
+        _ => unreachable!("not a streamed response"),
+    };
+    if chunked {
+        buf.extend_from_slice(b"0\r\n");
+        if let Some(ref trailers) = rsp.trailers {
+            trailers.encode(buf);
+        }
         buf.extend_from_slice(b"\r\n");
-        buf.extend_from_slice(h.as_bytes());
     }
+    Ok(total)
+}
+
+/// a body sent with a `Content-Length` that ended before it
+fn short_body(limit: u64, total: u64) -> io::Error {
+    let msg = format!("body ended after {total} of {limit} bytes");
+    io::Error::new(io::ErrorKind::UnexpectedEof, msg)
+}
+
+fn stream_reader(
+    mut reader: Box<dyn Read>,
+    chunked: bool,
+    limit: Option<u64>,
+    buf: &mut BytesMut,
+    out: &mut impl Write,
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let want = match limit {
+            Some(limit) if limit == total => break,
+            Some(limit) => CHUNK_LEN.min((limit - total) as usize),
+            None => CHUNK_LEN,
+        };
+        // reserve a fixed width chunk size line and patch it after the read
+        let head = buf.len();
+        if chunked {
+            buf.extend_from_slice(b"00000000\r\n");
+        }
+        buf.reserve(CHUNK_LEN + 2);
+        let read_buf: &mut [u8] = unsafe { std::mem::transmute(&mut *buf.chunk_mut()) };
+        let n = match reader.read(&mut read_buf[..want]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                buf.truncate(head);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            buf.truncate(head);
+            if let Some(limit) = limit {
+                return Err(short_body(limit, total));
+            }
+            break;
+        }
+        unsafe { buf.advance_mut(n) };
+        total += n as u64;
+        if chunked {
+            write_chunk_size(&mut buf[head..head + 8], n);
+            buf.extend_from_slice(b"\r\n");
+        }
+
+        if buf.len() >= FLUSH_LEN {
+            out.write_all(buf)?;
+            buf.clear();
+        }
+    }
+    Ok(total)
+}
+
+// the head and the pending responses go out right away, the client
+// should not wait for the first flush to see the status
+fn stream_channel(
+    rx: mpsc::Receiver<Option<Vec<u8>>>,
+    chunked: bool,
+    limit: Option<u64>,
+    buf: &mut BytesMut,
+    out: &mut impl Write,
+) -> io::Result<u64> {
+    out.write_all(buf)?;
+    buf.clear();
+    out.flush()?;
+
+    let mut total = 0;
+    while limit != Some(total) {
+        let Ok(data) = rx.recv() else { break };
+        let mut data = match data {
+            Some(data) => data,
+            None => {
+                out.write_all(buf)?;
+                buf.clear();
+                out.flush()?;
+                continue;
+            }
+        };
+        if data.is_empty() {
+            continue;
+        }
+        if let Some(limit) = limit {
+            data.truncate(data.len().min((limit - total) as usize));
+        }
+        total += data.len() as u64;
+        if chunked {
+            let head = buf.len();
+            buf.extend_from_slice(b"00000000\r\n");
+            write_chunk_size(&mut buf[head..head + 8], data.len());
+        }
+        buf.extend_from_slice(&data);
+        if chunked {
+            buf.extend_from_slice(b"\r\n");
+        }
 
+        if buf.len() >= FLUSH_LEN {
+            out.write_all(buf)?;
+            buf.clear();
+        }
+    }
+    match limit {
+        Some(limit) if total < limit => Err(short_body(limit, total)),
+        _ => Ok(total),
+    }
+}
+
+/// encode the head of a file response and send it out with the pending responses,
+/// then have the transport send the file content. return the body length
+pub(crate) fn encode_file<S: Transport>(
+    mut rsp: Response,
+    buf: &mut BytesMut,
+    out: &mut S,
+) -> io::Result<u64> {
+    let (mut file, len) = match std::mem::replace(&mut rsp.body, Body::Dummy) {
+        Body::File(file, len) => (file, len),
+        _ => unreachable!("not a file response"),
+    };
+    if rsp.is_bodiless() {
+        return Ok(encode(rsp, buf));
+    }
+
+    encode_status(&rsp, buf);
+    encode_length(&rsp, len, buf);
+    encode_headers(&rsp, buf);
     buf.extend_from_slice(b"\r\n\r\n");
-    buf.extend_from_slice(rsp.get_body());
+    if rsp.head {
+        return Ok(0);
+    }
+
+    out.write_all(buf)?;
+    buf.clear();
+    out.send_file(&mut file, len)?;
+    Ok(len)
 }
 
-pub fn encode_error(e: io::Error, buf: &mut BytesMut) {
+/// encode a response with a large shared body, the head and the pending responses
+/// in `buf` are written out together with the body. return the body length
+pub(crate) fn encode_shared(
+    mut rsp: Response,
+    buf: &mut BytesMut,
+    out: &mut impl Write,
+) -> io::Result<u64> {
+    // nothing to write without a copy
+    if rsp.is_bodiless() || rsp.head {
+        return Ok(encode(rsp, buf));
+    }
+    let body = match std::mem::replace(&mut rsp.body, Body::Dummy) {
+        Body::Bytes(b) => b,
+        _ => unreachable!("not a shared body response"),
+    };
+
+    encode_status(&rsp, buf);
+    encode_length(&rsp, body.len() as u64, buf);
+    encode_headers(&rsp, buf);
+    buf.extend_from_slice(b"\r\n\r\n");
+
+    write_all_vectored(out, buf, &body)?;
+    buf.clear();
+    Ok(body.len() as u64)
+}
+
+/// write the head then the body, in as few calls as the writer allows
+fn write_all_vectored(out: &mut impl Write, mut head: &[u8], mut body: &[u8]) -> io::Result<()> {
+    while !head.is_empty() {
+        let n = match out.write_vectored(&[IoSlice::new(head), IoSlice::new(body)]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n < head.len() {
+            head = &head[n..];
+        } else {
+            body = &body[n - head.len()..];
+            head = &[];
+        }
+    }
+    out.write_all(body)
+}
+
+/// write `n` as zero padded hex digits, leading zeros are allowed in a chunk size
+#[inline]
+fn write_chunk_size(dst: &mut [u8], mut n: usize) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    for d in dst.iter_mut().rev() {
+        *d = HEX[n & 0xf];
+        n >>= 4;
+    }
+}
+
+/// encode the head of an event stream response, the body is close delimited
+/// return the receiving end of the events
+pub(crate) fn encode_sse_head(mut rsp: Response, buf: &mut BytesMut) -> mpsc::Receiver<Vec<u8>> {
+    encode_status(&rsp, buf);
+    buf.extend_from_slice(b"\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache");
+    encode_headers(&rsp, buf);
+    buf.extend_from_slice(b"\r\n\r\n");
+    rsp.sse.take().expect("not an event stream response")
+}
+
+/// encode the `101 Switching Protocols` head, it has no body.
+/// return what runs the connection after it
+pub(crate) fn encode_upgrade_head(mut rsp: Response, buf: &mut BytesMut) -> OnUpgrade {
+    encode_status(&rsp, buf);
+    // `Connection: Upgrade` is among the headers
+    for h in &rsp.headers {
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(h.as_bytes());
+    }
+    buf.extend_from_slice(b"\r\n\r\n");
+    rsp.upgrade.take().expect("not an upgrade response")
+}
+
+/// return the body length
+pub fn encode_error(e: io::Error, rsp: &Response, buf: &mut BytesMut) -> u64 {
     error!("error in service: err = {:?}", e);
-    let msg_string = e.to_string();
-    let msg = msg_string.as_bytes();
+    let msg_string;
+    let (code, status, msg) = match (Rejection::of(&e), HttpError::of(&e)) {
+        (Some(r), _) => (r.code, r.msg, r.msg.as_bytes()),
+        (_, Some(e)) => {
+            let status = e.status();
+            (
+                status.as_u16() as usize,
+                status.reason(),
+                e.message().as_bytes(),
+            )
+        }
+        _ => {
+            msg_string = e.to_string();
+            (500, "Internal Server Error", msg_string.as_bytes())
+        }
+    };
 
-    buf.extend_from_slice(b"HTTP/1.1 500 Internal Server Error\r\nServer: M\r\nDate: ");
+    buf.extend_from_slice(version(rsp.http10));
+    let mut itoa = itoa::Buffer::new();
+    buf.extend_from_slice(itoa.format(code).as_bytes());
+    buf.extend_from_slice(b" ");
+    buf.extend_from_slice(status.as_bytes());
+    buf.extend_from_slice(rsp.server);
+    buf.extend_from_slice(b"\r\nDate: ");
     crate::date::append_date(buf);
+    encode_connection(rsp.close, rsp.http10, buf);
     buf.extend_from_slice(b"\r\nContent-Length: ");
-    let mut length = itoa::Buffer::new();
-    buf.extend_from_slice(length.format(msg.len()).as_bytes());
+    buf.extend_from_slice(itoa.format(msg.len()).as_bytes());
 
     buf.extend_from_slice(b"\r\n\r\n");
+    if rsp.head {
+        return 0;
+    }
     buf.extend_from_slice(msg);
+    msg.len() as u64
+}
+
+/// the status an error returned by the service is answered with
+pub(crate) fn error_status(e: &io::Error) -> usize {
+    match (Rejection::of(e), HttpError::of(e)) {
+        (Some(r), _) => r.code,
+        (_, Some(e)) => e.status().as_u16() as usize,
+        _ => 500,
+    }
+}
+
+/// encode the error status for a request refused before reaching the service
+pub(crate) fn encode_rejection(code: usize, msg: &'static str, server: &[u8], buf: &mut BytesMut) {
+    buf.extend_from_slice(b"HTTP/1.1 ");
+    let mut itoa = itoa::Buffer::new();
+    buf.extend_from_slice(itoa.format(code).as_bytes());
+    buf.extend_from_slice(b" ");
+    buf.extend_from_slice(msg.as_bytes());
+    buf.extend_from_slice(server);
+    buf.extend_from_slice(b"\r\nDate: ");
+    crate::date::append_date(buf);
+    buf.extend_from_slice(b"\r\nConnection: close\r\nContent-Length: ");
+    buf.extend_from_slice(itoa.format(msg.len()).as_bytes());
+    buf.extend_from_slice(b"\r\n\r\n");
+    buf.extend_from_slice(msg.as_bytes());
 }
 
 // impl io::Write for the response body
@@ -159,3 +1097,136 @@ impl<'a> io::Write for BodyWriter<'a> {
         Ok(())
     }
 }
+
+/// the writing half of a body streamed with `Response::body_sender`
+///
+/// the writes are buffered until `flush`, which pushes them through the
+/// connection to the client. a clone starts with an empty buffer, the
+/// pending bytes are sent when a sender is dropped
+pub struct BodySender {
+    tx: mpsc::Sender<Option<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl BodySender {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.buf);
+        self.tx.send(Some(data)).map_err(|_| closed())
+    }
+}
+
+#[inline]
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "response body closed")
+}
+
+impl io::Write for BodySender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_LEN {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()?;
+        self.tx.send(None).map_err(|_| closed())
+    }
+}
+
+impl Clone for BodySender {
+    fn clone(&self) -> Self {
+        BodySender {
+            tx: self.tx.clone(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Drop for BodySender {
+    fn drop(&mut self) {
+        self.send().ok();
+    }
+}
+
+impl std::fmt::Debug for BodySender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodySender")
+            .field("buffered", &self.buf.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_response(f: impl FnOnce(&mut Response)) -> Vec<u8> {
+        let mut body = BytesMut::new();
+        let mut rsp = Response::new(&mut body, b"");
+        f(&mut rsp);
+        let mut buf = BytesMut::new();
+        encode(rsp, &mut buf);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn append_header() {
+        let head = with_response(|rsp| {
+            rsp.append_header("X-A", "1\t2 \u{e9}")
+                .append_header("X-A", "");
+        });
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.contains("\r\nX-A: 1\t2 \u{e9}\r\nX-A: \r\n"), "{head}");
+    }
+
+    #[test]
+    fn redirect() {
+        let head = with_response(|rsp| {
+            rsp.body("moved");
+            rsp.redirect("/a b?c=\r\nSet-Cookie:%20x\0\u{e9}");
+        });
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 302 Found\r\n"), "{head}");
+        let location = "\r\nLocation: /a%20b?c=%0D%0ASet-Cookie:%20x%00\u{e9}\r\n";
+        assert!(head.contains(location), "{head}");
+        assert!(head.contains("Content-Length: 0\r\n"), "{head}");
+        assert!(!head.contains("moved"), "{head}");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value of header")]
+    fn append_header_line_break() {
+        with_response(|rsp| {
+            rsp.append_header("X-A", "1\r\nSet-Cookie: a=b");
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid header name")]
+    fn append_header_bad_name() {
+        with_response(|rsp| {
+            rsp.append_header("X-A: b\r\nX-B", "1");
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid header name")]
+    fn interim_bad_name() {
+        with_response(|rsp| {
+            rsp.interim(StatusCode::EarlyHints, &[("", "x")]);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value of header")]
+    fn interim_nul() {
+        with_response(|rsp| {
+            rsp.interim(StatusCode::EarlyHints, &[("Link", "<a>\0")]);
+        });
+    }
+}
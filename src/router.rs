@@ -0,0 +1,223 @@
+//! request routing by path and method
+
+use std::io;
+use std::sync::Arc;
+
+use crate::http_server::HttpService;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// a request handler registered on the router
+pub type Handler = Arc<dyn Fn(Request, &mut Response) -> io::Result<()> + Send + Sync>;
+
+/// dispatch requests to handlers by path and method
+///
+/// a path is matched exactly, one ending with `/*` matches everything under it.
+/// the routes are tried in the order they are added.
+/// when the path matches but no handler is there for the method the request is
+/// answered with `405 Method Not Allowed` listing the methods in `Allow`,
+/// an unknown path gets `404 Not Found`. `HEAD` falls back to the `GET` handler,
+/// the server leaves out the body
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Arc<Vec<Route>>,
+}
+
+#[derive(Clone)]
+struct Route {
+    path: String,
+    prefix: bool,
+    methods: Vec<(String, Handler)>,
+    // the `Allow` header for a 405
+    allow: String,
+}
+
+impl Route {
+    #[inline]
+    fn matches(&self, path: &str) -> bool {
+        if !self.prefix {
+            return self.path == path;
+        }
+        // `/a/*` matches `/a` and anything below `/a/`
+        match path.strip_prefix(self.path.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    #[inline]
+    fn handler(&self, method: &str) -> Option<&Handler> {
+        self.methods
+            .iter()
+            .find(|(m, _)| m == method)
+            .map(|(_, handler)| handler)
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add the handler for `method` on `path`
+    pub fn route<F>(mut self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let (path, prefix) = match path.strip_suffix("/*") {
+            Some(p) => (p, true),
+            None => (path, false),
+        };
+        let routes = Arc::make_mut(&mut self.routes);
+        let i = match routes
+            .iter()
+            .position(|r| r.path == path && r.prefix == prefix)
+        {
+            Some(i) => i,
+            None => {
+                routes.push(Route {
+                    path: path.to_owned(),
+                    prefix,
+                    methods: Vec::new(),
+                    allow: String::new(),
+                });
+                routes.len() - 1
+            }
+        };
+        let route = &mut routes[i];
+        route.methods.retain(|(m, _)| m != method);
+        route.methods.push((method.to_owned(), Arc::new(handler)));
+        let mut methods: Vec<&str> = route.methods.iter().map(|(m, _)| m.as_str()).collect();
+        if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        route.allow = format!("Allow: {}", methods.join(", "));
+        self
+    }
+
+    pub fn get<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("GET", path, handler)
+    }
+
+    pub fn post<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("POST", path, handler)
+    }
+
+    pub fn put<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("PUT", path, handler)
+    }
+
+    pub fn delete<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("DELETE", path, handler)
+    }
+
+    pub fn patch<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.route("PATCH", path, handler)
+    }
+}
+
+impl HttpService for Router {
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        let path = req.path();
+        let path = path.split_once('?').map_or(path, |(p, _)| p);
+        let route = match self.routes.iter().find(|r| r.matches(path)) {
+            Some(route) => route,
+            None => {
+                rsp.status(StatusCode::NotFound);
+                return Ok(());
+            }
+        };
+        let method = req.method();
+        let handler = match route.handler(method.as_str()) {
+            None if method == Method::Head => route.handler("GET"),
+            handler => handler,
+        };
+        match handler {
+            Some(handler) => handler(req, rsp),
+            None => {
+                rsp.status(StatusCode::MethodNotAllowed)
+                    .header(route.allow.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::with_request;
+
+    fn ok(_: Request, _: &mut Response) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[test]
+    fn allow() {
+        let router = Router::new().get("/a", ok).post("/a", ok).put("/b", ok);
+        assert_eq!(router.routes[0].allow, "Allow: GET, POST, HEAD");
+        assert_eq!(router.routes[1].allow, "Allow: PUT");
+        let router = router.route("HEAD", "/a", ok);
+        assert_eq!(router.routes[0].allow, "Allow: GET, POST, HEAD");
+    }
+
+    /// the status the router answers the request with
+    fn call(router: &mut Router, head: &str) -> usize {
+        let mut body = bytes::BytesMut::new();
+        let mut rsp = Response::new(&mut body, b"");
+        with_request(head, |req| router.call(req, &mut rsp)).unwrap();
+        rsp.code()
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let get = |_: Request, rsp: &mut Response| {
+            rsp.status(StatusCode::Accepted);
+            Ok(())
+        };
+        let mut router = Router::new().get("/a", get).post("/b", ok);
+        assert_eq!(call(&mut router, "GET /a HTTP/1.1\r\nHost: h\r\n\r\n"), 202);
+        assert_eq!(
+            call(&mut router, "HEAD /a HTTP/1.1\r\nHost: h\r\n\r\n"),
+            202
+        );
+        assert_eq!(
+            call(&mut router, "HEAD /b HTTP/1.1\r\nHost: h\r\n\r\n"),
+            405
+        );
+        assert_eq!(
+            call(&mut router, "HEAD /c HTTP/1.1\r\nHost: h\r\n\r\n"),
+            404
+        );
+    }
+
+    #[test]
+    fn matches() {
+        let router = Router::new().get("/a", ok).get("/b/*", ok);
+        let (exact, prefix) = (&router.routes[0], &router.routes[1]);
+        assert!(exact.matches("/a"));
+        assert!(!exact.matches("/a/"));
+        assert!(!exact.matches("/ab"));
+        assert!(prefix.matches("/b"));
+        assert!(prefix.matches("/b/"));
+        assert!(prefix.matches("/b/c/d"));
+        assert!(!prefix.matches("/bc"));
+    }
+}
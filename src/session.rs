@@ -0,0 +1,445 @@
+//! cookie sessions
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::cookie::{self, Cookie, SameSite};
+use crate::headers;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::Response;
+
+/// the browsers drop bigger cookies
+const MAX_COOKIE_LEN: usize = 4096;
+const NONCE_LEN: usize = 12;
+/// how long a cookie without a max age is accepted after it was issued
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// the session of the request, see `Request::session`
+///
+/// a small string map that lives in the cookie, every change is sent back
+/// to the client with the response
+#[derive(Debug, Clone, Default)]
+pub struct Session(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    values: BTreeMap<String, String>,
+    changed: bool,
+}
+
+impl Session {
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.0.lock().unwrap().values.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: impl Into<String>, value: impl Into<String>) {
+        let mut state = self.0.lock().unwrap();
+        state.values.insert(key.into(), value.into());
+        state.changed = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.0.lock().unwrap();
+        let value = state.values.remove(key);
+        state.changed |= value.is_some();
+        value
+    }
+
+    /// drop all the values, the cookie is removed from the client
+    pub fn clear(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.changed |= !state.values.is_empty();
+        state.values.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Signed,
+    Encrypted,
+}
+
+/// keep a `Session` in a signed or encrypted cookie
+///
+/// a signed cookie can be read by the client but not changed, an encrypted one
+/// can't be read either. the first key protects the new cookies, the keys added
+/// with `old_key` are still accepted so they can be rotated out without logging
+/// everybody out, a cookie under an old key is reissued with the current one.
+/// a cookie that doesn't verify is ignored and the request gets an empty session
+///
+/// the cookie carries its expiry under the signature or the encryption, an expired
+/// one is ignored like a forged one. a session that goes on past half its lifetime
+/// is reissued with a new expiry
+///
+/// ```ignore
+/// let service = Chain::new(App).wrap(Sessions::encrypted(secret).old_key(previous));
+///
+/// // in the handler
+/// let session = req.session().unwrap();
+/// session.insert("user", "alice");
+/// ```
+#[derive(Clone)]
+pub struct Sessions {
+    mode: Mode,
+    keys: Vec<Vec<u8>>,
+    cookie_name: String,
+    path: String,
+    max_age: Option<Duration>,
+    secure: bool,
+}
+
+impl Sessions {
+    /// sign the cookies with HMAC-SHA256, the key should be at least 32 random bytes
+    pub fn signed(key: impl Into<Vec<u8>>) -> Self {
+        Self::new(Mode::Signed, key.into())
+    }
+
+    /// encrypt the cookies with ChaCha20-Poly1305 under a key derived from `key`,
+    /// which should be at least 32 random bytes
+    pub fn encrypted(key: impl Into<Vec<u8>>) -> Self {
+        Self::new(Mode::Encrypted, key.into())
+    }
+
+    fn new(mode: Mode, key: Vec<u8>) -> Self {
+        Sessions {
+            mode,
+            keys: vec![key],
+            cookie_name: "session".to_owned(),
+            path: "/".to_owned(),
+            max_age: None,
+            secure: false,
+        }
+    }
+
+    /// a previous key that is still accepted
+    pub fn old_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// the cookie name, `session` by default, panics if it is not a token
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        assert!(headers::is_token(&name), "invalid cookie name {name:?}");
+        self.cookie_name = name;
+        self
+    }
+
+    /// the cookie path, `/` by default, panics on a `;` or a control character
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        assert!(cookie::is_attribute(&path), "invalid cookie path {path:?}");
+        self.path = path;
+        self
+    }
+
+    /// keep the session for this long, it ends with the browser session by default
+    ///
+    /// the sealed expiry follows it, without a max age a cookie is accepted
+    /// for a day after it was issued
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// only send the cookie over https
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    fn cookie(&self, value: String) -> Cookie {
+        let mut cookie = Cookie::new(self.cookie_name.as_str(), value)
+            .path(self.path.as_str())
+            .same_site(SameSite::Lax)
+            .secure(self.secure)
+            .http_only(true);
+        if let Some(max_age) = self.max_age {
+            cookie = cookie.max_age(max_age);
+        }
+        cookie
+    }
+
+    /// the payload of the cookie and whether it was under the current key
+    fn open(&self, value: &str) -> Option<(Vec<u8>, bool)> {
+        self.keys.iter().enumerate().find_map(|(i, key)| {
+            let payload = match self.mode {
+                Mode::Signed => verify(key, value),
+                Mode::Encrypted => decrypt(key, value),
+            }?;
+            Some((payload, i == 0))
+        })
+    }
+
+    fn lifetime(&self) -> u64 {
+        self.max_age.unwrap_or(DEFAULT_LIFETIME).as_secs()
+    }
+
+    /// the values of an unexpired cookie at `now` and whether it is still
+    /// good to keep, under the current key and in the first half of its lifetime
+    fn load(&self, value: &str, now: u64) -> Option<(BTreeMap<String, String>, bool)> {
+        let (payload, current) = self.open(value)?;
+        let (expires, values) = payload.split_first_chunk::<8>()?;
+        let left = u64::from_be_bytes(*expires)
+            .checked_sub(now)
+            .filter(|&s| s > 0)?;
+        let fresh = left > self.lifetime() / 2;
+        Some((deserialize(values)?, current && fresh))
+    }
+
+    /// the expiry in unix seconds followed by the values
+    fn seal(&self, values: &BTreeMap<String, String>, now: u64) -> io::Result<String> {
+        let expires = now.saturating_add(self.lifetime());
+        let mut payload = expires.to_be_bytes().to_vec();
+        payload.extend_from_slice(&serialize(values));
+        let key = &self.keys[0];
+        match self.mode {
+            Mode::Signed => Ok(sign(key, &payload)),
+            Mode::Encrypted => encrypt(key, &payload),
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle(&self, mut req: Request, rsp: &mut Response, next: Next) -> io::Result<()> {
+        let now = unix_now();
+        let mut current = true;
+        let values = match req
+            .cookie(&self.cookie_name)
+            .and_then(|v| self.load(v, now))
+        {
+            Some((values, keep)) => {
+                current = keep;
+                values
+            }
+            None => BTreeMap::new(),
+        };
+        let session = Session(Arc::new(Mutex::new(State {
+            values,
+            changed: false,
+        })));
+        req.set_session(session.clone());
+        next.run(req, rsp)?;
+
+        let state = session.0.lock().unwrap();
+        if !state.changed && (current || state.values.is_empty()) {
+            return Ok(());
+        }
+        if state.values.is_empty() {
+            rsp.set_cookie(Cookie::removal(self.cookie_name.as_str()).path(self.path.as_str()));
+            return Ok(());
+        }
+        let value = self.seal(&state.values, now)?;
+        if value.len() > MAX_COOKIE_LEN {
+            return Err(io::Error::other("session is too large for a cookie"));
+        }
+        rsp.set_cookie(self.cookie(value));
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// each key and value with a big endian u16 length prefix
+fn serialize(values: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (k, v) in values {
+        for s in [k, v] {
+            let len = s.len().min(u16::MAX as usize);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+            out.extend_from_slice(&s.as_bytes()[..len]);
+        }
+    }
+    out
+}
+
+/// `None` unless the whole buffer is key/value pairs
+fn deserialize(mut buf: &[u8]) -> Option<BTreeMap<String, String>> {
+    fn next(buf: &mut &[u8]) -> Option<String> {
+        let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+        let s = std::str::from_utf8(buf.get(2..2 + len)?).ok()?.to_owned();
+        *buf = &buf[2 + len..];
+        Some(s)
+    }
+    let mut values = BTreeMap::new();
+    while !buf.is_empty() {
+        let key = next(&mut buf)?;
+        values.insert(key, next(&mut buf)?);
+    }
+    Some(values)
+}
+
+fn mac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::new_from_slice(key).expect("hmac takes a key of any size")
+}
+
+/// `<payload>.<tag>` in url safe base64
+fn sign(key: &[u8], payload: &[u8]) -> String {
+    let payload = B64.encode(payload);
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    let tag = B64.encode(mac.finalize().into_bytes());
+    format!("{payload}.{tag}")
+}
+
+fn verify(key: &[u8], value: &str) -> Option<Vec<u8>> {
+    let (payload, tag) = value.rsplit_once('.')?;
+    let tag = B64.decode(tag).ok()?;
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&tag).ok()?;
+    B64.decode(payload).ok()
+}
+
+fn cipher(key: &[u8]) -> ChaCha20Poly1305 {
+    let key = Sha256::digest(key);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// `<nonce><ciphertext>` in url safe base64
+fn encrypt(key: &[u8], payload: &[u8]) -> io::Result<String> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher(key)
+        .encrypt(&nonce, payload)
+        .map_err(|_| io::Error::other("session encryption failed"))?;
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&sealed);
+    Ok(B64.encode(out))
+}
+
+fn decrypt(key: &[u8], value: &str) -> Option<Vec<u8>> {
+    let sealed = B64.decode(value).ok()?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    cipher(key).decrypt(Nonce::from_slice(nonce), sealed).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        let owned = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        owned.collect()
+    }
+
+    fn both(key: &str) -> [Sessions; 2] {
+        [Sessions::signed(key), Sessions::encrypted(key)]
+    }
+
+    #[test]
+    fn serialization() {
+        let map = values(&[("a", "1"), ("", ""), ("user", "\u{e9}l\u{e8}ve")]);
+        assert_eq!(deserialize(&serialize(&map)), Some(map));
+        assert_eq!(deserialize(&[]), Some(BTreeMap::new()));
+        // a key without its value, a length past the end
+        assert_eq!(deserialize(&[0, 1, b'a']), None);
+        assert_eq!(deserialize(&[0, 5, b'a']), None);
+        assert_eq!(deserialize(&[0, 1, 0xff, 0, 0]), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = values(&[("user", "alice")]);
+        for sessions in both("k1") {
+            let value = sessions.seal(&map, NOW).unwrap();
+            assert_eq!(sessions.load(&value, NOW), Some((map.clone(), true)));
+            assert!(Cookie::new("session", value)
+                .to_string()
+                .starts_with("session="));
+        }
+    }
+
+    #[test]
+    fn expiry() {
+        let map = values(&[("user", "alice")]);
+        let day = DEFAULT_LIFETIME.as_secs();
+        for sessions in both("k1") {
+            let value = sessions.seal(&map, NOW).unwrap();
+            assert_eq!(
+                sessions.load(&value, NOW + day / 2 - 1),
+                Some((map.clone(), true))
+            );
+            // past half its lifetime it is reissued
+            assert_eq!(
+                sessions.load(&value, NOW + day / 2),
+                Some((map.clone(), false))
+            );
+            assert_eq!(
+                sessions.load(&value, NOW + day - 1),
+                Some((map.clone(), false))
+            );
+            assert_eq!(sessions.load(&value, NOW + day), None);
+            assert_eq!(sessions.load(&value, u64::MAX), None);
+
+            let sessions = sessions.max_age(Duration::from_secs(60));
+            let value = sessions.seal(&map, NOW).unwrap();
+            assert!(sessions.load(&value, NOW + 59).is_some());
+            assert_eq!(sessions.load(&value, NOW + 60), None);
+        }
+    }
+
+    #[test]
+    fn forged() {
+        let map = values(&[("user", "alice")]);
+        for sessions in both("k1") {
+            let value = sessions.seal(&map, NOW).unwrap();
+            let mut bytes = value.clone().into_bytes();
+            bytes[2] = if bytes[2] == b'A' { b'B' } else { b'A' };
+            let tampered = String::from_utf8(bytes).unwrap();
+            assert_eq!(sessions.load(&tampered, NOW), None);
+            assert_eq!(sessions.load("", NOW), None);
+            assert_eq!(sessions.load("a.b", NOW), None);
+            assert_eq!(sessions.load(&value[..value.len() - 4], NOW), None);
+        }
+        // the expiry can't be moved out of the signed payload
+        let sessions = Sessions::signed("k1");
+        let value = sessions.seal(&map, NOW).unwrap();
+        let (_, tag) = value.rsplit_once('.').unwrap();
+        let mut payload = u64::MAX.to_be_bytes().to_vec();
+        payload.extend_from_slice(&serialize(&map));
+        let extended = format!("{}.{tag}", B64.encode(payload));
+        assert_eq!(sessions.load(&extended, NOW), None);
+    }
+
+    #[test]
+    fn key_rotation() {
+        let map = values(&[("user", "alice")]);
+        for (old, new) in both("k1").into_iter().zip(both("k2")) {
+            let value = old.seal(&map, NOW).unwrap();
+            assert_eq!(new.load(&value, NOW), None);
+            let new = new.old_key("k1");
+            // accepted and reissued under the current key
+            assert_eq!(new.load(&value, NOW), Some((map.clone(), false)));
+            let value = new.seal(&map, NOW).unwrap();
+            assert_eq!(new.load(&value, NOW), Some((map.clone(), true)));
+        }
+    }
+
+    #[test]
+    fn signed_and_encrypted_differ() {
+        let map = values(&[("user", "alice")]);
+        let signed = Sessions::signed("k1").seal(&map, NOW).unwrap();
+        let encrypted = Sessions::encrypted("k1").seal(&map, NOW).unwrap();
+        assert_eq!(Sessions::encrypted("k1").load(&signed, NOW), None);
+        assert_eq!(Sessions::signed("k1").load(&encrypted, NOW), None);
+    }
+}
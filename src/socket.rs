@@ -0,0 +1,104 @@
+//! options of the listening and accepted sockets
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use std::net::TcpListener;
+
+use may::net::TcpStream;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+/// the listen backlog when none is set, the one of std
+const BACKLOG: i32 = 128;
+
+/// the socket options set on the server builder
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SocketOptions {
+    pub(crate) nodelay: bool,
+    pub(crate) keepalive: Option<Duration>,
+    pub(crate) keepalive_interval: Option<Duration>,
+    // not settable on windows
+    #[cfg_attr(windows, allow(dead_code))]
+    pub(crate) keepalive_retries: Option<u32>,
+    pub(crate) backlog: Option<u32>,
+    pub(crate) send_buffer_size: Option<usize>,
+    pub(crate) recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    /// bind a listener to the first address that works, like `TcpListener::bind`
+    pub(crate) fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpListener> {
+        let mut last = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_addr(addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to")
+        }))
+    }
+
+    fn bind_addr(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // what std does, a restarted server can bind while the old connections linger
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        // the accepted sockets inherit the buffer sizes
+        self.set_buffers(&socket)?;
+        socket.bind(&addr.into())?;
+        let backlog = self
+            .backlog
+            .map_or(BACKLOG, |n| n.min(i32::MAX as u32) as i32);
+        socket.listen(backlog)?;
+        Ok(socket.into())
+    }
+
+    /// set the options on an accepted connection
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if self.keepalive.is_none()
+            && self.send_buffer_size.is_none()
+            && self.recv_buffer_size.is_none()
+        {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        let fd =
+            unsafe { std::os::fd::BorrowedFd::borrow_raw(std::os::fd::AsRawFd::as_raw_fd(stream)) };
+        #[cfg(windows)]
+        let fd = unsafe {
+            std::os::windows::io::BorrowedSocket::borrow_raw(
+                std::os::windows::io::AsRawSocket::as_raw_socket(stream),
+            )
+        };
+        let socket = SockRef::from(&fd);
+        self.set_buffers(&socket)?;
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            #[cfg(not(windows))]
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    fn set_buffers(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+//! server-sent events support
+
+use std::io;
+
+use may::sync::mpsc;
+
+/// the sending half of a `text/event-stream` response
+///
+/// it can be cloned and moved into other coroutines, each event is
+/// flushed to the client as soon as the connection picks it up.
+/// the stream ends once all the senders are dropped
+#[derive(Clone)]
+pub struct SseSender(pub(crate) mpsc::Sender<Vec<u8>>);
+
+impl SseSender {
+    /// send an unnamed event, multi line data is split into several `data:` fields
+    pub fn send(&self, data: &str) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(data.len() + 16);
+        push_data(&mut frame, data);
+        self.push(frame)
+    }
+
+    /// send an event with the given `event:` name
+    pub fn send_event(&self, event: &str, data: &str) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(event.len() + data.len() + 24);
+        push_field(&mut frame, "event", event);
+        push_data(&mut frame, data);
+        self.push(frame)
+    }
+
+    /// send a comment line, handy as a keep alive for idle streams
+    pub fn comment(&self, text: &str) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(text.len() + 4);
+        for line in text.lines() {
+            push_field(&mut frame, "", line);
+        }
+        frame.push(b'\n');
+        self.push(frame)
+    }
+
+    #[inline]
+    fn push(&self, frame: Vec<u8>) -> io::Result<()> {
+        self.0
+            .send(frame)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event stream closed"))
+    }
+}
+
+#[inline]
+fn push_field(frame: &mut Vec<u8>, name: &str, value: &str) {
+    frame.extend_from_slice(name.as_bytes());
+    frame.extend_from_slice(b": ");
+    frame.extend_from_slice(value.as_bytes());
+    frame.push(b'\n');
+}
+
+fn push_data(frame: &mut Vec<u8>, data: &str) {
+    if data.is_empty() {
+        push_field(frame, "data", "");
+    }
+    for line in data.lines() {
+        push_field(frame, "data", line);
+    }
+    // the blank line dispatches the event
+    frame.push(b'\n');
+}
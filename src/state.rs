@@ -0,0 +1,74 @@
+//! application state shared by every connection
+
+use std::io;
+use std::sync::Arc;
+
+use crate::http_server::{HttpServer, HttpService};
+use crate::request::Request;
+use crate::response::Response;
+
+/// a handler given the shared state on each call, see `HttpServer::with_state`
+pub struct WithState<S, F> {
+    state: Arc<S>,
+    handler: Arc<F>,
+}
+
+// a derive would want `S: Clone` and `F: Clone`
+impl<S, F> Clone for WithState<S, F> {
+    fn clone(&self) -> Self {
+        WithState {
+            state: self.state.clone(),
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+impl<S, F> WithState<S, F>
+where
+    S: Send + Sync + 'static,
+    F: Fn(&S, Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+{
+    pub fn new(state: Arc<S>, handler: F) -> Self {
+        WithState {
+            state,
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub fn state(&self) -> &Arc<S> {
+        &self.state
+    }
+}
+
+impl<S, F> HttpService for WithState<S, F>
+where
+    S: Send + Sync + 'static,
+    F: Fn(&S, Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+{
+    #[inline]
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        (self.handler)(&self.state, req, rsp)
+    }
+}
+
+impl<S, F> HttpServer<WithState<S, F>>
+where
+    S: Send + Sync + 'static,
+    F: Fn(&S, Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
+{
+    /// serve `handler` with the state shared by all the connections
+    ///
+    /// ```ignore
+    /// struct App { greeting: String }
+    ///
+    /// let app = Arc::new(App { greeting: "Hello".to_owned() });
+    /// HttpServer::with_state(app, |app, _req, rsp| {
+    ///     rsp.body_vec(app.greeting.clone().into_bytes());
+    ///     Ok(())
+    /// })
+    /// .start("0.0.0.0:8080")?;
+    /// ```
+    pub fn with_state(state: Arc<S>, handler: F) -> Self {
+        HttpServer(WithState::new(state, handler))
+    }
+}
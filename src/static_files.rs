@@ -0,0 +1,306 @@
+//! serving the files of a directory
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use httpdate::HttpDate;
+
+use crate::http_server::HttpService;
+use crate::method::Method;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// the file served for a directory
+const INDEX: &str = "index.html";
+
+/// serve the files under a directory for the requests under a url prefix
+///
+/// `/static/css/site.css` is looked up as `<root>/css/site.css` for the prefix
+/// `/static`, a directory is answered with its `index.html`.
+/// a path that can't be mapped below the root (`..`, an encoded separator, ...)
+/// or a missing file gets `404 Not Found`, other methods than `GET` and `HEAD`
+/// get `405 Method Not Allowed`. a `Range` request is answered with the part of the file
+/// it asks for.
+/// the files are sent with their `Last-Modified` time, a file that has not changed
+/// since the `If-Modified-Since` of the request gets `304 Not Modified`
+///
+/// ```ignore
+/// let files = StaticFiles::new("/static", "./public");
+/// let router = Router::new()
+///     .get("/", index)
+///     .get("/static/*", move |req, rsp| files.serve(req, rsp));
+/// ```
+#[derive(Clone)]
+pub struct StaticFiles {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    prefix: String,
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(prefix: &str, root: impl Into<PathBuf>) -> Self {
+        StaticFiles {
+            inner: Arc::new(Inner {
+                prefix: prefix.trim_end_matches('/').to_owned(),
+                root: root.into(),
+            }),
+        }
+    }
+
+    /// answer the request with the file it points to
+    pub fn serve(&self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            rsp.status(StatusCode::MethodNotAllowed)
+                .header("Allow: GET, HEAD");
+            return Ok(());
+        }
+        let path = req.path();
+        let path = path.split_once('?').map_or(path, |(p, _)| p);
+        let path = match self.resolve(path) {
+            Some(path) => path,
+            None => {
+                rsp.status(StatusCode::NotFound);
+                return Ok(());
+            }
+        };
+        let (file, path) = match open(path) {
+            Ok(found) => found,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                rsp.status(StatusCode::NotFound);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        rsp.header(format!("Content-Type: {}", mime_type(&path)));
+        if let Ok(modified) = file.metadata()?.modified() {
+            let modified = HttpDate::from(modified);
+            rsp.header(format!("Last-Modified: {modified}"));
+            // `If-None-Match` takes precedence, there is no etag to match it
+            let since = match header(&req, "if-none-match") {
+                Some(_) => None,
+                None => header(&req, "if-modified-since").and_then(|d| d.parse().ok()),
+            };
+            if since.is_some_and(|since: HttpDate| modified <= since) {
+                rsp.status(StatusCode::NotModified);
+                return Ok(());
+            }
+        }
+        rsp.file_range(file, header(&req, "range"))
+    }
+
+    /// map the url path to a file path below the root
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let rest = path.strip_prefix(self.inner.prefix.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let mut file = self.inner.root.clone();
+        for segment in rest.split('/') {
+            let segment = percent_decode(segment)?;
+            match segment.as_str() {
+                "" | "." => {}
+                ".." => return None,
+                // a decoded separator or drive prefix could step out of the root
+                s if s.contains(['/', '\\', ':', '\0']) => return None,
+                s => file.push(s),
+            }
+        }
+        Some(file)
+    }
+}
+
+impl HttpService for StaticFiles {
+    #[inline]
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        self.serve(req, rsp)
+    }
+}
+
+/// the value of the named request header
+fn header<'r>(req: &'r Request, name: &str) -> Option<&'r str> {
+    req.headers()
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+/// open the file, or the index of a directory
+fn open(mut path: PathBuf) -> io::Result<(File, PathBuf)> {
+    if path.is_dir() {
+        path.push(INDEX);
+    }
+    let file = File::open(&path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok((file, path))
+}
+
+/// decode the `%XX` escapes of a path segment, `None` when it is not valid utf-8
+fn percent_decode(s: &str) -> Option<String> {
+    if !s.contains('%') {
+        return Some(s.to_owned());
+    }
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let hi = (bytes.next()? as char).to_digit(16)?;
+        let lo = (bytes.next()? as char).to_digit(16)?;
+        out.push((hi << 4 | lo) as u8);
+    }
+    String::from_utf8(out).ok()
+}
+
+/// guess the `Content-Type` from the file extension
+fn mime_type(path: &Path) -> &'static str {
+    let ext = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::with_request;
+
+    fn files() -> StaticFiles {
+        StaticFiles::new("/static/", "/srv")
+    }
+
+    #[test]
+    fn resolve() {
+        let files = files();
+        let resolve = |path| files.resolve(path);
+        assert_eq!(resolve("/static"), Some(PathBuf::from("/srv")));
+        assert_eq!(resolve("/static/"), Some(PathBuf::from("/srv")));
+        assert_eq!(
+            resolve("/static/a/b.css"),
+            Some(PathBuf::from("/srv/a/b.css"))
+        );
+        assert_eq!(resolve("/static/a//./b"), Some(PathBuf::from("/srv/a/b")));
+        assert_eq!(resolve("/static/a%20b"), Some(PathBuf::from("/srv/a b")));
+        assert_eq!(resolve("/static/%2e%2e."), Some(PathBuf::from("/srv/...")));
+        assert_eq!(resolve("/staticx/a"), None);
+        assert_eq!(resolve("/other/a"), None);
+    }
+
+    #[test]
+    fn resolve_traversal() {
+        let files = files();
+        let bad = [
+            "/static/..",
+            "/static/a/../../etc/passwd",
+            "/static/%2e%2e/etc/passwd",
+            "/static/%2E%2E",
+            // encoded separators
+            "/static/..%2fetc",
+            "/static/a%2F..%2F..",
+            "/static/..%5c..%5cwindows",
+            // drive prefixes and NUL
+            "/static/C:",
+            "/static/c:%5cwindows",
+            "/static/a%00.html",
+            // bad escapes
+            "/static/%",
+            "/static/%2",
+            "/static/%zz",
+            "/static/%ff",
+        ];
+        for path in bad {
+            assert_eq!(files.resolve(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a"), Some("a".to_owned()));
+        assert_eq!(percent_decode("%41%62"), Some("Ab".to_owned()));
+        assert_eq!(percent_decode("%c3%a9"), Some("\u{e9}".to_owned()));
+        assert_eq!(percent_decode("%e9"), None);
+        assert_eq!(percent_decode("%4"), None);
+    }
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(mime_type(Path::new("a.HTML")), "text/html; charset=utf-8");
+        assert_eq!(mime_type(Path::new("a.tar.gz")), "application/gzip");
+        assert_eq!(mime_type(Path::new("a")), "application/octet-stream");
+        assert_eq!(
+            mime_type(Path::new("a.unknown")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn methods() {
+        let dir = std::env::temp_dir().join(format!("static-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(INDEX), "hello").unwrap();
+        let files = StaticFiles::new("/", &dir);
+
+        let serve = |head: &str| {
+            let mut body = bytes::BytesMut::new();
+            let mut rsp = Response::new(&mut body, b"");
+            with_request(head, |req| files.serve(req, &mut rsp)).unwrap();
+            let allow = rsp.header_value("allow").map(str::to_owned);
+            (rsp.code(), rsp.is_file(), allow)
+        };
+        assert_eq!(
+            serve("GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            (200, true, None)
+        );
+        assert_eq!(
+            serve("HEAD / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            (200, true, None)
+        );
+        assert_eq!(serve("GET /missing HTTP/1.1\r\nHost: a\r\n\r\n").0, 404);
+        let allow = Some("GET, HEAD".to_owned());
+        assert_eq!(
+            serve("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n"),
+            (405, false, allow)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+//! systemd socket activation and readiness notification
+//!
+//! ```ignore
+//! // in the unit: Type=notify, with a .socket unit holding the listener
+//! let server = ServerBuilder::new().start_activated(HttpServer(Hello))?;
+//! server.join().unwrap();
+//! ```
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+// the first passed socket, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// take the listening sockets passed by systemd, in the order of the socket unit
+///
+/// empty when the process was not socket activated. the variables are removed
+/// from the environment so the sockets are not passed on to the child processes,
+/// a second call finds none
+pub fn listen_fds() -> io::Result<Vec<TcpListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    // the variables are meant for another process
+    if pid.and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let n: RawFd = match fds.map(|n| n.parse()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => return Err(invalid("bad LISTEN_FDS")),
+        None => return Ok(Vec::new()),
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + n)
+        .map(|fd| {
+            // they are inherited without close-on-exec
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD, 0) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(unsafe { TcpListener::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+/// send a state like `READY=1` or `STOPPING=1` to the service manager
+///
+/// `false` when the process is not run by one that listens for it
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let path = path.into_encoded_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(
+            std::str::from_utf8(&path).map_err(|_| invalid("bad NOTIFY_SOCKET"))?,
+        )?,
+    };
+    let sock = UnixDatagram::unbound()?;
+    sock.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+/// tell the service manager the server is up
+pub fn notify_ready() -> io::Result<bool> {
+    notify("READY=1")
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
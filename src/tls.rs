@@ -0,0 +1,408 @@
+//! pluggable TLS layer for the server
+//!
+//! each backend lives behind its own cargo feature, `rustls` or `native-tls`
+//!
+//! only HTTP/1.x is served, so advertise `http/1.1` (and not `h2`) via ALPN;
+//! a client that still starts speaking HTTP/2 gets a GOAWAY asking it to downgrade
+//!
+//! with rustls several certificates are served through `SniResolver`,
+//! native-tls has no hook to choose one by server name
+//!
+//! client certificates are asked for and verified by the rustls config, with
+//! `ServerConfig::builder().with_client_cert_verifier(..)`, the verified one is
+//! then on `ConnectionInfo::tls`. native-tls servers can't ask for one
+
+use std::fmt::{self, Write};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use may::net::TcpStream;
+use sha2::{Digest, Sha256};
+
+use crate::connection::PeerCertificate;
+use crate::http_server::Transport;
+
+/// a TLS implementation that can wrap accepted connections
+///
+/// implemented for `Arc<rustls::ServerConfig>` and `native_tls::TlsAcceptor`
+pub trait TlsAcceptor: Clone + Send + 'static {
+    #[doc(hidden)]
+    type Stream: Transport + Send + 'static;
+
+    /// wrap the accepted socket in a TLS session
+    /// this is called inside the connection coroutine so it may block on the handshake
+    fn accept(&self, sock: TcpStream) -> io::Result<Self::Stream>;
+
+    /// `accept` with a blocking handshake bounded by `timeout`, the server
+    /// calls this one. a backend that leaves the handshake to the connection
+    /// loop, where the timers apply, doesn't need it
+    #[doc(hidden)]
+    fn accept_timeout(
+        &self,
+        sock: TcpStream,
+        timeout: Option<Duration>,
+    ) -> io::Result<Self::Stream> {
+        let _ = timeout;
+        self.accept(sock)
+    }
+}
+
+/// a TLS acceptor that can be swapped while the server runs, so renewed
+/// certificates are picked up without a restart
+///
+/// the new connections are handshaked with the latest acceptor,
+/// the open ones keep their session
+///
+/// ```ignore
+/// let tls = ReloadableTls::new(load_config()?);
+/// tls.watch(vec!["cert.pem".into(), "key.pem".into()], Duration::from_secs(60), load_config);
+/// let server = HttpServer(Hello).start_tls("0.0.0.0:443", tls.clone())?;
+/// ```
+#[derive(Clone)]
+pub struct ReloadableTls<A> {
+    current: Arc<RwLock<A>>,
+}
+
+impl<A: TlsAcceptor + Sync> ReloadableTls<A> {
+    pub fn new(acceptor: A) -> Self {
+        ReloadableTls {
+            current: Arc::new(RwLock::new(acceptor)),
+        }
+    }
+
+    /// handshake the next connections with this acceptor
+    pub fn reload(&self, acceptor: A) {
+        *self.current.write().unwrap() = acceptor;
+    }
+
+    /// reload with `load` once any of the files is modified, they are checked
+    /// every `interval`. a failed load is logged and the current acceptor kept,
+    /// it is tried again on the next change
+    ///
+    /// the watch ends when all the clones of the `ReloadableTls` are dropped
+    pub fn watch<F>(&self, files: Vec<PathBuf>, interval: Duration, load: F)
+    where
+        F: Fn() -> io::Result<A> + Send + 'static,
+    {
+        let current = Arc::downgrade(&self.current);
+        let modified = move || -> Vec<Option<SystemTime>> {
+            files
+                .iter()
+                .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+        let mut last = modified();
+        may::go!(move || loop {
+            may::coroutine::sleep(interval);
+            let current = match current.upgrade() {
+                Some(current) => current,
+                None => return,
+            };
+            let now = modified();
+            if now == last {
+                continue;
+            }
+            last = now;
+            match load() {
+                Ok(acceptor) => {
+                    *current.write().unwrap() = acceptor;
+                    info!("tls certificates reloaded");
+                }
+                Err(e) => error!("tls reload err = {:?}", e),
+            }
+        });
+    }
+}
+
+impl<A: TlsAcceptor + Sync> TlsAcceptor for ReloadableTls<A> {
+    type Stream = A::Stream;
+
+    fn accept(&self, sock: TcpStream) -> io::Result<A::Stream> {
+        self.accept_timeout(sock, None)
+    }
+
+    fn accept_timeout(&self, sock: TcpStream, timeout: Option<Duration>) -> io::Result<A::Stream> {
+        let acceptor = self.current.read().unwrap().clone();
+        acceptor.accept_timeout(sock, timeout)
+    }
+}
+
+impl<A> fmt::Debug for ReloadableTls<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReloadableTls").finish_non_exhaustive()
+    }
+}
+
+/// a client certificate with the fields handlers look at
+///
+/// the fields that can't be parsed are left empty, the rustls or native-tls
+/// verifier already checked the certificate
+pub(crate) fn peer_certificate(der: Vec<u8>) -> PeerCertificate {
+    let fingerprint = Sha256::digest(&der).iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    });
+    let (subject, alt_names) = parse_certificate(&der).unwrap_or_default();
+    PeerCertificate {
+        der,
+        subject,
+        alt_names,
+        fingerprint,
+    }
+}
+
+pub(crate) const SEQUENCE: u8 = 0x30;
+// the name attributes that have a short name
+const ATTRIBUTES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (
+        &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01],
+        "emailAddress",
+    ),
+];
+// 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// the subject and the alternative names of an X.509 certificate
+fn parse_certificate(der: &[u8]) -> Option<(String, Vec<String>)> {
+    let (_, cert, _) = element(der, SEQUENCE)?;
+    let (_, tbs, _) = element(cert, SEQUENCE)?;
+    let (tag, _, mut rest) = any_element(tbs)?;
+    // the version is left out for v1
+    if tag == 0xa0 {
+        rest = any_element(rest)?.2;
+    }
+    // the signature algorithm, the issuer and the validity come before the subject
+    for _ in 0..3 {
+        rest = any_element(rest)?.2;
+    }
+    let (_, name, mut rest) = element(rest, SEQUENCE)?;
+    let subject = parse_name(name)?;
+
+    let mut alt_names = Vec::new();
+    while let Some((tag, value, next)) = any_element(rest) {
+        rest = next;
+        if tag != 0xa3 {
+            continue;
+        }
+        let (_, mut extensions, _) = element(value, SEQUENCE)?;
+        while let Some((_, extension, next)) = element(extensions, SEQUENCE) {
+            extensions = next;
+            let (_, oid, mut fields) = element(extension, 0x06)?;
+            if oid != SUBJECT_ALT_NAME {
+                continue;
+            }
+            // skip the critical flag
+            if let Some((0x01, _, next)) = any_element(fields) {
+                fields = next;
+            }
+            let (_, value, _) = element(fields, 0x04)?;
+            let (_, mut names, _) = element(value, SEQUENCE)?;
+            while let Some((tag, name, next)) = any_element(names) {
+                names = next;
+                let name = match tag {
+                    0x81 => format!("email:{}", String::from_utf8_lossy(name)),
+                    0x82 => format!("DNS:{}", String::from_utf8_lossy(name)),
+                    0x86 => format!("URI:{}", String::from_utf8_lossy(name)),
+                    0x87 => match name.len() {
+                        4 => format!("IP:{}", Ipv4Addr::from(<[u8; 4]>::try_from(name).ok()?)),
+                        16 => format!("IP:{}", Ipv6Addr::from(<[u8; 16]>::try_from(name).ok()?)),
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                alt_names.push(name);
+            }
+        }
+    }
+    Some((subject, alt_names))
+}
+
+/// `C=US, O=Example, CN=client` from the sets of attributes of a name
+fn parse_name(mut name: &[u8]) -> Option<String> {
+    let mut out = String::new();
+    while let Some((_, mut set, next)) = element(name, 0x31) {
+        name = next;
+        while let Some((_, attribute, next)) = element(set, SEQUENCE) {
+            set = next;
+            let (_, oid, value) = element(attribute, 0x06)?;
+            let (tag, value, _) = any_element(value)?;
+            if !out.is_empty() {
+                out.push_str(", ");
+            }
+            match ATTRIBUTES.iter().find(|(o, _)| *o == oid) {
+                Some((_, short)) => out.push_str(short),
+                None => out.push_str(&oid_string(oid)),
+            }
+            out.push('=');
+            // BMPString is UTF-16, the other string types are ASCII or UTF-8
+            if tag == 0x1e {
+                let units: Vec<u16> = value
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                out.push_str(&String::from_utf16_lossy(&units));
+            } else {
+                out.push_str(&String::from_utf8_lossy(value));
+            }
+        }
+    }
+    Some(out)
+}
+
+/// the dotted form of an object identifier
+fn oid_string(oid: &[u8]) -> String {
+    let mut out = String::new();
+    let mut n: u64 = 0;
+    for &b in oid {
+        n = n << 7 | (b & 0x7f) as u64;
+        if b & 0x80 != 0 {
+            continue;
+        }
+        if out.is_empty() {
+            // the first byte holds the first two arcs
+            let first = (n / 40).min(2);
+            let _ = write!(out, "{}.{}", first, n - first * 40);
+        } else {
+            let _ = write!(out, ".{n}");
+        }
+        n = 0;
+    }
+    out
+}
+
+/// the next DER element if it has the tag, with its contents and what follows it
+pub(crate) fn element(input: &[u8], tag: u8) -> Option<(u8, &[u8], &[u8])> {
+    any_element(input).filter(|(t, _, _)| *t == tag)
+}
+
+/// the next DER element, with its tag, its contents and what follows it
+pub(crate) fn any_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (len as usize, rest)
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // self-signed P-256 certificates made with openssl 3:
+    // v1.der: `req -x509 -x509v1 -subj "/CN=v1 client"`
+    // v3.der: `req -x509 -utf8 -subj "/C=DE/O=Exämple GmbH/serialNumber=42/CN=client"`
+    //   with the alternative names below and a critical basicConstraints
+    // bmp.der: `req -x509` with `string_mask = default` and `CN = Łódź`
+    const V1: &[u8] = include_bytes!("testdata/v1.der");
+    const V3: &[u8] = include_bytes!("testdata/v3.der");
+    const BMP: &[u8] = include_bytes!("testdata/bmp.der");
+
+    #[test]
+    fn certificates() {
+        let (subject, alt_names) = parse_certificate(V3).unwrap();
+        assert_eq!(subject, "C=DE, O=Exämple GmbH, 2.5.4.5=42, CN=client");
+        assert_eq!(
+            alt_names,
+            [
+                "DNS:client.example.com",
+                "IP:10.0.0.1",
+                "IP:2001:db8::1",
+                "URI:spiffe://example.com/client",
+                "email:a@example.com",
+            ]
+        );
+        assert_eq!(
+            parse_certificate(V1).unwrap(),
+            ("CN=v1 client".into(), vec![])
+        );
+        assert_eq!(parse_certificate(BMP).unwrap(), ("CN=Łódź".into(), vec![]));
+
+        let cert = peer_certificate(V3.to_vec());
+        assert_eq!(cert.der(), V3);
+        assert_eq!(
+            cert.subject(),
+            "C=DE, O=Exämple GmbH, 2.5.4.5=42, CN=client"
+        );
+        assert_eq!(cert.subject_alt_names().len(), 5);
+    }
+
+    #[test]
+    fn malformed_certificates() {
+        for cert in [V1, V3, BMP] {
+            for len in 0..cert.len() {
+                assert_eq!(parse_certificate(&cert[..len]), None, "{len}");
+            }
+            // the certificate claims more bytes than there are
+            let mut long = cert.to_vec();
+            long[3] += 1;
+            assert_eq!(parse_certificate(&long), None);
+        }
+        let cert = peer_certificate(b"not a certificate".to_vec());
+        assert_eq!((cert.subject(), cert.subject_alt_names()), ("", &[][..]));
+    }
+
+    #[test]
+    fn elements() {
+        assert_eq!(
+            any_element(&[0x04, 2, 1, 2, 3]),
+            Some((0x04, &[1, 2][..], &[3][..]))
+        );
+        assert_eq!(any_element(&[0x04, 0]), Some((0x04, &[][..], &[][..])));
+        // long form lengths
+        let mut der = vec![0x04, 0x81, 0x80];
+        der.extend_from_slice(&[7; 0x80]);
+        assert_eq!(
+            any_element(&der).map(|(_, v, r)| (v.len(), r.len())),
+            Some((0x80, 0))
+        );
+        let mut der = vec![0x04, 0x82, 0x01, 0x00];
+        der.extend_from_slice(&[7; 0x101]);
+        assert_eq!(
+            any_element(&der).map(|(_, v, r)| (v.len(), r.len())),
+            Some((0x100, 1))
+        );
+        // truncated, indefinite or too long lengths
+        assert_eq!(any_element(&[]), None);
+        assert_eq!(any_element(&[0x04]), None);
+        assert_eq!(any_element(&[0x04, 3, 1, 2]), None);
+        assert_eq!(any_element(&[0x04, 0x82, 0x01]), None);
+        assert_eq!(any_element(&[0x04, 0x81, 0x02, 1]), None);
+        assert_eq!(any_element(&[0x30, 0x80, 0, 0]), None);
+        assert_eq!(any_element(&[0x04, 0x85, 0, 0, 0, 0, 1, 0]), None);
+        assert_eq!(any_element(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+
+        assert_eq!(element(&[0x04, 0], 0x04).map(|e| e.0), Some(0x04));
+        assert_eq!(element(&[0x04, 0], 0x30), None);
+    }
+
+    #[test]
+    fn oids() {
+        assert_eq!(oid_string(&[0x55, 0x04, 0x05]), "2.5.4.5");
+        assert_eq!(
+            oid_string(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d]),
+            "1.2.840.113549"
+        );
+        assert_eq!(oid_string(&[0x88, 0x37, 0x03]), "2.999.3");
+        assert_eq!(oid_string(&[0x00]), "0.0");
+    }
+}
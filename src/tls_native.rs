@@ -0,0 +1,177 @@
+//! native-tls (system OpenSSL/SChannel/SecureTransport) backend of the TLS layer
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+#[cfg(unix)]
+use bytes::BytesMut;
+#[cfg(unix)]
+use may::io::{WaitIo, WaitIoWaker};
+use may::net::TcpStream;
+use native_tls::HandshakeError;
+
+use crate::connection::TlsInfo;
+use crate::http_server::Transport;
+#[cfg(unix)]
+use crate::http_server::{nonblock_read, nonblock_write};
+use crate::tls::{peer_certificate, TlsAcceptor};
+
+impl TlsAcceptor for native_tls::TlsAcceptor {
+    type Stream = NativeTlsStream;
+
+    fn accept(&self, sock: TcpStream) -> io::Result<NativeTlsStream> {
+        self.accept_timeout(sock, None)
+    }
+
+    fn accept_timeout(
+        &self,
+        sock: TcpStream,
+        timeout: Option<Duration>,
+    ) -> io::Result<NativeTlsStream> {
+        // the handshake runs on the coroutine blocking socket,
+        // a client stalling in it is dropped after `timeout`
+        sock.set_read_timeout(timeout)?;
+        sock.set_write_timeout(timeout)?;
+        let sock = Sock {
+            inner: sock,
+            nonblock: false,
+        };
+        let stream = match native_tls::TlsAcceptor::accept(self, sock) {
+            Ok(stream) => stream,
+            Err(HandshakeError::Failure(e)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(HandshakeError::WouldBlock(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "tls handshake interrupted",
+                ))
+            }
+        };
+        let sock = &stream.get_ref().inner;
+        sock.set_read_timeout(None)?;
+        sock.set_write_timeout(None)?;
+        Ok(NativeTlsStream(stream))
+    }
+}
+
+/// the socket under the tls session
+///
+/// when `nonblock` is set the raw socket is used so `WouldBlock`
+/// is reported back to the connection loop instead of parking the coroutine
+struct Sock {
+    inner: TcpStream,
+    nonblock: bool,
+}
+
+impl Read for Sock {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        if self.nonblock {
+            return self.inner.inner_mut().read(buf);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Sock {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        if self.nonblock {
+            return self.inner.inner_mut().write(buf);
+        }
+        self.inner.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// a server side native-tls session over an accepted `TcpStream`
+pub struct NativeTlsStream(native_tls::TlsStream<Sock>);
+
+impl Read for NativeTlsStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for NativeTlsStream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for NativeTlsStream {
+    #[cfg(not(unix))]
+    #[inline]
+    fn set_read_timeout(&self, timeout: Option<std::time::Duration>) -> io::Result<()> {
+        self.0.get_ref().inner.set_read_timeout(timeout)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn reset_io(&self) {
+        WaitIo::reset_io(&self.0.get_ref().inner)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn wait_io(&self) {
+        WaitIo::wait_io(&self.0.get_ref().inner)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn waker(&self) -> WaitIoWaker {
+        WaitIo::waker(&self.0.get_ref().inner)
+    }
+
+    #[cfg(unix)]
+    fn read_nonblock(&mut self, req_buf: &mut BytesMut) -> io::Result<usize> {
+        self.0.get_mut().nonblock = true;
+        let ret = nonblock_read(&mut self.0, req_buf);
+        self.0.get_mut().nonblock = false;
+        ret
+    }
+
+    #[cfg(unix)]
+    fn write_nonblock(&mut self, write_buf: &mut BytesMut) -> io::Result<usize> {
+        self.0.get_mut().nonblock = true;
+        let ret = nonblock_write(&mut self.0, write_buf);
+        self.0.get_mut().nonblock = false;
+        ret
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        let peer_certificate = match self.0.peer_certificate() {
+            Ok(Some(cert)) => cert.to_der().ok().map(peer_certificate),
+            _ => None,
+        };
+        // the other details are not exposed by native-tls
+        Some(TlsInfo {
+            peer_certificate,
+            ..TlsInfo::default()
+        })
+    }
+
+    fn close(&mut self) {
+        self.0.shutdown().ok();
+        self.0
+            .get_ref()
+            .inner
+            .shutdown(std::net::Shutdown::Both)
+            .ok();
+    }
+}
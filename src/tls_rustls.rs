@@ -0,0 +1,281 @@
+//! rustls backend of the TLS layer
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+#[cfg(unix)]
+use bytes::{Buf, BytesMut};
+#[cfg(unix)]
+use may::io::{WaitIo, WaitIoWaker};
+use may::net::TcpStream;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ProtocolVersion, ServerConfig, ServerConnection};
+
+use crate::connection::TlsInfo;
+#[cfg(unix)]
+use crate::http_server::nonblock_read;
+use crate::http_server::Transport;
+use crate::tls::{peer_certificate, TlsAcceptor};
+
+impl TlsAcceptor for Arc<ServerConfig> {
+    type Stream = RustlsStream;
+
+    fn accept(&self, sock: TcpStream) -> io::Result<RustlsStream> {
+        let conn = ServerConnection::new(self.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // keep the default limit on the buffered records, the responses that don't
+        // fit wait in the write buffer where `max_queued_response` counts them
+        Ok(RustlsStream { sock, conn })
+    }
+}
+
+type Resolve = dyn Fn(&str) -> Option<Arc<CertifiedKey>> + Send + Sync;
+
+/// choose the certificate by the server name the client asks for (SNI),
+/// so one listener serves several domains
+///
+/// a name is matched exactly, `*.example.com` matches any name below
+/// `example.com` but not `example.com` itself. an unknown name goes to the
+/// callback if there is one, then to the fallback certificate. a client
+/// that sends no name only gets the fallback
+///
+/// ```ignore
+/// let certs = SniResolver::new()
+///     .cert("example.com", example)
+///     .cert("*.example.org", wildcard)
+///     .fallback(default);
+/// let config = ServerConfig::builder()
+///     .with_no_client_auth()
+///     .with_cert_resolver(Arc::new(certs));
+/// ```
+#[derive(Default)]
+pub struct SniResolver {
+    certs: Vec<(String, Arc<CertifiedKey>)>,
+    resolve: Option<Box<Resolve>>,
+    fallback: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// serve `name`, or the names below it for a `*.` pattern, with the certificate
+    pub fn cert(mut self, name: &str, cert: Arc<CertifiedKey>) -> Self {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.certs.retain(|(n, _)| *n != name);
+        self.certs.push((name, cert));
+        // the exact names first, then the longest wildcard
+        self.certs.sort_by_key(|(n, _)| match n.strip_prefix('*') {
+            Some(suffix) => std::cmp::Reverse(suffix.len()),
+            None => std::cmp::Reverse(usize::MAX),
+        });
+        self
+    }
+
+    /// look up the names that are not added, a certificate loaded on demand
+    /// should be cached by the callback as it runs on every handshake
+    pub fn resolve<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Option<Arc<CertifiedKey>> + Send + Sync + 'static,
+    {
+        self.resolve = Some(Box::new(f));
+        self
+    }
+
+    /// the certificate for the names no other one is found for
+    pub fn fallback(mut self, cert: Arc<CertifiedKey>) -> Self {
+        self.fallback = Some(cert);
+        self
+    }
+
+    fn find(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.trim_end_matches('.');
+        let found = self.certs.iter().find(|(n, _)| match n.strip_prefix('*') {
+            Some(suffix) => {
+                name.len() > suffix.len()
+                    && name.as_bytes()[name.len() - suffix.len()..]
+                        .eq_ignore_ascii_case(suffix.as_bytes())
+            }
+            None => n.eq_ignore_ascii_case(name),
+        });
+        match found {
+            Some((_, cert)) => Some(cert.clone()),
+            None => self.resolve.as_ref().and_then(|f| f(name)),
+        }
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.find(name))
+            .or_else(|| self.fallback.clone())
+    }
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.certs.iter().map(|(n, _)| n.as_str()).collect();
+        f.debug_struct("SniResolver")
+            .field("names", &names)
+            .field("resolve", &self.resolve.is_some())
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+/// a server side rustls session over an accepted `TcpStream`
+///
+/// the handshake is driven lazily by the connection loop
+pub struct RustlsStream {
+    sock: TcpStream,
+    conn: ServerConnection,
+}
+
+impl RustlsStream {
+    #[cfg(unix)]
+    fn process_packets(&mut self) -> io::Result<()> {
+        if let Err(e) = self.conn.process_new_packets() {
+            // try to deliver the alert before giving up
+            self.conn.write_tls(self.sock.inner_mut()).ok();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(())
+    }
+}
+
+impl Read for RustlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        rustls::Stream::new(&mut self.conn, &mut self.sock).read(buf)
+    }
+}
+
+impl Write for RustlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        rustls::Stream::new(&mut self.conn, &mut self.sock).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        rustls::Stream::new(&mut self.conn, &mut self.sock).flush()
+    }
+}
+
+impl Transport for RustlsStream {
+    #[cfg(not(unix))]
+    #[inline]
+    fn set_read_timeout(&self, timeout: Option<std::time::Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn reset_io(&self) {
+        WaitIo::reset_io(&self.sock)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn wait_io(&self) {
+        WaitIo::wait_io(&self.sock)
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn waker(&self) -> WaitIoWaker {
+        WaitIo::waker(&self.sock)
+    }
+
+    #[cfg(unix)]
+    fn read_nonblock(&mut self, req_buf: &mut BytesMut) -> io::Result<usize> {
+        let mut read_cnt = 0;
+        loop {
+            let would_block = match self.conn.read_tls(self.sock.inner_mut()) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+                Ok(_) => false,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => true,
+                Err(err) => return Err(err),
+            };
+            self.process_packets()?;
+            // the plain text reader reports `WouldBlock` once it's drained
+            read_cnt += nonblock_read(&mut self.conn.reader(), req_buf)?;
+            if would_block {
+                return Ok(read_cnt);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn write_nonblock(&mut self, write_buf: &mut BytesMut) -> io::Result<usize> {
+        let len = write_buf.len();
+        loop {
+            let mut n = 0;
+            if !write_buf.is_empty() {
+                n = self.conn.writer().write(write_buf)?;
+                write_buf.advance(n);
+            }
+
+            // the handshake may also have records pending even without any response
+            let mut sent = false;
+            while self.conn.wants_write() {
+                match self.conn.write_tls(self.sock.inner_mut()) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+                    Ok(_) => sent = true,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(len - write_buf.len())
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            // the records are out, go on with the rest that didn't fit in
+            if write_buf.is_empty() || (n == 0 && !sent) {
+                return Ok(len - write_buf.len());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        if self.conn.is_handshaking() {
+            return None;
+        }
+        let peer_certificate = self
+            .conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| peer_certificate(cert.to_vec()));
+        let version = self.conn.protocol_version().map(|v| match v {
+            ProtocolVersion::SSLv2 => "SSLv2",
+            ProtocolVersion::SSLv3 => "SSLv3",
+            ProtocolVersion::TLSv1_0 => "TLSv1",
+            ProtocolVersion::TLSv1_1 => "TLSv1.1",
+            ProtocolVersion::TLSv1_2 => "TLSv1.2",
+            ProtocolVersion::TLSv1_3 => "TLSv1.3",
+            _ => "unknown",
+        });
+        Some(TlsInfo {
+            version,
+            cipher_suite: self
+                .conn
+                .negotiated_cipher_suite()
+                .and_then(|s| s.suite().as_str()),
+            alpn_protocol: self.conn.alpn_protocol().map(|p| p.to_vec()),
+            server_name: self.conn.server_name().map(|n| n.to_owned()),
+            peer_certificate,
+        })
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        self.conn.write_tls(&mut self.sock).ok();
+        self.sock.shutdown(std::net::Shutdown::Both).ok();
+    }
+}
@@ -0,0 +1,60 @@
+//! run a synchronous `tower::Service` as an `HttpService`
+
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use bytes::Bytes;
+use may::coroutine::{self, Coroutine};
+use tower_service::Service;
+
+use crate::http_server::HttpService;
+use crate::request::Request;
+use crate::response::Response;
+
+/// serve the requests with a `tower::Service` over the `http` crate types
+///
+/// the request body is read in completely before the service is called.
+/// the service is expected to be synchronous, a pending future parks the
+/// connection coroutine until it is woken up
+#[derive(Clone)]
+pub struct TowerService<S>(pub S);
+
+impl<S, B> HttpService for TowerService<S>
+where
+    S: Service<http::Request<Bytes>, Response = http::Response<B>>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    B: Into<Bytes>,
+{
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        let req = http::Request::try_from(req)?;
+        let service = &mut self.0;
+        block_on(poll_fn(|cx| service.poll_ready(cx))).map_err(io::Error::other)?;
+        let res = block_on(service.call(req)).map_err(io::Error::other)?;
+        rsp.write_http(res);
+        Ok(())
+    }
+}
+
+struct CoroutineWaker(Coroutine);
+
+impl Wake for CoroutineWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// drive the future to completion on the current coroutine
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let waker = Waker::from(Arc::new(CoroutineWaker(coroutine::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => coroutine::park(),
+        }
+    }
+}
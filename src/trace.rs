@@ -0,0 +1,116 @@
+//! W3C trace context propagation
+
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Write as _};
+use std::hash::{BuildHasher, Hasher};
+
+/// the `traceparent` and `tracestate` of a request, see `Request::trace_context`
+///
+/// to propagate the trace on an outbound call, take a `child` for the new span
+/// and send its `traceparent` (and `tracestate`) headers
+///
+/// ```ignore
+/// if let Some(ctx) = req.trace_context() {
+///     let span = ctx.child();
+///     upstream.header("traceparent", &span.traceparent());
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// the id of the calling span
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+    /// the vendor specific `tracestate`, passed on as is
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// parse the `traceparent` header value, `None` when it is not valid
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = hex::<1>(parts.next()?)?[0];
+        let trace_id = hex::<16>(parts.next()?)?;
+        let parent_id = hex::<8>(parts.next()?)?;
+        let flags = hex::<1>(parts.next()?)?[0];
+        // a later version may add fields, version 00 has exactly four
+        if version == 0xff || (version == 0 && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        let state = tracestate
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned);
+        Some(TraceContext {
+            trace_id,
+            parent_id,
+            flags,
+            state,
+        })
+    }
+
+    /// the caller asked for the trace to be recorded
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    /// the context of a new span in the same trace
+    pub fn child(&self) -> Self {
+        let mut parent_id = [0; 8];
+        while parent_id == [0; 8] {
+            let mut h = RandomState::new().build_hasher();
+            h.write(&self.trace_id);
+            parent_id = h.finish().to_be_bytes();
+        }
+        TraceContext {
+            parent_id,
+            ..self.clone()
+        }
+    }
+
+    /// the `traceparent` header value
+    pub fn traceparent(&self) -> String {
+        self.to_string()
+    }
+
+    /// the `tracestate` header value, if there is one to pass on
+    pub fn tracestate(&self) -> Option<&str> {
+        self.state.as_deref()
+    }
+}
+
+/// the version 00 `traceparent`
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("00-")?;
+        for b in self.trace_id {
+            write!(f, "{b:02x}")?;
+        }
+        f.write_char('-')?;
+        for b in self.parent_id {
+            write!(f, "{b:02x}")?;
+        }
+        write!(f, "-{:02x}", self.flags)
+    }
+}
+
+/// decode exactly `N` bytes of lowercase hex
+fn hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    let s = s.as_bytes();
+    if s.len() != N * 2 {
+        return None;
+    }
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    let mut out = [0; N];
+    for (i, pair) in s.chunks(2).enumerate() {
+        out[i] = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(out)
+}
@@ -0,0 +1,40 @@
+//! trailer fields of chunked responses
+
+use std::sync::{Arc, Mutex};
+
+/// the trailer fields sent after the last chunk of a streamed body,
+/// see `Response::trailers`
+///
+/// it can be cloned into the reader of the body, which sets the values
+/// once it knows them, at the latest when it returns the end of the body
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(Arc<Mutex<Vec<(String, String)>>>);
+
+impl Trailers {
+    /// set the field `name: value` in place of any earlier value
+    pub fn set(&self, name: &str, value: impl Into<String>) {
+        let value = value.into();
+        debug_assert!(
+            !name.contains([':', '\r', '\n']) && !value.contains(['\r', '\n']),
+            "invalid trailer {name}: {value}"
+        );
+        let mut fields = self.0.lock().unwrap();
+        match fields
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some(field) => field.1 = value,
+            None => fields.push((name.to_owned(), value)),
+        }
+    }
+
+    /// append the fields as they go after the last chunk
+    pub(crate) fn encode(&self, buf: &mut bytes::BytesMut) {
+        for (name, value) in self.0.lock().unwrap().iter() {
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! handing the connection over to another protocol
+
+use std::io::{self, Read, Write};
+
+use crate::http_server::Transport;
+
+/// what runs the connection after the switch, see `Response::upgrade`
+pub(crate) type OnUpgrade = Box<dyn FnOnce(Upgraded) -> io::Result<()> + Send>;
+
+/// the connection after the `101 Switching Protocols` response
+///
+/// the bytes the client sent right after the request are read first
+pub struct Upgraded<'a> {
+    stream: &'a mut dyn Transport,
+    buffered: &'a [u8],
+}
+
+impl<'a> Upgraded<'a> {
+    pub(crate) fn new(stream: &'a mut dyn Transport, buffered: &'a [u8]) -> Self {
+        Upgraded { stream, buffered }
+    }
+}
+
+impl<'a> Read for Upgraded<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buffered.is_empty() {
+            return self.buffered.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl<'a> Write for Upgraded<'a> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
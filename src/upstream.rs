@@ -0,0 +1,561 @@
+//! connections to upstream servers and the framing of their responses
+
+use std::io::{self, BufRead, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use may::net::TcpStream;
+
+use crate::pool::Pool;
+use crate::request::MAX_HEADERS;
+
+/// the biggest upstream response head
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// a connection to an upstream with its read buffer
+pub(crate) struct Upstream {
+    stream: TcpStream,
+    addr: SocketAddr,
+    created: Instant,
+    // it served a response before
+    reused: bool,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Upstream {
+    /// connect to the upstream, the timeout applies to each read and write
+    pub(crate) fn connect(
+        addr: SocketAddr,
+        connect_timeout: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        let stream = match connect_timeout {
+            Some(t) => TcpStream::connect_timeout(&addr, t)?,
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(Upstream {
+            stream,
+            addr,
+            created: Instant::now(),
+            reused: false,
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    #[inline]
+    pub(crate) fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// the timeout of each read and write
+    pub(crate) fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// hand it out again from the pool, it has nothing left to read
+    pub(crate) fn reuse(&mut self) {
+        self.reused = true;
+        self.buf.clear();
+        self.pos = 0;
+    }
+
+    /// it comes from the pool, the upstream may have closed it in the meantime
+    #[inline]
+    pub(crate) fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// nothing has been received on it since it was handed out
+    #[inline]
+    pub(crate) fn is_untouched(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// the buffered bytes that are not consumed yet
+    #[inline]
+    pub(crate) fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// read more bytes into the buffer, 0 at the end of the stream
+    pub(crate) fn fill_more(&mut self) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        }
+        let start = self.buf.len();
+        self.buf.resize(start + 4096, 0);
+        let ret = self.stream.read(&mut self.buf[start..]);
+        self.buf.truncate(start + *ret.as_ref().unwrap_or(&0));
+        ret
+    }
+
+    /// the upstream has not closed the connection or sent anything unasked for
+    #[cfg(unix)]
+    pub(crate) fn is_open(&mut self) -> bool {
+        match self.stream.inner().peek(&mut [0]) {
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        }
+    }
+
+    // the socket can't be polled without blocking
+    #[cfg(not(unix))]
+    pub(crate) fn is_open(&mut self) -> bool {
+        true
+    }
+}
+
+impl Read for Upstream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() && out.len() >= 4096 {
+            return self.stream.read(out);
+        }
+        let n = self.fill_buf()?.read(out)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Upstream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.fill_more()?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.buf.len());
+    }
+}
+
+impl Write for Upstream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// the parsed head of an upstream response
+pub(crate) struct Head {
+    pub(crate) code: u16,
+    pub(crate) framing: Framing,
+    // the connection can take another request after the body
+    pub(crate) keep_alive: bool,
+}
+
+/// how the body of the upstream response ends
+pub(crate) enum Framing {
+    // the bytes left
+    Length(u64),
+    Chunked(Chunked),
+    Close,
+}
+
+impl Framing {
+    fn of(headers: &[httparse::Header<'_>]) -> io::Result<Framing> {
+        if let Some(te) = header_of(headers, "transfer-encoding") {
+            let last = te.rsplit(',').next().unwrap_or_default();
+            return Ok(if last.trim().eq_ignore_ascii_case("chunked") {
+                Framing::Chunked(Chunked::default())
+            } else {
+                Framing::Close
+            });
+        }
+        match header_of(headers, "content-length") {
+            Some(n) => Some(n.trim())
+                .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|n| n.parse().ok())
+                .map(Framing::Length)
+                .ok_or_else(|| invalid_data("invalid content length")),
+            None => Ok(Framing::Close),
+        }
+    }
+
+    #[inline]
+    fn is_done(&self) -> bool {
+        match self {
+            Framing::Length(left) => *left == 0,
+            Framing::Chunked(chunked) => chunked.done,
+            Framing::Close => false,
+        }
+    }
+}
+
+/// read until a whole response head is buffered and hand its headers to `on_headers`.
+/// the interim responses are skipped, `101` is not expected as `Upgrade` is not sent
+pub(crate) fn read_head(
+    upstream: &mut Upstream,
+    on_headers: impl FnOnce(&[httparse::Header<'_>]),
+) -> io::Result<Head> {
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut head = httparse::Response::new(&mut headers);
+        let status = head
+            .parse(upstream.buffer())
+            .map_err(|_| invalid_data("invalid response head"))?;
+        let len = match status {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial if upstream.buffer().len() >= MAX_HEAD_LEN => {
+                return Err(invalid_data("response head too large"));
+            }
+            httparse::Status::Partial => {
+                if upstream.fill_more()? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                continue;
+            }
+        };
+        let code = head.code.unwrap_or_default();
+        if (100..200).contains(&code) {
+            upstream.consume(len);
+            continue;
+        }
+        let framing = Framing::of(head.headers)?;
+        let keep_alive = head.version == Some(1)
+            && !header_of(head.headers, "connection")
+                .unwrap_or_default()
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case("close"));
+        on_headers(head.headers);
+        upstream.consume(len);
+        return Ok(Head {
+            code,
+            framing,
+            keep_alive,
+        });
+    }
+}
+
+/// the body of an upstream response, the connection goes back to the pool
+/// once it is read to the end
+pub(crate) struct ResponseBody {
+    upstream: Option<Upstream>,
+    framing: Framing,
+    keep_alive: bool,
+    pool: Option<Arc<Pool>>,
+}
+
+impl ResponseBody {
+    /// the body after `head`, `has_body` is false for the response to a `HEAD`
+    /// request, or with a status that has none, whatever the framing headers say
+    pub(crate) fn new(
+        upstream: Upstream,
+        head: Head,
+        has_body: bool,
+        pool: Option<Arc<Pool>>,
+    ) -> Self {
+        let has_body = has_body && !matches!(head.code, 204 | 304);
+        let mut body = ResponseBody {
+            upstream: Some(upstream),
+            framing: if has_body {
+                head.framing
+            } else {
+                Framing::Length(0)
+            },
+            keep_alive: head.keep_alive,
+            pool,
+        };
+        if !has_body {
+            body.release();
+        }
+        body
+    }
+
+    /// hand the connection back if it can take another request
+    fn release(&mut self) {
+        if let Some(upstream) = self.upstream.take() {
+            if let Some(pool) = self.pool.as_ref().filter(|_| self.keep_alive) {
+                if self.framing.is_done() {
+                    pool.checkin(upstream);
+                }
+            }
+        }
+    }
+}
+
+impl Read for ResponseBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let upstream = match self.upstream.as_mut() {
+            Some(upstream) => upstream,
+            None => return Ok(0),
+        };
+        let n = match self.framing {
+            Framing::Length(ref mut left) => {
+                let max = buf.len().min((*left).min(usize::MAX as u64) as usize);
+                if max == 0 {
+                    0
+                } else {
+                    let n = upstream.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *left -= n as u64;
+                    n
+                }
+            }
+            Framing::Chunked(ref mut chunked) => chunked.read(upstream, buf)?,
+            Framing::Close => return upstream.read(buf),
+        };
+        if self.framing.is_done() {
+            self.release();
+        }
+        Ok(n)
+    }
+}
+
+/// the value of the named header
+pub(crate) fn header_of<'h>(headers: &'h [httparse::Header<'_>], name: &str) -> Option<&'h str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// the state of a de-chunked body, the trailers are dropped
+#[derive(Default)]
+pub(crate) struct Chunked {
+    // the bytes left in the current chunk
+    left: u64,
+    done: bool,
+}
+
+impl Chunked {
+    fn read(&mut self, r: &mut impl BufRead, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 && !self.done {
+            self.next_chunk(r)?;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let n = r.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.left -= n as u64;
+        if self.left == 0 {
+            let mut crlf = String::new();
+            read_line(r, &mut crlf)?;
+            if !crlf.trim().is_empty() {
+                return Err(invalid_data("invalid chunk data"));
+            }
+        }
+        Ok(n)
+    }
+
+    /// read the next chunk size line, at the last chunk skip the trailers
+    fn next_chunk(&mut self, r: &mut impl BufRead) -> io::Result<()> {
+        let mut line = String::new();
+        read_line(r, &mut line)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        // from_str_radix takes a sign too
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid_data("invalid chunk size"));
+        }
+        self.left =
+            u64::from_str_radix(size, 16).map_err(|_| invalid_data("invalid chunk size"))?;
+        if self.left == 0 {
+            // the trailers are bound like a head
+            let mut len = 0;
+            loop {
+                read_line(r, &mut line)?;
+                if line.trim().is_empty() {
+                    break;
+                }
+                len += line.len();
+                if len > MAX_HEAD_LEN {
+                    return Err(invalid_data("trailers too large"));
+                }
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+/// read a line ending with `\n`, one longer than a head may be is refused
+fn read_line(r: &mut impl BufRead, line: &mut String) -> io::Result<()> {
+    line.clear();
+    let n = r.take(MAX_HEAD_LEN as u64).read_line(line)?;
+    if line.ends_with('\n') {
+        return Ok(());
+    }
+    if n < MAX_HEAD_LEN {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Err(invalid_data("line too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dechunk(mut body: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+        let mut chunked = Chunked::default();
+        let mut out = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match chunked.read(&mut body, &mut buf)? {
+                0 => return Ok((out, body.len())),
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn chunked() {
+        let body = b"5\r\nhello\r\n1;ext=\"a;b\"\r\n,\r\nA\r\n worldwide\r\n0\r\n\r\nnext";
+        let (out, left) = dechunk(body).unwrap();
+        assert_eq!(out, b"hello, worldwide");
+        // the next response is not touched
+        assert_eq!(left, 4);
+
+        let trailers = b"3\nabc\n0\nX-Sum: 1\r\nX-Other: 2\r\n\r\nnext";
+        assert_eq!(dechunk(trailers).unwrap(), (b"abc".to_vec(), 4));
+        assert_eq!(dechunk(b"0\r\n\r\n").unwrap(), (vec![], 0));
+    }
+
+    #[test]
+    fn bad_chunks() {
+        let invalid = [
+            &b"x\r\nabc\r\n0\r\n\r\n"[..],
+            b"+3\r\nabc\r\n0\r\n\r\n",
+            b"-0\r\n\r\n",
+            b"\r\nabc\r\n0\r\n\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+            b"ffffffffffffffffff\r\n",
+        ];
+        for body in invalid {
+            let err = dechunk(body).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{body:?}");
+        }
+        let truncated = [
+            &b"5\r\nhel"[..],
+            b"3\r\nabc",
+            b"3\r\nabc\r\n",
+            b"0\r\nX: 1\r\n",
+            b"5",
+        ];
+        for body in truncated {
+            let err = dechunk(body).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{body:?}");
+        }
+
+        let mut trailers = b"0\r\n".to_vec();
+        while trailers.len() <= MAX_HEAD_LEN {
+            trailers.extend_from_slice(b"X-Padding: 0123456789\r\n");
+        }
+        trailers.extend_from_slice(b"\r\n");
+        assert_eq!(
+            dechunk(&trailers).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn lines() {
+        let mut line = String::new();
+        let mut r = &b"a\r\nb\nc"[..];
+        read_line(&mut r, &mut line).unwrap();
+        assert_eq!(line, "a\r\n");
+        read_line(&mut r, &mut line).unwrap();
+        assert_eq!(line, "b\n");
+        let err = read_line(&mut r, &mut line).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let long = vec![b'a'; MAX_HEAD_LEN + 1];
+        let err = read_line(&mut &long[..], &mut line).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn framing(headers: &[(&'static str, &'static str)]) -> io::Result<Framing> {
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|&(name, value)| httparse::Header {
+                name,
+                value: value.as_bytes(),
+            })
+            .collect();
+        Framing::of(&headers)
+    }
+
+    #[test]
+    fn framings() {
+        assert!(matches!(framing(&[]).unwrap(), Framing::Close));
+        assert!(matches!(
+            framing(&[("Content-Length", " 42 ")]).unwrap(),
+            Framing::Length(42)
+        ));
+        assert!(matches!(
+            framing(&[("content-length", "0")]).unwrap(),
+            Framing::Length(0)
+        ));
+        let chunked = framing(&[("Transfer-Encoding", "gzip, Chunked")]).unwrap();
+        assert!(matches!(chunked, Framing::Chunked(_)) && !chunked.is_done());
+        // chunked wins over the length
+        let both = [("Content-Length", "5"), ("Transfer-Encoding", "chunked")];
+        assert!(matches!(framing(&both).unwrap(), Framing::Chunked(_)));
+        // not chunked last, read until closed
+        let gzip = [
+            ("Transfer-Encoding", "chunked, gzip"),
+            ("Content-Length", "5"),
+        ];
+        assert!(matches!(framing(&gzip).unwrap(), Framing::Close));
+
+        for bad in [
+            "",
+            "+5",
+            "-1",
+            "5, 5",
+            "0x10",
+            "1 0",
+            "99999999999999999999",
+        ] {
+            let err = framing(&[("Content-Length", bad)]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{bad:?}");
+        }
+
+        assert!(Framing::Length(0).is_done() && !Framing::Length(1).is_done());
+        assert!(!Framing::Close.is_done());
+    }
+
+    #[test]
+    fn headers() {
+        let headers = [
+            httparse::Header {
+                name: "Content-Type",
+                value: b"text/plain",
+            },
+            httparse::Header {
+                name: "X-Bin",
+                value: b"\xff",
+            },
+        ];
+        assert_eq!(header_of(&headers, "content-type"), Some("text/plain"));
+        assert_eq!(header_of(&headers, "x-bin"), None);
+        assert_eq!(header_of(&headers, "location"), None);
+    }
+}
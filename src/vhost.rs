@@ -0,0 +1,166 @@
+//! request routing by `Host`
+
+use std::fmt;
+use std::io;
+
+use crate::http_server::HttpService;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// a service of a host, cloned for each connection like the `VirtualHosts` itself
+trait HostService: HttpService + Send + Sync {
+    fn clone_box(&self) -> Box<dyn HostService>;
+}
+
+impl<T: HttpService + Clone + Send + Sync + 'static> HostService for T {
+    #[inline]
+    fn clone_box(&self) -> Box<dyn HostService> {
+        Box::new(self.clone())
+    }
+}
+
+/// dispatch requests to a service by the `Host` header, so one listener
+/// serves several sites
+///
+/// a host is matched exactly, `*.example.com` matches any name below
+/// `example.com` but not `example.com` itself. the exact names come first,
+/// then the longest wildcard. the port and the case of the header don't matter.
+/// a request for no known host goes to the fallback service, it is answered
+/// with `421 Misdirected Request` without one
+///
+/// ```ignore
+/// let hosts = VirtualHosts::new()
+///     .host("example.com", Router::new().get("/", home))
+///     .host("*.example.com", StaticFiles::new("/", "./sites"))
+///     .fallback(NotFound);
+/// HttpServer(hosts).start("0.0.0.0:8080")?;
+/// ```
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: Vec<(String, Box<dyn HostService>)>,
+    // the `*.` patterns, the suffix keeps its leading `.`
+    wildcards: Vec<(String, Box<dyn HostService>)>,
+    fallback: Option<Box<dyn HostService>>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// serve `host`, or the names below it for a `*.` pattern, with the service.
+    /// a host added twice keeps the last service
+    pub fn host<S>(mut self, host: &str, service: S) -> Self
+    where
+        S: HttpService + Clone + Send + Sync + 'static,
+    {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let (hosts, name) = match host.strip_prefix('*') {
+            Some(suffix) => (&mut self.wildcards, suffix.to_owned()),
+            None => (&mut self.hosts, host),
+        };
+        hosts.retain(|(h, _)| *h != name);
+        hosts.push((name, Box::new(service)));
+        // the longest suffix is the most specific
+        self.wildcards
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        self
+    }
+
+    /// the service for the requests of no known host
+    pub fn fallback<S>(mut self, service: S) -> Self
+    where
+        S: HttpService + Clone + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(service));
+        self
+    }
+
+    /// all the services, each site of a connection gets its events
+    fn services(&mut self) -> impl Iterator<Item = &mut Box<dyn HostService>> {
+        let hosts = self.hosts.iter_mut().chain(self.wildcards.iter_mut());
+        hosts.map(|(_, s)| s).chain(self.fallback.as_mut())
+    }
+
+    fn service(&mut self, host: Option<&str>) -> Option<&mut Box<dyn HostService>> {
+        if let Some(host) = host.map(host_name) {
+            let i = self
+                .hosts
+                .iter()
+                .position(|(h, _)| h.eq_ignore_ascii_case(host));
+            if let Some(i) = i {
+                return Some(&mut self.hosts[i].1);
+            }
+            let i = self.wildcards.iter().position(|(suffix, _)| {
+                host.len() > suffix.len()
+                    && host.as_bytes()[host.len() - suffix.len()..]
+                        .eq_ignore_ascii_case(suffix.as_bytes())
+            });
+            if let Some(i) = i {
+                return Some(&mut self.wildcards[i].1);
+            }
+        }
+        self.fallback.as_mut()
+    }
+}
+
+/// the name of a `Host` value, without the port and the trailing dot
+fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    let name = match host.strip_prefix('[') {
+        // an IPv6 literal keeps its brackets
+        Some(rest) => rest.find(']').map_or(host, |end| &host[..end + 2]),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.trim_end_matches('.')
+}
+
+impl Clone for VirtualHosts {
+    fn clone(&self) -> Self {
+        let clone = |hosts: &[(String, Box<dyn HostService>)]| {
+            hosts
+                .iter()
+                .map(|(h, s)| (h.clone(), s.clone_box()))
+                .collect()
+        };
+        VirtualHosts {
+            hosts: clone(&self.hosts),
+            wildcards: clone(&self.wildcards),
+            fallback: self.fallback.as_ref().map(|s| s.clone_box()),
+        }
+    }
+}
+
+impl fmt::Debug for VirtualHosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = |hosts: &[(String, Box<dyn HostService>)]| {
+            hosts.iter().map(|(h, _)| h.clone()).collect::<Vec<_>>()
+        };
+        f.debug_struct("VirtualHosts")
+            .field("hosts", &names(&self.hosts))
+            .field("wildcards", &names(&self.wildcards))
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl HttpService for VirtualHosts {
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        match self.service(req.host()) {
+            Some(service) => service.call(req, rsp),
+            None => {
+                rsp.status(StatusCode::MisdirectedRequest);
+                Ok(())
+            }
+        }
+    }
+
+    fn on_connection_start(&mut self) {
+        self.services().for_each(|s| s.on_connection_start());
+    }
+
+    fn on_connection_end(&mut self) {
+        self.services().for_each(|s| s.on_connection_end());
+    }
+}